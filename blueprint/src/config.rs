@@ -0,0 +1,622 @@
+//! Operator configuration
+//!
+//! Settings that are chosen by the operator running the blueprint rather than by
+//! the service owners. They are read from a TOML file at startup, either from the
+//! path in the `SERVER_BLUEPRINT_CONFIG` environment variable or from
+//! `server-blueprint/config.toml` in the data directory. A missing file means the
+//! defaults are used.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// The environment variable that overrides the path of the operator configuration
+pub const CONFIG_PATH_ENV: &str = "SERVER_BLUEPRINT_CONFIG";
+
+/// The operator configuration
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OperatorConfig {
+    /// Host ports handed out to the servers
+    pub ports: PortsConfig,
+    /// How servers are stopped
+    pub stop: StopConfig,
+    /// How Docker images are pulled
+    pub images: ImagesConfig,
+    /// How the images of the Dockerfile runtime are built
+    pub builds: BuildsConfig,
+    /// How the artifacts of the Binary runtime are fetched
+    pub binaries: BinariesConfig,
+    /// The sandbox of the Wasm runtime
+    pub wasm: WasmConfig,
+    /// Runtimes that run the servers with a command, by the name servers ask for them by
+    pub runtimes: BTreeMap<String, CommandRuntimeConfig>,
+    /// The resources servers may use
+    pub limits: LimitsConfig,
+    /// How long the volumes of removed servers are kept
+    pub volumes: VolumesConfig,
+    /// Where and how many backups are kept
+    pub backups: BackupsConfig,
+    /// Where the TCP tunnels to the servers listen
+    pub tunnels: TunnelsConfig,
+    /// Credentials for private Docker registries, by the name servers refer to them with
+    pub registries: BTreeMap<String, RegistryConfig>,
+    /// Which runtimes, images and packages servers may use
+    pub policy: PolicyConfig,
+}
+
+/// The range of host ports the servers are allocated from
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortsConfig {
+    /// The first port of the range
+    pub start: u16,
+    /// The last port of the range, inclusive
+    pub end: u16,
+}
+
+impl Default for PortsConfig {
+    fn default() -> Self {
+        Self {
+            start: 30000,
+            end: 39999,
+        }
+    }
+}
+
+impl PortsConfig {
+    /// The configured port range
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+/// Where the TCP tunnels to the servers listen
+///
+/// Unlike the server ports, which are only bound on the loopback interface behind
+/// the auth proxy, tunnel ports are reachable by the clients.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelsConfig {
+    /// The address the tunnels listen on
+    pub bind_address: IpAddr,
+    /// The first port of the range the tunnels are allocated from
+    pub start: u16,
+    /// The last port of the range, inclusive
+    pub end: u16,
+}
+
+impl Default for TunnelsConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            start: 40000,
+            end: 40999,
+        }
+    }
+}
+
+impl TunnelsConfig {
+    /// The configured port range
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+/// Credentials for a private Docker registry
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// The registry the credentials are for, e.g. `ghcr.io`
+    pub server_address: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// An identity token to use instead of a username and password
+    pub identity_token: Option<String>,
+    /// The services that may use the credentials, all services if empty
+    pub service_ids: Vec<u64>,
+}
+
+// The config is logged at startup, so the secrets are left out
+impl fmt::Debug for RegistryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryConfig")
+            .field("server_address", &self.server_address)
+            .field("username", &self.username)
+            .field("service_ids", &self.service_ids)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Which runtimes, images and packages servers may use
+///
+/// The rules are glob patterns. A server is denied if any deny rule matches, or if
+/// there are allow rules and none of them matches.
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Which runtimes are enabled
+    pub runtimes: RuntimesPolicy,
+    /// Docker images servers may use, matched against the image as requested and its
+    /// full name, e.g. `nginx:latest` and `docker.io/library/nginx:latest`
+    pub allow_images: Vec<String>,
+    /// Docker images servers must not use
+    pub deny_images: Vec<String>,
+    /// Registries servers may pull images from, e.g. `docker.io` or `ghcr.io`
+    pub allow_registries: Vec<String>,
+    /// Registries servers must not pull images from
+    pub deny_registries: Vec<String>,
+    /// Python and JavaScript packages servers may run, matched against the package as
+    /// requested and its name, e.g. `pkg==1.2.3` and `pkg`, and the artifact URLs and
+    /// paths of binaries
+    pub allow_packages: Vec<String>,
+    /// Python and JavaScript packages and binary artifacts servers must not run
+    pub deny_packages: Vec<String>,
+    /// Limits forced on the servers of matching images and packages
+    pub overrides: Vec<PolicyOverride>,
+}
+
+/// Which runtimes are enabled
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimesPolicy {
+    pub docker: bool,
+    pub python: bool,
+    pub javascript: bool,
+    pub dockerfile: bool,
+    pub compose: bool,
+    pub binary: bool,
+    pub wasm: bool,
+    /// The runtimes registered by the operator, by name, enabled unless set to false
+    pub custom: BTreeMap<String, bool>,
+}
+
+impl Default for RuntimesPolicy {
+    fn default() -> Self {
+        Self {
+            docker: true,
+            python: true,
+            javascript: true,
+            dockerfile: true,
+            compose: true,
+            binary: true,
+            wasm: true,
+            custom: BTreeMap::new(),
+        }
+    }
+}
+
+/// Limits forced on the servers of matching images and packages
+///
+/// Servers asking for more, or for no limit, get the override instead. Zero means
+/// no override.
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyOverride {
+    /// The images and packages the override applies to, matched like the allow rules
+    pub matches: Vec<String>,
+    /// The most CPU time, in thousandths of a CPU
+    pub max_cpu_millis: u64,
+    /// The most memory, in MiB
+    pub max_memory_mb: u64,
+    /// The most processes and threads
+    pub max_pids: u64,
+    /// The most disk usage, in MiB, for the runtimes that can limit it
+    pub max_disk_mb: u64,
+}
+
+/// How servers are stopped
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopConfig {
+    /// How long a server gets to exit after `SIGTERM` before it is killed, in seconds
+    pub grace_period_secs: u64,
+}
+
+impl Default for StopConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_secs: 10,
+        }
+    }
+}
+
+impl StopConfig {
+    /// The configured grace period
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+/// How Docker images are pulled
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesConfig {
+    /// How long pulling an image may take before it is aborted, in seconds
+    pub pull_timeout_secs: u64,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            pull_timeout_secs: 600,
+        }
+    }
+}
+
+impl ImagesConfig {
+    /// The configured pull timeout
+    pub fn pull_timeout(&self) -> Duration {
+        Duration::from_secs(self.pull_timeout_secs)
+    }
+}
+
+/// How the images of the Dockerfile runtime are built
+///
+/// The resource limits apply to each build step. Zero means no limit.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildsConfig {
+    /// How long a build may take before it is aborted, in seconds
+    pub timeout_secs: u64,
+    /// The largest build context and inline Dockerfile, in MiB, before and after decompression
+    pub max_context_mb: u64,
+    /// The CPU time of a build, in thousandths of a CPU
+    pub cpu_millis: u64,
+    /// The memory of a build, in MiB
+    pub memory_mb: u64,
+}
+
+impl Default for BuildsConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 900,
+            max_context_mb: 16,
+            cpu_millis: 2000,
+            memory_mb: 2048,
+        }
+    }
+}
+
+impl BuildsConfig {
+    /// The configured build timeout
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// The configured maximum context size, in bytes
+    pub fn max_context_bytes(&self) -> u64 {
+        self.max_context_mb.saturating_mul(1024 * 1024)
+    }
+}
+
+/// How the artifacts of the Binary runtime are fetched
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BinariesConfig {
+    /// How long downloading an artifact may take before it is aborted, in seconds
+    pub download_timeout_secs: u64,
+    /// The largest artifact, in MiB, before and after unpacking
+    pub max_size_mb: u64,
+    /// Whether servers may run artifacts from the operator's file system
+    pub allow_local_files: bool,
+}
+
+impl Default for BinariesConfig {
+    fn default() -> Self {
+        Self {
+            download_timeout_secs: 300,
+            max_size_mb: 256,
+            allow_local_files: false,
+        }
+    }
+}
+
+impl BinariesConfig {
+    /// The configured download timeout
+    pub fn download_timeout(&self) -> Duration {
+        Duration::from_secs(self.download_timeout_secs)
+    }
+
+    /// The configured maximum artifact size, in bytes
+    pub fn max_size_bytes(&self) -> u64 {
+        self.max_size_mb.saturating_mul(1024 * 1024)
+    }
+}
+
+/// The sandbox of the Wasm runtime
+///
+/// Every request runs in a fresh instance of the component, under these limits.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmConfig {
+    /// The fuel a request may consume, roughly the number of instructions it may run
+    pub fuel_per_request: u64,
+    /// The memory of an instance, in MiB, unless the server asks for less
+    pub max_memory_mb: u64,
+    /// How long a request may take before it is aborted, in seconds
+    pub request_timeout_secs: u64,
+    /// How many requests a server handles at once, the others wait
+    pub max_concurrent_requests: usize,
+    /// Whether components may send HTTP requests
+    pub allow_outgoing_http: bool,
+    /// Whether components get the working directory of their server, mounted at `/data`
+    pub allow_work_dir: bool,
+}
+
+impl Default for WasmConfig {
+    fn default() -> Self {
+        Self {
+            fuel_per_request: 5_000_000_000,
+            max_memory_mb: 128,
+            request_timeout_secs: 30,
+            max_concurrent_requests: 32,
+            allow_outgoing_http: false,
+            allow_work_dir: false,
+        }
+    }
+}
+
+impl WasmConfig {
+    /// The configured request timeout
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+/// A runtime that runs the servers with a command, like `deno run`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandRuntimeConfig {
+    /// The program to run, a path or a name looked up in `PATH`
+    pub program: String,
+    /// The arguments of the program
+    ///
+    /// `{package}`, `{port}` and `{work_dir}` are replaced by those of the server, and an
+    /// argument that is just `{args}` by the arguments of the server. Without one, the
+    /// arguments of the server follow the template. `{{` and `}}` are literal braces.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The environment variables of the operator the servers get, besides `PATH` and `HOME`
+    #[serde(default)]
+    pub env_passthrough: Vec<String>,
+    /// A command that succeeds if the runtime is installed, like `["deno", "--version"]`
+    #[serde(default)]
+    pub check: Vec<String>,
+}
+
+impl CommandRuntimeConfig {
+    /// The argument that stands for the arguments of the server
+    pub const ARGS_PLACEHOLDER: &str = "{args}";
+
+    /// Replace the placeholders of an argument template with their `value`
+    pub fn expand(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+        let invalid = |reason: String| {
+            Error::InvalidOperatorConfig(format!("the argument `{template}` {reason}"))
+        };
+        let mut expanded = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            expanded.push_str(&rest[..start]);
+            let tail = &rest[start..];
+            if let Some(after) = tail.strip_prefix("{{") {
+                expanded.push('{');
+                rest = after;
+            } else if let Some(after) = tail.strip_prefix("}}") {
+                expanded.push('}');
+                rest = after;
+            } else if let Some(after) = tail.strip_prefix('{') {
+                let end = after
+                    .find('}')
+                    .ok_or_else(|| invalid("has an unclosed `{`".to_string()))?;
+                let name = &after[..end];
+                let value =
+                    value(name).ok_or_else(|| invalid(format!("has an unknown `{{{name}}}`")))?;
+                expanded.push_str(&value);
+                rest = &after[end + 1..];
+            } else {
+                return Err(invalid("has an unmatched `}`".to_string()));
+            }
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    fn validate(&self, name: &str) -> Result<(), Error> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidOperatorConfig(format!(
+                "runtime `{name}`: {reason}"
+            )))
+        };
+        if self.program.trim().is_empty() || self.program.contains('\0') {
+            return invalid("`program` must be the name or path of a program");
+        }
+        let placeholders =
+            |name: &str| matches!(name, "package" | "port" | "work_dir").then(String::new);
+        for arg in &self.args {
+            if arg.contains('\0') {
+                return invalid("arguments must not contain NUL bytes");
+            }
+            if arg != Self::ARGS_PLACEHOLDER {
+                if let Err(Error::InvalidOperatorConfig(reason)) = Self::expand(arg, placeholders) {
+                    return invalid(&reason);
+                }
+            }
+        }
+        let valid_name = |var: &String| !var.is_empty() && !var.contains(['=', '\0']);
+        if !self.env_passthrough.iter().all(valid_name) {
+            return invalid("`env_passthrough` must hold environment variable names");
+        }
+        if self
+            .check
+            .first()
+            .is_some_and(|program| program.trim().is_empty())
+        {
+            return invalid("`check` must start with a program");
+        }
+        Ok(())
+    }
+}
+
+/// The maximum resources a server may use
+///
+/// Zero means no maximum. Servers that do not set a limit get the maximum.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The maximum CPU time, in thousandths of a CPU
+    pub max_cpu_millis: u64,
+    /// The maximum memory, in MiB
+    pub max_memory_mb: u64,
+    /// The maximum swap on top of the memory limit, in MiB
+    pub max_swap_mb: u64,
+    /// The maximum number of processes and threads
+    pub max_pids: u64,
+    /// The maximum disk usage, in MiB
+    ///
+    /// Only the Docker runtimes can limit the disk usage, the other runtimes reject
+    /// disk limits and are not limited by this.
+    pub max_disk_mb: u64,
+    /// The cgroup v2 directory the cgroups of the process runtimes are created in
+    ///
+    /// The operator needs write access to it, and the `cpu`, `memory` and `pids`
+    /// controllers must be available to it.
+    pub cgroup_root: PathBuf,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_cpu_millis: 0,
+            max_memory_mb: 0,
+            max_swap_mb: 0,
+            max_pids: 0,
+            max_disk_mb: 0,
+            cgroup_root: PathBuf::from("/sys/fs/cgroup/server-blueprint"),
+        }
+    }
+}
+
+/// How long the volumes of removed servers are kept
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumesConfig {
+    /// How long a volume no server uses is kept before it is deleted, in hours
+    ///
+    /// Zero means such volumes are never deleted.
+    pub retention_hours: u64,
+}
+
+impl Default for VolumesConfig {
+    fn default() -> Self {
+        Self {
+            retention_hours: 7 * 24,
+        }
+    }
+}
+
+impl VolumesConfig {
+    /// The configured retention period in seconds, if unused volumes are deleted at all
+    pub fn retention_secs(&self) -> Option<u64> {
+        (self.retention_hours != 0).then(|| self.retention_hours.saturating_mul(3600))
+    }
+}
+
+/// Where and how many backups are kept
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupsConfig {
+    /// The directory the backups are stored in
+    ///
+    /// Defaults to `server-blueprint/backups` in the data directory.
+    pub dir: Option<PathBuf>,
+    /// The most backups kept per service, older ones are deleted
+    pub max_backups: u64,
+    /// The shortest interval of scheduled backups, in hours
+    pub min_interval_hours: u64,
+}
+
+impl Default for BackupsConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_backups: 10,
+            min_interval_hours: 1,
+        }
+    }
+}
+
+impl OperatorConfig {
+    /// Load the operator configuration for the given blueprint data directory
+    pub async fn load(data_dir: &Path) -> Result<Self, Error> {
+        let path = std::env::var_os(CONFIG_PATH_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| data_dir.join("config.toml"));
+        let config = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => toml::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                blueprint_sdk::debug!(path = %path.display(), "No operator config, using defaults");
+                Self::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        config.validate()?;
+        blueprint_sdk::debug!(?config, path = %path.display(), "Loaded operator config");
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::InvalidOperatorConfig(reason.to_string()));
+        if self.ports.start == 0 || self.ports.start > self.ports.end {
+            return invalid("`ports.start` must be non-zero and not after `ports.end`");
+        }
+        if self.tunnels.start == 0 || self.tunnels.start > self.tunnels.end {
+            return invalid("`tunnels.start` must be non-zero and not after `tunnels.end`");
+        }
+        if self.tunnels.start <= self.ports.end && self.ports.start <= self.tunnels.end {
+            return invalid("the `tunnels` port range must not overlap the `ports` range");
+        }
+        if self.images.pull_timeout_secs == 0 {
+            return invalid("`images.pull_timeout_secs` must be non-zero");
+        }
+        if self.builds.timeout_secs == 0 || self.builds.max_context_mb == 0 {
+            return invalid("`builds.timeout_secs` and `builds.max_context_mb` must be non-zero");
+        }
+        if self.binaries.download_timeout_secs == 0 || self.binaries.max_size_mb == 0 {
+            return invalid(
+                "`binaries.download_timeout_secs` and `binaries.max_size_mb` must be non-zero",
+            );
+        }
+        if self.wasm.fuel_per_request == 0
+            || self.wasm.max_memory_mb == 0
+            || self.wasm.request_timeout_secs == 0
+            || self.wasm.max_concurrent_requests == 0
+        {
+            return invalid("the limits in `wasm` must be non-zero");
+        }
+        if self.backups.max_backups == 0 {
+            return invalid("`backups.max_backups` must be non-zero");
+        }
+        let too_small =
+            |cpu_millis: u64| cpu_millis != 0 && cpu_millis < crate::ResourceLimits::MIN_CPU_MILLIS;
+        if too_small(self.limits.max_cpu_millis)
+            || too_small(self.builds.cpu_millis)
+            || self
+                .policy
+                .overrides
+                .iter()
+                .any(|rule| too_small(rule.max_cpu_millis))
+        {
+            return invalid("CPU limits must be zero or at least 10 thousandths of a CPU");
+        }
+        for (name, runtime) in &self.runtimes {
+            runtime.validate(name)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::manager::ServerId;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// SDK error
@@ -15,8 +17,50 @@ pub enum Error {
     InvalidRequestParams(#[from] blueprint_sdk::tangle::serde::error::Error),
     #[error("Invalid request params: unknown runtime")]
     UnknownRuntime,
+    #[error("Invalid server config: {0}")]
+    InvalidConfig(String),
+    #[error("Denied by the operator policy: {0}")]
+    PolicyViolation(String),
     #[error("Missing port binding")]
     MissingPortBinding,
+    #[error("No port is available in the configured range")]
+    NoPortAvailable,
+    #[error("Port {0} is still in use")]
+    PortUnavailable(u16),
+    #[error("Server did not become ready: {0}")]
+    NotReady(String),
+    #[error("The {subject} does not match its pinned digest {expected}, got {actual}")]
+    IntegrityMismatch {
+        subject: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Only the owner of service {0} can do this")]
+    NotOwner(u64),
+    #[error("No server is running in slot `{}` of service {}", .0.slot, .0.service_id)]
+    ServerNotFound(ServerId),
+    #[error("A server is already running in slot `{}` of service {}", .0.slot, .0.service_id)]
+    ServerExists(ServerId),
+    #[error("Backup {0} does not exist")]
+    BackupNotFound(String),
+    #[error("The server in slot `{}` of service {} has no backups", .0.slot, .0.service_id)]
+    NoBackups(ServerId),
+    #[error("Backup {0} is corrupted: its checksum does not match")]
+    BackupCorrupted(String),
+    #[error("The server in slot `{}` of service {} is being restored", .0.slot, .0.service_id)]
+    Restoring(ServerId),
+
+    /// Server state (de)serialization error
+    #[error("Invalid server state: {0}")]
+    State(#[from] serde_json::Error),
+    #[error("Unsupported server state version {0}")]
+    UnsupportedStateVersion(u32),
+
+    /// Operator configuration error
+    #[error("Invalid operator config: {0}")]
+    OperatorConfig(#[from] toml::de::Error),
+    #[error("Invalid operator config: {0}")]
+    InvalidOperatorConfig(String),
 
     /// I/O error
     #[error(transparent)]
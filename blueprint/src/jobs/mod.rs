@@ -1,8 +1,22 @@
+mod server_backup;
+mod server_logs;
+mod server_restart;
 mod server_start;
+mod server_status;
 mod server_stop;
 
 pub const SERVER_START_JOB_ID: u8 = 0;
 pub const SERVER_STOP_JOB_ID: u8 = 1;
+pub const SERVER_LOGS_JOB_ID: u8 = 2;
+pub const SERVER_STATUS_JOB_ID: u8 = 3;
+pub const SERVER_RESTART_JOB_ID: u8 = 4;
+pub const SERVER_RECONFIGURE_JOB_ID: u8 = 5;
+pub const SERVER_BACKUP_JOB_ID: u8 = 6;
+pub const SERVER_RESTORE_JOB_ID: u8 = 7;
 
+pub use server_backup::{RestoreRequest, server_backup, server_restore};
+pub use server_logs::{LogsQuery, server_logs};
+pub use server_restart::{server_reconfigure, server_restart};
 pub use server_start::server_start;
+pub use server_status::{PortStatus, PullStatus, ServerHealth, ServerStatus, server_status};
 pub use server_stop::server_stop;
@@ -0,0 +1,68 @@
+use blueprint_sdk::extract::Context;
+use blueprint_sdk::tangle::extract::{Caller, Optional, ServiceId, TangleArg, TangleResult};
+
+use crate::error::Error;
+use crate::manager::{ServerId, ServerManager};
+use crate::{BackupOptions, MyContext};
+
+/// Which backup to restore into which server
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreRequest {
+    /// The slot of the server, defaults to the `default` slot
+    #[serde(default)]
+    pub slot: Optional<String>,
+    /// The id of the backup, defaults to the newest one
+    #[serde(default)]
+    pub backup_id: Optional<String>,
+}
+
+/// Back up the data of a server
+///
+/// Docker servers are backed up by their volumes, process servers by their working
+/// directory. A non-zero `intervalHours` also schedules further backups, a zero one
+/// cancels the schedule. Returns the id of the new backup.
+pub async fn server_backup(
+    Context(ctx): Context<MyContext>,
+    ServiceId(service_id): ServiceId,
+    Caller(caller): Caller,
+    TangleArg(options): TangleArg<BackupOptions>,
+) -> Result<TangleResult<String>, Error> {
+    options.validate(&ctx.config.backups)?;
+    let id = ServerId::from_slot(service_id, options.slot.0.clone())?;
+    let target = {
+        let manager = ctx.server_manager.lock().await;
+        manager.check_owner(service_id, &caller)?;
+        manager.backup_target(&id)?
+    };
+
+    // Archive without holding the manager lock, so other jobs are not blocked meanwhile
+    let backup_id = ServerManager::take_backup(&ctx, &target, &options).await?;
+    ctx.server_manager
+        .lock()
+        .await
+        .schedule_backups(&id, options)
+        .await?;
+
+    Ok(TangleResult(backup_id))
+}
+
+/// Restore the data of a server from a backup and restart it
+///
+/// Returns the id of the restored backup.
+pub async fn server_restore(
+    Context(ctx): Context<MyContext>,
+    ServiceId(service_id): ServiceId,
+    Caller(caller): Caller,
+    TangleArg(request): TangleArg<RestoreRequest>,
+) -> Result<TangleResult<String>, Error> {
+    ctx.server_manager
+        .lock()
+        .await
+        .check_owner(service_id, &caller)?;
+    let id = ServerId::from_slot(service_id, request.slot.0)?;
+    // Restore without holding the manager lock, so other jobs are not blocked meanwhile
+    let backup_id = ServerManager::restore_server(&ctx, &id, request.backup_id.0).await?;
+
+    Ok(TangleResult(backup_id))
+}
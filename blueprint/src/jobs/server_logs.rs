@@ -0,0 +1,74 @@
+use blueprint_sdk::extract::Context;
+use blueprint_sdk::tangle::extract::{Caller, List, Optional, ServiceId, TangleArg, TangleResult};
+
+use crate::MyContext;
+use crate::error::Error;
+use crate::manager::ServerId;
+
+/// The default number of lines returned when no limit is given
+const DEFAULT_TAIL: u64 = 100;
+/// The maximum number of lines a single call can return
+const MAX_TAIL: u64 = 1000;
+/// The maximum total size of the returned lines, in bytes
+const MAX_RESULT_SIZE: usize = 256 * 1024;
+
+/// Which log lines to fetch
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogsQuery {
+    /// Only return the last `tail` lines
+    /// Defaults to 100 lines, at most 1000 lines are returned
+    #[serde(default)]
+    pub tail: Optional<u64>,
+    /// Only return lines captured at or after this time, in milliseconds since the UNIX epoch
+    #[serde(default)]
+    pub since: Optional<u64>,
+    /// Only return lines captured at or before this time, in milliseconds since the UNIX epoch
+    #[serde(default)]
+    pub until: Optional<u64>,
+    /// The slot of the server, defaults to the `default` slot
+    #[serde(default)]
+    pub slot: Optional<String>,
+}
+
+/// Fetch the captured stdout/stderr lines of a server
+///
+/// Each line is formatted as `{timestamp_ms} {stdout|stderr} {line}`.
+pub async fn server_logs(
+    Context(ctx): Context<MyContext>,
+    ServiceId(service_id): ServiceId,
+    Caller(caller): Caller,
+    TangleArg(query): TangleArg<LogsQuery>,
+) -> Result<TangleResult<List<String>>, Error> {
+    let id = ServerId::from_slot(service_id, query.slot.0)?;
+    {
+        let manager = ctx.server_manager.lock().await;
+        manager.check_owner(service_id, &caller)?;
+        manager.server(&id)?;
+    }
+
+    let tail = query.tail.0.unwrap_or(DEFAULT_TAIL).clamp(1, MAX_TAIL);
+    // A server that did not come back after an operator restart has no buffer
+    let lines = match ctx.logs.get(&id).await {
+        Some(buffer) => buffer.query(
+            usize::try_from(tail).unwrap_or(usize::MAX),
+            query.since.0,
+            query.until.0,
+        ),
+        None => Vec::new(),
+    };
+
+    // Keep the most recent lines that fit in the result
+    let mut size = 0;
+    let mut result: Vec<String> = lines
+        .iter()
+        .rev()
+        .map(ToString::to_string)
+        .take_while(|line| {
+            size += line.len();
+            size <= MAX_RESULT_SIZE
+        })
+        .collect();
+    result.reverse();
+    Ok(TangleResult(List(result)))
+}
@@ -0,0 +1,55 @@
+use blueprint_sdk::auth::proxy::DEFAULT_AUTH_PROXY_PORT;
+use blueprint_sdk::extract::Context;
+use blueprint_sdk::tangle::extract::{Caller, Optional, ServiceId, TangleArg, TangleResult};
+
+use crate::error::Error;
+use crate::manager::ServerId;
+use crate::manager::docker::DockerRunner;
+use crate::{MyContext, SlotConfig};
+
+/// Restart the server in the given slot, or in the default slot, with its current
+/// configuration
+///
+/// The server keeps its port and proxy route.
+pub async fn server_restart(
+    Context(ctx): Context<MyContext>,
+    ServiceId(service_id): ServiceId,
+    Caller(caller): Caller,
+    TangleArg(slot): TangleArg<Optional<String>>,
+) -> Result<TangleResult<String>, Error> {
+    let mut manager = ctx.server_manager.lock().await;
+    manager.check_owner(service_id, &caller)?;
+    let id = ServerId::from_slot(service_id, slot.0)?;
+    manager.restart_server(&ctx, &id).await?;
+    let endpoint = format!("http://127.0.0.1:{DEFAULT_AUTH_PROXY_PORT}");
+
+    Ok(TangleResult(endpoint))
+}
+
+/// Replace the server in a slot with one running the given configuration
+///
+/// The new server keeps the port and proxy route of the current one. If it fails
+/// to start, the current configuration is restored. A free slot gets a new server.
+pub async fn server_reconfigure(
+    Context(ctx): Context<MyContext>,
+    ServiceId(service_id): ServiceId,
+    Caller(caller): Caller,
+    TangleArg(request): TangleArg<SlotConfig>,
+) -> Result<TangleResult<String>, Error> {
+    ctx.server_manager
+        .lock()
+        .await
+        .check_owner(service_id, &caller)?;
+    let id = ServerId::from_slot(service_id, request.slot.0)?;
+    // Pull the image before locking the manager, so other jobs are not blocked meanwhile
+    DockerRunner.prefetch(&ctx, &id, &request.config).await?;
+
+    let mut manager = ctx.server_manager.lock().await;
+    manager.check_owner(service_id, &caller)?;
+    manager
+        .reconfigure_server(&ctx, &id, request.config)
+        .await?;
+    let endpoint = format!("http://127.0.0.1:{DEFAULT_AUTH_PROXY_PORT}");
+
+    Ok(TangleResult(endpoint))
+}
@@ -1,6 +1,4 @@
-use blueprint_sdk::auth::models::ServiceOwnerModel;
 use blueprint_sdk::auth::proxy::DEFAULT_AUTH_PROXY_PORT;
-use blueprint_sdk::auth::types::KeyType;
 use blueprint_sdk::tangle::extract::{BlockHash, List, ServiceId, TangleArg};
 use blueprint_sdk::tangle::serde::from_field;
 use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api;
@@ -9,10 +7,16 @@ use blueprint_sdk::{
 };
 use futures::TryFutureExt;
 
-use crate::MyContext;
 use crate::error::Error;
+use crate::manager::ServerId;
+use crate::manager::docker::DockerRunner;
+use crate::{MyContext, ServerConfig};
 
-/// Start the configured server
+/// Start the configured servers
+///
+/// The server from the request params runs in the `default` slot, the further
+/// servers each in their own slot. Requests to `/{slot}/...` are routed to the
+/// server in that slot, all others to the default one.
 pub async fn server_start(
     Context(ctx): Context<MyContext>,
     ServiceId(service_id): ServiceId,
@@ -46,35 +50,32 @@ pub async fn server_start(
         return Err(Error::MissingRequestParams);
     }
 
-    let config = from_field::<crate::RequestParams>(request_args.0.pop().unwrap())
-        .map(|p| p.config)
+    let params = from_field::<crate::RequestParams>(request_args.0.pop().unwrap())
         .map_err(Error::InvalidRequestParams)?;
+    let configs: Vec<(Option<String>, ServerConfig)> = std::iter::once((None, params.config))
+        .chain(
+            params
+                .servers
+                .0
+                .unwrap_or_default()
+                .0
+                .into_iter()
+                .map(|server| (server.slot.0, server.config)),
+        )
+        .collect();
 
-    blueprint_sdk::debug!(?config, %service_id, %owner, "Starting server with config");
-
-    let mut server_manager = ctx.server_manager.lock().await;
-    let endpoint = server_manager
-        .start_server(&ctx, service_id, owner.clone(), config)
-        .await?;
+    blueprint_sdk::debug!(?configs, %service_id, %owner, "Starting servers with configs");
 
-    let bridge = ctx.env.bridge().await?;
+    // Pull the images before locking the manager, so other jobs are not blocked meanwhile
+    futures::future::try_join_all(configs.iter().map(|(slot, config)| async {
+        let id = ServerId::from_slot(service_id, slot.clone())?;
+        DockerRunner.prefetch(&ctx, &id, config).await
+    }))
+    .await?;
 
-    bridge
-        .register_blueprint_service_proxy(
-            service_id,
-            Some("server_"),
-            &endpoint,
-            &[
-                ServiceOwnerModel {
-                    key_type: KeyType::Sr25519 as _,
-                    key_bytes: owner.0.to_vec(),
-                },
-                ServiceOwnerModel {
-                    key_type: KeyType::Ecdsa as _,
-                    key_bytes: ecdsa_owner,
-                },
-            ],
-        )
+    let mut server_manager = ctx.server_manager.lock().await;
+    server_manager
+        .start_servers(&ctx, service_id, owner, ecdsa_owner, configs)
         .await?;
 
     let endpoint = format!("http://127.0.0.1:{DEFAULT_AUTH_PROXY_PORT}");
@@ -0,0 +1,169 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blueprint_sdk::extract::Context;
+use blueprint_sdk::tangle::extract::{Caller, List, Optional, ServiceId, TangleArg, TangleResult};
+
+use crate::error::Error;
+use crate::manager::images::PullProgress;
+use crate::manager::status::ServerState;
+use crate::manager::{ServerId, port_path};
+use crate::{MyContext, PortProtocol, ServerRuntime};
+
+/// The health of a server, as reported to its owner
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerHealth {
+    /// The server is (re)starting and not serving requests yet
+    Starting,
+    /// The server is up and serving requests
+    Ready,
+    /// The server failed and will not be restarted
+    Crashed,
+    /// The server was stopped or exited on its own
+    Stopped,
+}
+
+impl From<ServerState> for ServerHealth {
+    fn from(state: ServerState) -> Self {
+        match state {
+            ServerState::Running => Self::Ready,
+            ServerState::Restarting => Self::Starting,
+            ServerState::Failed => Self::Crashed,
+            ServerState::Exited | ServerState::Stopped => Self::Stopped,
+        }
+    }
+}
+
+/// A container port published by a server
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortStatus {
+    /// The name of the port, if it was named in the server config
+    pub name: Optional<String>,
+    /// The port inside the container
+    pub container_port: u16,
+    /// The protocol of the port
+    pub protocol: PortProtocol,
+    /// The host port it is published on, bound to the loopback interface of the operator
+    pub host_port: u16,
+    /// The proxy path prefix of the port, if it is a named HTTP port
+    pub path: Optional<String>,
+}
+
+/// A running pull of the Docker image a server is started with
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PullStatus {
+    /// The image being pulled
+    pub image: String,
+    /// The bytes downloaded so far
+    pub downloaded_bytes: u64,
+    /// The size of the layers Docker has reported so far, which grows during the pull
+    pub total_bytes: u64,
+    /// How long the pull has been running, in seconds
+    pub elapsed_secs: u64,
+}
+
+impl From<PullProgress> for PullStatus {
+    fn from(progress: PullProgress) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        Self {
+            image: progress.image,
+            downloaded_bytes: progress.downloaded_bytes,
+            total_bytes: progress.total_bytes,
+            elapsed_secs: now.saturating_sub(progress.started_at),
+        }
+    }
+}
+
+/// The runtime health of a service's server
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStatus {
+    /// The slot the server runs in
+    pub slot: String,
+    /// The runtime the server runs on
+    pub runtime: ServerRuntime,
+    /// The package or Docker image as requested
+    pub package: String,
+    /// The resolved image digest or the pinned package version, if known
+    pub version: Optional<String>,
+    /// The health of the server
+    pub state: ServerHealth,
+    /// How long the server has been up, in seconds, if it is ready
+    pub uptime_secs: Optional<u64>,
+    /// How many times the server was restarted after a failure
+    pub restart_count: u32,
+    /// The exit code of the last run, if it exited
+    pub last_exit_code: Optional<i64>,
+    /// Whether the last run was killed for running out of memory
+    pub oom_killed: bool,
+    /// The server endpoint registered with the auth proxy, if it is proxied
+    pub endpoint: Optional<String>,
+    /// Whether the proxy route of the service could not be registered, so the server
+    /// may be unreachable until the service is restarted or reconfigured
+    pub degraded: bool,
+    /// The container ports the server publishes
+    pub ports: List<PortStatus>,
+    /// The port the TCP tunnel to the server listens on, if it has one
+    pub tunnel_port: Optional<u16>,
+    /// The image pull the server waits for, while it is (re)started with a new image
+    pub pull: Optional<PullStatus>,
+    /// The ids of the backups of the server, oldest first
+    pub backups: List<String>,
+    /// The slots of all servers of the service
+    pub slots: List<String>,
+}
+
+/// Get the runtime status of the server in the given slot, or in the default slot
+pub async fn server_status(
+    Context(ctx): Context<MyContext>,
+    ServiceId(service_id): ServiceId,
+    Caller(caller): Caller,
+    TangleArg(slot): TangleArg<Optional<String>>,
+) -> Result<TangleResult<ServerStatus>, Error> {
+    let manager = ctx.server_manager.lock().await;
+    manager.check_owner(service_id, &caller)?;
+    let id = ServerId::from_slot(service_id, slot.0)?;
+    let server = manager.server(&id)?;
+
+    let ports = server
+        .published_ports
+        .iter()
+        .map(|published| PortStatus {
+            name: Optional(published.name.clone()),
+            container_port: published.container_port,
+            protocol: published.protocol,
+            host_port: published.host_port,
+            path: Optional(
+                published
+                    .name
+                    .as_ref()
+                    .filter(|_| published.http)
+                    .map(|name| port_path(&id.slot, name)),
+            ),
+        })
+        .collect();
+    let status = server.status.get();
+    Ok(TangleResult(ServerStatus {
+        slot: id.slot.clone(),
+        runtime: server.runtime.clone(),
+        package: server.package.clone(),
+        version: Optional(server.version.clone()),
+        state: status.state.into(),
+        uptime_secs: Optional(status.uptime_secs()),
+        restart_count: status.restart_count,
+        last_exit_code: Optional(status.exit_code),
+        oom_killed: status.oom_killed,
+        endpoint: Optional(manager.endpoints.get(&service_id).cloned()),
+        degraded: manager.is_degraded(service_id),
+        ports: List(ports),
+        tunnel_port: Optional(server.tunnel.as_ref().and(server.tunnel_port)),
+        pull: Optional(ctx.images.progress(&id).map(PullStatus::from)),
+        backups: List(ctx.backups.list(&id).await?),
+        slots: List(manager.slots(service_id)),
+    }))
+}
@@ -1,21 +1,34 @@
 use blueprint_sdk::extract::Context;
 use blueprint_sdk::tangle::extract::TangleResult;
-use blueprint_sdk::tangle::extract::{ServiceId, TangleArg};
+use blueprint_sdk::tangle::extract::{Caller, Optional, ServiceId, TangleArg};
 
 use crate::MyContext;
 use crate::error::Error;
+use crate::manager::ServerId;
 
-/// Stop the configured server
+/// Stop the server in the given slot, or all servers if no slot is given
+///
+/// The argument used to be `()`, callers now pass an optional slot name instead.
+/// Only the service owner may stop its servers. Returns `false` if nothing was
+/// stopped, e.g. because the service has no servers.
 pub async fn server_stop(
     Context(ctx): Context<MyContext>,
     ServiceId(service_id): ServiceId,
-    TangleArg(_): TangleArg<()>,
+    Caller(caller): Caller,
+    TangleArg(slot): TangleArg<Optional<String>>,
 ) -> Result<TangleResult<bool>, Error> {
     let mut manager = ctx.server_manager.lock().await;
-    let stopped = manager.stop_server(service_id).await?;
-    let bridge = ctx.env.bridge().await?;
-    bridge
-        .unregister_blueprint_service_proxy(service_id)
-        .await?;
+    // A service without servers has no owner to check against
+    if manager.slots(service_id).is_empty() {
+        return Ok(TangleResult(false));
+    }
+    manager.check_owner(service_id, &caller)?;
+    let stopped = match slot.0 {
+        Some(slot) => {
+            let id = ServerId::from_slot(service_id, Some(slot))?;
+            manager.stop_server(&ctx, &id).await?
+        }
+        None => manager.stop_service(&ctx, service_id).await?,
+    };
     Ok(TangleResult(stopped))
 }
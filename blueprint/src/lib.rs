@@ -1,11 +1,33 @@
+use crate::config::OperatorConfig;
 use crate::manager::ServerManager;
+use crate::manager::backups::BackupStore;
+use crate::manager::binary::Artifact;
+use crate::manager::command::CommandRunner;
+use crate::manager::compose::Stack;
+use crate::manager::events::DockerEventsWatcher;
+use crate::manager::images::ImagePuller;
+use crate::manager::js::JsRunner;
+use crate::manager::logs::LogStore;
+use crate::manager::policy::Policy;
+use crate::manager::ports::PortAllocator;
+use crate::manager::python::PythonRunner;
+use crate::manager::registry::OperatorKey;
+use crate::manager::state::StateStore;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use blueprint_sdk::auth::models::ServiceOwnerModel;
+use blueprint_sdk::auth::types::KeyType;
 use blueprint_sdk::macros::context::ServicesContext;
 use blueprint_sdk::runner::config::BlueprintEnvironment;
 use blueprint_sdk::tangle::extract::{List, Optional, TangleArg};
+use blueprint_sdk::tangle_subxt::subxt::utils::AccountId32;
 use docktopus::bollard::Docker;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// The operator configuration
+pub mod config;
 /// Different types of errors that can occur in the server
 mod error;
 /// Blueprint Jobs
@@ -13,12 +35,23 @@ mod jobs;
 /// The server manager
 mod manager;
 
-
-pub use jobs::{SERVER_START_JOB_ID, SERVER_STOP_JOB_ID, server_start, server_stop};
+pub use error::Error;
+pub use jobs::{
+    LogsQuery, PortStatus, PullStatus, RestoreRequest, SERVER_BACKUP_JOB_ID, SERVER_LOGS_JOB_ID,
+    SERVER_RECONFIGURE_JOB_ID, SERVER_RESTART_JOB_ID, SERVER_RESTORE_JOB_ID, SERVER_START_JOB_ID,
+    SERVER_STATUS_JOB_ID, SERVER_STOP_JOB_ID, ServerHealth, ServerStatus, server_backup,
+    server_logs, server_reconfigure, server_restart, server_restore, server_start, server_status,
+    server_stop,
+};
+pub use manager::runtimes::{DynServerRunner, RuntimeRegistry};
+pub use manager::status::{RuntimeStatus, ServerState, SharedStatus};
+pub use manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
 
 /// Represents the runtime of the server (Python, JS, Docker etc.)
-#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
-#[serde(rename_all = "lowercase")]
+///
+/// Runtimes are named in lowercase, any name that is not built in refers to a runtime
+/// in the [`RuntimeRegistry`] of the operator.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ServerRuntime {
     /// Unknown runtime
     #[default]
@@ -29,6 +62,74 @@ pub enum ServerRuntime {
     Javascript,
     /// using a docker container to run the server
     Docker,
+    /// Builds a Docker image from a Dockerfile and runs it like the Docker runtime
+    Dockerfile,
+    /// Runs a stack of containers from a Docker Compose document
+    Compose,
+    /// Downloads a native binary, checks its digest and runs it as a process
+    Binary,
+    /// Serves a WebAssembly HTTP component in a sandbox inside the operator
+    Wasm,
+    /// A runtime registered by the operator, by its name
+    Custom(String),
+}
+
+impl ServerRuntime {
+    /// The runtime of a name
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "unknown" => Self::Unknown,
+            "python" => Self::Python,
+            "javascript" => Self::Javascript,
+            "docker" => Self::Docker,
+            "dockerfile" => Self::Dockerfile,
+            "compose" => Self::Compose,
+            "binary" => Self::Binary,
+            "wasm" => Self::Wasm,
+            name => Self::Custom(name.to_string()),
+        }
+    }
+
+    /// The name of the runtime
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Python => "python",
+            Self::Javascript => "javascript",
+            Self::Docker => "docker",
+            Self::Dockerfile => "dockerfile",
+            Self::Compose => "compose",
+            Self::Binary => "binary",
+            Self::Wasm => "wasm",
+            Self::Custom(name) => name,
+        }
+    }
+
+    /// Whether the runtime runs a server in a single Docker container
+    pub fn is_container(&self) -> bool {
+        matches!(self, Self::Docker | Self::Dockerfile)
+    }
+
+    /// Whether the runtime can enforce a disk limit
+    ///
+    /// Only containers have a writable layer of a fixed size, processes can write
+    /// anywhere the operator can.
+    pub fn limits_disk(&self) -> bool {
+        matches!(self, Self::Docker | Self::Dockerfile | Self::Compose)
+    }
+}
+
+impl serde::Serialize for ServerRuntime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ServerRuntime {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(Self::from_name(&name))
+    }
 }
 
 #[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -39,6 +140,8 @@ pub struct ServerConfig {
     /// The package to use for the server or the docker image
     ///
     /// Example: `my-package@x.y.z` for Python or JS, or `nginx:latest` for Docker
+    /// For the Dockerfile and Compose runtimes it only names the build or stack, e.g. `my-app`
+    /// For the Binary and Wasm runtimes it is the URL or absolute path of the artifact
     pub package: String,
     /// A list of arguments to pass to the server
     /// This is optional and can be empty
@@ -48,82 +151,1031 @@ pub struct ServerConfig {
     /// This is optional and can be empty
     #[serde(default)]
     pub env: Optional<List<(String, String)>>,
+    /// How to tell that the server is ready to accept requests
+    /// Defaults to a TCP connect probe on the allocated port
+    #[serde(default)]
+    pub readiness: Optional<ReadinessConfig>,
+    /// The resources the server may use
+    /// Unset limits default to the operator maximums
+    #[serde(default)]
+    pub resources: Optional<ResourceLimits>,
+    /// Named volumes to mount into the server, only supported by the Docker runtime
+    /// Their data survives the server being stopped and started again
+    #[serde(default)]
+    pub volumes: Optional<List<VolumeMount>>,
+    /// Names for the container ports, only supported by the Docker runtime
+    /// Every port the image exposes is published, named or not
+    #[serde(default)]
+    pub ports: Optional<List<PortConfig>>,
+    /// An authenticated TCP tunnel to the server, for clients that do not speak HTTP
+    /// Example: a Postgres or Redis client
+    #[serde(default)]
+    pub tunnel: Optional<TunnelConfig>,
+    /// Credentials to pull the image from a private registry, only supported by the Docker runtime
+    #[serde(default)]
+    pub registry_auth: Optional<RegistryAuth>,
+    /// The digest the server's content is pinned to, starting it fails on a mismatch
+    /// Docker: the image digest, like `sha256:…`
+    /// Python: the SHA-256 of the package's distribution file, like `sha256:…`
+    /// JavaScript: the npm integrity of the package, like `sha512-…`
+    /// Binary and Wasm: the SHA-256 of the artifact as downloaded, like `sha256:…`, required
+    #[serde(default)]
+    pub digest: Optional<String>,
+    /// What to build the image from, only supported and required by the Dockerfile runtime
+    #[serde(default)]
+    pub build: Optional<BuildConfig>,
+    /// The Docker Compose document of the stack, only supported and required by the Compose runtime
+    #[serde(default)]
+    pub compose: Optional<String>,
+    /// The path of the binary or component in an archive, only supported by the Binary and Wasm runtimes
+    /// Defaults to the only executable file in the archive
+    #[serde(default)]
+    pub binary: Optional<String>,
+}
 
+impl ServerConfig {
+    /// Check that the configuration can be used to start a server on this operator
+    pub fn validate(&self, config: &OperatorConfig) -> Result<(), error::Error> {
+        let invalid = |reason: String| Err(error::Error::InvalidConfig(reason));
+        if matches!(self.runtime, ServerRuntime::Unknown) {
+            return Err(error::Error::UnknownRuntime);
+        }
+        if self.package.trim().is_empty() {
+            return invalid("the package must not be empty".to_string());
+        }
+        if self.package.contains('\0') {
+            return invalid("the package must not contain NUL bytes".to_string());
+        }
+        let args = self
+            .args
+            .0
+            .as_ref()
+            .map(|args| args.0.as_slice())
+            .unwrap_or_default();
+        if args.iter().any(|arg| arg.contains('\0')) {
+            return invalid("arguments must not contain NUL bytes".to_string());
+        }
+        let stack = match (&self.compose.0, &self.runtime) {
+            (Some(document), ServerRuntime::Compose) => Some(Stack::parse(document)?),
+            (None, ServerRuntime::Compose) => {
+                return invalid("the Compose runtime needs a `compose` document".to_string());
+            }
+            (Some(_), _) => {
+                return invalid("`compose` is only supported by the Compose runtime".to_string());
+            }
+            (None, _) => None,
+        };
+        if stack.is_some() && !args.is_empty() {
+            return invalid("the Compose runtime takes the commands from the document".to_string());
+        }
+        let env = self
+            .env
+            .0
+            .as_ref()
+            .map(|env| env.0.as_slice())
+            .unwrap_or_default();
+        for (key, value) in env {
+            if key.is_empty() || key.contains(['=', '\0']) || value.contains('\0') {
+                return invalid(format!("invalid environment variable `{key}`"));
+            }
+            if key == "PORT" {
+                return invalid("`PORT` is set by the blueprint".to_string());
+            }
+        }
+        if let Some(resources) = &self.resources.0 {
+            resources.validate(&config.limits)?;
+            if resources.disk_mb != 0 && !self.runtime.limits_disk() {
+                return invalid(format!(
+                    "the {} runtime cannot limit `diskMb`",
+                    self.runtime.name()
+                ));
+            }
+        }
+        let volumes = self
+            .volumes
+            .0
+            .as_ref()
+            .map(|volumes| volumes.0.as_slice())
+            .unwrap_or_default();
+        if !volumes.is_empty() && stack.is_some() {
+            return invalid("the volumes of a stack are declared in its document".to_string());
+        }
+        if !volumes.is_empty() && !self.runtime.is_container() {
+            return invalid("volumes are only supported by the Docker runtimes".to_string());
+        }
+        for (i, volume) in volumes.iter().enumerate() {
+            volume.validate()?;
+            if volumes[..i].iter().any(|other| other.name == volume.name) {
+                return invalid(format!("volume `{}` is declared twice", volume.name));
+            }
+            if volumes[..i].iter().any(|other| other.path == volume.path) {
+                return invalid(format!("two volumes are mounted at `{}`", volume.path));
+            }
+        }
+        let ports = self
+            .ports
+            .0
+            .as_ref()
+            .map(|ports| ports.0.as_slice())
+            .unwrap_or_default();
+        if !ports.is_empty() && stack.is_some() {
+            return invalid("the public ports of a stack are declared in its document".to_string());
+        }
+        if !ports.is_empty() && !self.runtime.is_container() {
+            return invalid("ports are only supported by the Docker runtimes".to_string());
+        }
+        for (i, port) in ports.iter().enumerate() {
+            port.validate()?;
+            if ports[..i].iter().any(|other| other.name == port.name) {
+                return invalid(format!("port `{}` is declared twice", port.name));
+            }
+            if ports[..i]
+                .iter()
+                .any(|other| other.port == port.port && other.protocol == port.protocol)
+            {
+                return invalid(format!(
+                    "port {}/{} is named twice",
+                    port.port, port.protocol
+                ));
+            }
+        }
+        if let Some(auth) = &self.registry_auth.0 {
+            if !self.runtime.is_container() && stack.is_none() {
+                return invalid(
+                    "registry credentials are only supported by the Docker runtimes".to_string(),
+                );
+            }
+            auth.validate(config)?;
+        }
+        // A probe command must never run on the operator itself
+        let exec_probe = self
+            .readiness
+            .0
+            .as_ref()
+            .is_some_and(|readiness| readiness.probe == ProbeKind::Exec);
+        if exec_probe && !self.runtime.is_container() && stack.is_none() {
+            return invalid(
+                "`exec` readiness probes are only supported by the Docker runtimes".to_string(),
+            );
+        }
+        if let Some(digest) = &self.digest.0 {
+            self.validate_digest(digest)?;
+        }
+        match (&self.build.0, &self.runtime) {
+            (Some(build), ServerRuntime::Dockerfile) => build.validate(config)?,
+            (None, ServerRuntime::Dockerfile) => {
+                return invalid("the Dockerfile runtime needs a `build`".to_string());
+            }
+            (Some(_), _) => {
+                return invalid("`build` is only supported by the Dockerfile runtime".to_string());
+            }
+            (None, _) => {}
+        }
+        if matches!(self.runtime, ServerRuntime::Binary | ServerRuntime::Wasm) {
+            self.validate_artifact(config)?;
+        } else if self.binary.0.is_some() {
+            return invalid(
+                "`binary` is only supported by the Binary and Wasm runtimes".to_string(),
+            );
+        }
+        if let Some(name) = self
+            .tunnel
+            .0
+            .as_ref()
+            .and_then(|tunnel| tunnel.port.0.as_ref())
+        {
+            // The public services of a stack are named TCP ports
+            if let Some(stack) = &stack {
+                if !stack.public_services().any(|service| service == name) {
+                    return invalid(format!("the tunnel service `{name}` is not public"));
+                }
+                return Ok(());
+            }
+            let Some(port) = ports.iter().find(|port| &port.name == name) else {
+                return invalid(format!("the tunnel port `{name}` is not declared"));
+            };
+            if port.protocol != PortProtocol::Tcp {
+                return invalid(format!("the tunnel port `{name}` must use TCP"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_artifact(&self, config: &OperatorConfig) -> Result<(), error::Error> {
+        let invalid = |reason: &str| Err(error::Error::InvalidConfig(reason.to_string()));
+        if matches!(self.runtime, ServerRuntime::Wasm) && !cfg!(feature = "wasm") {
+            return invalid("this operator is built without the Wasm runtime");
+        }
+        match Artifact::parse(&self.package) {
+            None => {
+                return invalid(
+                    "the artifact must be an `https://` or `http://` URL, or an absolute path",
+                );
+            }
+            Some(Artifact::File(_)) if !config.binaries.allow_local_files => {
+                return invalid("the operator does not allow local artifacts");
+            }
+            Some(_) => {}
+        }
+        if self.digest.0.is_none() {
+            return invalid("the artifact needs a `digest`");
+        }
+        if let Some(binary) = &self.binary.0 {
+            let path = std::path::Path::new(binary);
+            let relative = path
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+            if binary.is_empty() || binary.contains('\0') || !relative {
+                return invalid("`binary` must be a relative path inside the archive");
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_digest(&self, digest: &str) -> Result<(), error::Error> {
+        let invalid = |reason: &str| Err(error::Error::InvalidConfig(reason.to_string()));
+        let is_sha256 = digest.strip_prefix("sha256:").is_some_and(|hex| {
+            hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        });
+        let is_sha512_integrity = digest.strip_prefix("sha512-").is_some_and(|base64| {
+            base64.len() == 88
+                && base64
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+        });
+        // Only exact versions are immutable in the package registries
+        let pinned = match self.runtime {
+            ServerRuntime::Python => PythonRunner::requirement(&self.package).is_some(),
+            ServerRuntime::Javascript => JsRunner::pinned_version(&self.package).is_some(),
+            _ => true,
+        };
+        match self.runtime {
+            // A build is not reproducible, so its image cannot be pinned
+            ServerRuntime::Dockerfile => {
+                invalid("`digest` is not supported by the Dockerfile runtime")
+            }
+            ServerRuntime::Compose => {
+                invalid("`digest` is not supported by the Compose runtime, pin the images instead")
+            }
+            ServerRuntime::Docker
+            | ServerRuntime::Python
+            | ServerRuntime::Binary
+            | ServerRuntime::Wasm
+                if !is_sha256 =>
+            {
+                invalid("`digest` must be `sha256:` followed by 64 lowercase hex digits")
+            }
+            ServerRuntime::Javascript if !is_sha512_integrity => {
+                invalid("`digest` must be an npm integrity starting with `sha512-`")
+            }
+            _ if !pinned => invalid("a package with a `digest` must be pinned to an exact version"),
+            _ => Ok(()),
+        }
+    }
 }
 
+/// Credentials to pull the image of a Docker server from a private registry
+///
+/// Exactly one of `name` and `encrypted` must be set.
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryAuth {
+    /// The name of credentials stored in the operator config
+    #[serde(default)]
+    pub name: Optional<String>,
+    /// Hex-encoded credentials, encrypted to the operator's ECDSA key with ECIES
+    /// Example: the encryption of `{"username": "me", "password": "…"}`
+    #[serde(default)]
+    pub encrypted: Optional<String>,
+}
 
+impl RegistryAuth {
+    fn validate(&self, config: &OperatorConfig) -> Result<(), error::Error> {
+        let invalid = |reason: String| Err(error::Error::InvalidConfig(reason));
+        match (&self.name.0, &self.encrypted.0) {
+            (Some(name), None) if !config.registries.contains_key(name) => {
+                invalid(format!("unknown registry credentials `{name}`"))
+            }
+            (Some(_), None) => Ok(()),
+            (None, Some(encrypted)) => {
+                if hex::decode(encrypted.trim_start_matches("0x")).is_err() {
+                    return invalid("the encrypted registry credentials are not hex".to_string());
+                }
+                Ok(())
+            }
+            _ => invalid("registry credentials need either a `name` or `encrypted`".to_string()),
+        }
+    }
+}
+
+/// What the Dockerfile runtime builds the image of a server from
+///
+/// At least one of `dockerfile` and `context` must be set. An inline Dockerfile is
+/// built with the context if there is one, and with an empty context otherwise.
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildConfig {
+    /// The contents of the Dockerfile
+    #[serde(default)]
+    pub dockerfile: Optional<String>,
+    /// The build context, a base64-encoded tarball that may be gzipped
+    #[serde(default)]
+    pub context: Optional<String>,
+    /// The path of the Dockerfile in the context, if no inline `dockerfile` is given
+    /// Defaults to `Dockerfile`
+    #[serde(default)]
+    pub dockerfile_path: Optional<String>,
+    /// Build arguments, the values of `ARG` instructions
+    #[serde(default)]
+    pub args: Optional<List<(String, String)>>,
+    /// The stage of a multi-stage Dockerfile to build, defaults to the last one
+    #[serde(default)]
+    pub target: Optional<String>,
+}
+
+impl BuildConfig {
+    fn validate(&self, config: &OperatorConfig) -> Result<(), error::Error> {
+        let invalid = |reason: String| Err(error::Error::InvalidConfig(reason));
+        let max_context_bytes = config.builds.max_context_bytes();
+        match (&self.dockerfile.0, &self.context.0) {
+            (None, None) => {
+                return invalid("a build needs a `dockerfile` or a `context`".to_string());
+            }
+            (Some(dockerfile), _) if dockerfile.trim().is_empty() => {
+                return invalid("the Dockerfile must not be empty".to_string());
+            }
+            (Some(dockerfile), _) if dockerfile.len() as u64 > max_context_bytes => {
+                return invalid("the Dockerfile is too large".to_string());
+            }
+            _ => {}
+        }
+        if let Some(context) = &self.context.0 {
+            let Ok(context) = BASE64.decode(context) else {
+                return invalid("the build context is not base64".to_string());
+            };
+            if context.len() as u64 > max_context_bytes {
+                return invalid(format!(
+                    "the build context is larger than {} MiB",
+                    config.builds.max_context_mb
+                ));
+            }
+        }
+        if let Some(path) = &self.dockerfile_path.0 {
+            if self.dockerfile.0.is_some() {
+                return invalid(
+                    "`dockerfilePath` cannot be used with an inline `dockerfile`".to_string(),
+                );
+            }
+            let path = std::path::Path::new(path);
+            if path.as_os_str().is_empty()
+                || !path
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_)))
+            {
+                return invalid(
+                    "`dockerfilePath` must be a relative path in the context".to_string(),
+                );
+            }
+        }
+        let args = self
+            .args
+            .0
+            .as_ref()
+            .map(|args| args.0.as_slice())
+            .unwrap_or_default();
+        for (i, (key, _)) in args.iter().enumerate() {
+            if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                return invalid(format!("invalid build argument name `{key}`"));
+            }
+            if args[..i].iter().any(|(other, _)| other == key) {
+                return invalid(format!("build argument `{key}` is set twice"));
+            }
+        }
+        if let Some(target) = &self.target.0 {
+            if target.is_empty() || target.contains(char::is_whitespace) {
+                return invalid(format!("invalid build target `{target}`"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An authenticated TCP tunnel to a server
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelConfig {
+    /// The name of the TCP port the tunnel leads to
+    /// Defaults to the port the server is started on
+    #[serde(default)]
+    pub port: Optional<String>,
+}
+
+/// The transport protocol of a port
+#[derive(
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for PortProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => f.write_str("tcp"),
+            Self::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+/// A named port of a container
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortConfig {
+    /// The port inside the container
+    /// It is published even if the image does not expose it
+    pub port: u16,
+    /// The protocol of the port
+    #[serde(default)]
+    pub protocol: PortProtocol,
+    /// The name of the port, unique per server
+    /// Up to 32 lowercase ASCII letters, digits and `_`
+    pub name: String,
+    /// Whether the port serves HTTP and gets its own proxy route at `/{slot}/{name}`
+    #[serde(default)]
+    pub http: bool,
+}
+
+impl PortConfig {
+    fn validate(&self) -> Result<(), error::Error> {
+        if !manager::is_valid_name(&self.name) {
+            return Err(error::Error::InvalidConfig(format!(
+                "invalid port name `{}`",
+                self.name
+            )));
+        }
+        if self.port == 0 {
+            return Err(error::Error::InvalidConfig(format!(
+                "port `{}` must not be 0",
+                self.name
+            )));
+        }
+        if self.http && self.protocol != PortProtocol::Tcp {
+            return Err(error::Error::InvalidConfig(format!(
+                "HTTP port `{}` must use TCP",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A named volume mounted into a server
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeMount {
+    /// The name of the volume, unique per server
+    /// Up to 64 ASCII letters, digits, `_`, `.` and `-`
+    pub name: String,
+    /// The absolute path the volume is mounted at
+    /// Example: `/var/lib/postgresql/data`
+    pub path: String,
+    /// Whether the server may only read the volume
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl VolumeMount {
+    fn validate(&self) -> Result<(), error::Error> {
+        let valid_name = (1..=64).contains(&self.name.len())
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        if !valid_name {
+            return Err(error::Error::InvalidConfig(format!(
+                "invalid volume name `{}`",
+                self.name
+            )));
+        }
+        if !self.path.starts_with('/') || self.path == "/" || self.path.contains('\0') {
+            return Err(error::Error::InvalidConfig(format!(
+                "volume `{}` must be mounted at an absolute path other than `/`",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Resource limits of a server
+///
+/// A zero value means the operator maximum, or no limit if the operator has none.
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceLimits {
+    /// The CPU time the server may use, in thousandths of a CPU
+    /// Example: `1500` for one and a half CPUs
+    #[serde(default)]
+    pub cpu_millis: u64,
+    /// The relative CPU weight of the server under contention, as Docker CPU shares
+    /// Defaults to 1024 when zero
+    #[serde(default)]
+    pub cpu_shares: u64,
+    /// The memory limit, in MiB
+    #[serde(default)]
+    pub memory_mb: u64,
+    /// The swap the server may use on top of its memory limit, in MiB
+    /// Zero means no swap when there is a memory limit
+    #[serde(default)]
+    pub swap_mb: u64,
+    /// The maximum number of processes and threads
+    #[serde(default)]
+    pub pids: u64,
+    /// The disk limit, in MiB
+    /// Only supported by the Docker runtimes, where it limits the writable layer of
+    /// the containers
+    #[serde(default)]
+    pub disk_mb: u64,
+}
+
+impl ResourceLimits {
+    /// The smallest CPU limit, the kernel rejects CPU quotas below a hundredth of a CPU
+    pub const MIN_CPU_MILLIS: u64 = 10;
+
+    /// Check the limits against the operator maximums
+    pub fn validate(&self, max: &config::LimitsConfig) -> Result<(), error::Error> {
+        if self.cpu_millis != 0 && self.cpu_millis < Self::MIN_CPU_MILLIS {
+            return Err(error::Error::InvalidConfig(format!(
+                "`cpuMillis` must be at least {}, the kernel cannot enforce a smaller CPU quota",
+                Self::MIN_CPU_MILLIS
+            )));
+        }
+        let checks = [
+            ("cpuMillis", self.cpu_millis, max.max_cpu_millis),
+            ("memoryMb", self.memory_mb, max.max_memory_mb),
+            ("swapMb", self.swap_mb, max.max_swap_mb),
+            ("pids", self.pids, max.max_pids),
+            ("diskMb", self.disk_mb, max.max_disk_mb),
+        ];
+        for (name, value, max) in checks {
+            if max != 0 && value > max {
+                return Err(error::Error::InvalidConfig(format!(
+                    "`{name}` of {value} exceeds the operator maximum of {max}"
+                )));
+            }
+        }
+        if self.cpu_shares != 0 && !(2..=262_144).contains(&self.cpu_shares) {
+            return Err(error::Error::InvalidConfig(
+                "`cpuShares` must be between 2 and 262144".to_string(),
+            ));
+        }
+        if self.swap_mb != 0 && self.memory_mb == 0 && max.max_memory_mb == 0 {
+            return Err(error::Error::InvalidConfig(
+                "`swapMb` requires a memory limit".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The limits with the unset ones replaced by the operator maximums
+    ///
+    /// The disk maximum only applies to the runtimes that can enforce it.
+    pub fn with_defaults(&self, max: &config::LimitsConfig, runtime: &ServerRuntime) -> Self {
+        let or_max = |value: u64, max: u64| if value == 0 { max } else { value };
+        Self {
+            cpu_millis: or_max(self.cpu_millis, max.max_cpu_millis),
+            cpu_shares: self.cpu_shares,
+            memory_mb: or_max(self.memory_mb, max.max_memory_mb),
+            swap_mb: self.swap_mb,
+            pids: or_max(self.pids, max.max_pids),
+            disk_mb: if runtime.limits_disk() {
+                or_max(self.disk_mb, max.max_disk_mb)
+            } else {
+                0
+            },
+        }
+    }
+}
+
+/// How to back up a server
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupOptions {
+    /// Pause the server while its data is archived, for a consistent snapshot
+    #[serde(default)]
+    pub pause: bool,
+    /// Back the server up again every this many hours
+    /// Zero disables scheduled backups
+    #[serde(default)]
+    pub interval_hours: u64,
+    /// How many backups to keep, older ones are deleted
+    /// Defaults to the operator maximum when zero
+    #[serde(default)]
+    pub keep: u64,
+    /// The slot of the server to back up, defaults to the `default` slot
+    #[serde(default)]
+    pub slot: Optional<String>,
+}
+
+impl BackupOptions {
+    /// Check the options against the operator configuration
+    pub fn validate(&self, config: &config::BackupsConfig) -> Result<(), error::Error> {
+        if self.interval_hours != 0 && self.interval_hours < config.min_interval_hours {
+            return Err(error::Error::InvalidConfig(format!(
+                "`intervalHours` must be at least {}",
+                config.min_interval_hours
+            )));
+        }
+        if self.keep > config.max_backups {
+            return Err(error::Error::InvalidConfig(format!(
+                "`keep` of {} exceeds the operator maximum of {}",
+                self.keep, config.max_backups
+            )));
+        }
+        Ok(())
+    }
+
+    /// How many backups to keep
+    pub fn keep(&self, config: &config::BackupsConfig) -> usize {
+        let keep = if self.keep == 0 {
+            config.max_backups
+        } else {
+            self.keep
+        };
+        usize::try_from(keep).unwrap_or(usize::MAX)
+    }
+}
+
+/// The kind of readiness probe to run against a starting server
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// Succeeds once a TCP connection to the server port can be established
+    #[default]
+    Tcp,
+    /// Succeeds once a `GET` on the target path returns a 2xx or 3xx status
+    Http,
+    /// Succeeds once the target command exits with status 0
+    ///
+    /// The command runs inside the container, so only the container runtimes support it
+    Exec,
+    /// Succeeds once the server prints a line containing the target
+    Log,
+}
+
+/// Readiness probe configuration
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadinessConfig {
+    /// The kind of probe to run
+    #[serde(default)]
+    pub probe: ProbeKind,
+    /// The HTTP path, the command to run or the log line to wait for
+    /// Unused by the TCP probe
+    #[serde(default)]
+    pub target: String,
+    /// How long to wait for the server to become ready, in seconds
+    /// Defaults to 60 seconds when zero
+    #[serde(default)]
+    pub timeout_secs: u64,
+    /// The delay between two probe attempts, in milliseconds
+    /// Defaults to 500 milliseconds when zero
+    #[serde(default)]
+    pub interval_ms: u64,
+    /// The maximum number of failed probe attempts before giving up
+    /// Zero means the probe is retried until the timeout
+    #[serde(default)]
+    pub retries: u64,
+}
 
 /// The Service Request Parameters
 #[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RequestParams {
+    /// The server to run in the `default` slot
+    pub config: ServerConfig,
+    /// Further servers to run next to it, each in its own slot
+    #[serde(default)]
+    pub servers: Optional<List<SlotConfig>>,
+}
+
+/// The configuration of the server in a slot
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SlotConfig {
+    /// The slot of the server, defaults to the `default` slot
+    /// Up to 32 lowercase ASCII letters, digits and `_`
+    #[serde(default)]
+    pub slot: Optional<String>,
+    /// The configuration of the server
     pub config: ServerConfig,
 }
 
+/// How often unused volumes are looked for
+const VOLUME_GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// How often the backup schedules are checked
+const BACKUP_SCHEDULE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
 #[derive(Clone, ServicesContext)]
 pub struct MyContext {
     #[config]
     env: BlueprintEnvironment,
     pub server_manager: Arc<Mutex<ServerManager>>,
     pub docker: Arc<Docker>,
+    /// Keeps the status of the managed containers in sync with the Docker daemon
+    pub docker_events: DockerEventsWatcher,
+    /// The captured output of the servers
+    pub logs: LogStore,
+    /// The operator configuration
+    pub config: OperatorConfig,
+    /// Pulls the Docker images of the servers
+    pub images: ImagePuller,
+    /// Hands out the host ports of the servers
+    pub ports: PortAllocator,
+    /// Hands out the ports of the TCP tunnels
+    pub tunnel_ports: PortAllocator,
+    /// The key owners encrypt their registry credentials to, if the operator has one
+    pub operator_key: Option<OperatorKey>,
+    /// Which runtimes, images and packages servers may use
+    pub policy: Policy,
+    /// The backups of the servers
+    pub backups: BackupStore,
+    /// The runtimes the servers can run on
+    pub runtimes: RuntimeRegistry,
 }
 
 impl MyContext {
     pub async fn new(env: BlueprintEnvironment) -> Result<Self, error::Error> {
+        Self::with_runtimes(env, RuntimeRegistry::new()).await
+    }
+
+    /// Create the context with the runtimes of `runtimes`, usually the built-in ones and
+    /// some registered on top
+    ///
+    /// The command runtimes of the operator config are registered in addition.
+    pub async fn with_runtimes(
+        env: BlueprintEnvironment,
+        mut runtimes: RuntimeRegistry,
+    ) -> Result<Self, error::Error> {
         let docker_builder = docktopus::DockerBuilder::new().await.map_err(|e| {
             crate::error::Error::Io(std::io::Error::other(format!(
                 "Failed to create Docker client: {e}"
             )))
         })?;
+        let data_dir = env.data_dir.join("server-blueprint");
+        let config = OperatorConfig::load(&data_dir).await?;
+        let images = ImagePuller::load(
+            config.images.pull_timeout(),
+            data_dir.join("private-images.json"),
+        )
+        .await?;
+        let ports = PortAllocator::new(config.ports.range());
+        let tunnel_ports = PortAllocator::new(config.tunnels.range());
+        let policy = Policy::new(&config.policy)?;
+        let logs = LogStore::new(data_dir.join("logs"));
+        // The command runtimes of the operator config
+        for (name, runtime) in &config.runtimes {
+            runtimes.register(name, CommandRunner::new(name, runtime.clone()))?;
+        }
+        let backups = BackupStore::new(
+            config
+                .backups
+                .dir
+                .clone()
+                .unwrap_or_else(|| data_dir.join("backups")),
+        );
+        let operator_key = OperatorKey::load(&env);
+        let store = StateStore::new(data_dir);
+        let server_manager = ServerManager::load(store).await?;
+        let docker = docker_builder.client();
         Ok(Self {
             env,
-            server_manager: Arc::new(Mutex::new(ServerManager::default())),
-            docker: docker_builder.client(),
+            server_manager: Arc::new(Mutex::new(server_manager)),
+            docker_events: DockerEventsWatcher::spawn(docker.clone()),
+            docker,
+            logs,
+            config,
+            images,
+            ports,
+            tunnel_ports,
+            operator_key,
+            policy,
+            backups,
+            runtimes,
         })
     }
-    /// Finds the next available port by binding to localhost:0 and retrieving the assigned port.
-    ///
-    /// This function uses the OS's ability to assign an available port when binding to port 0.
-    /// The TCP listener is immediately closed after retrieving the port number.
-    ///
-    /// # Important: Race Condition Warning
-    ///
-    /// There is an inherent race condition in this approach: between the time the port is
-    /// released (when the listener is dropped) and when the caller attempts to use the port,
-    /// another process may bind to the same port. This is a fundamental limitation of this
-    /// approach and should be considered when using this function, but this scenario is
-    /// unlikely in practice for most applications.
-    ///
-    /// For critical applications, consider keeping the listener alive and passing it to the
-    /// caller, or using a more sophisticated port management strategy.
-    ///
-    /// # Returns
+
+    /// The working directory of a process server
+    pub fn work_dir(&self, id: &ServerId) -> PathBuf {
+        self.env
+            .data_dir
+            .join("server-blueprint")
+            .join("work")
+            .join(id.dir_name())
+    }
+
+    /// The directory the artifacts of the Binary and Wasm servers of a service are cached in
+    pub fn binary_cache(&self, service_id: u64) -> PathBuf {
+        self.env
+            .data_dir
+            .join("server-blueprint")
+            .join("binaries")
+            .join(service_id.to_string())
+    }
+
+    /// Periodically delete the volumes no server uses once their retention expired
     ///
-    /// Returns the port number that was available at the time of testing.
+    /// This should be called once at startup, after the servers were reconciled.
+    pub fn spawn_volume_gc(&self) {
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(VOLUME_GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                let collected = ServerManager::collect_volumes(&ctx).await;
+                if let Err(e) = collected {
+                    blueprint_sdk::debug!(?e, "Failed to collect unused volumes");
+                }
+            }
+        });
+    }
+
+    /// Periodically take the scheduled backups of the servers
     ///
-    /// # Errors
+    /// This should be called once at startup, after the servers were reconciled.
+    pub fn spawn_backup_scheduler(&self) {
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(BACKUP_SCHEDULE_INTERVAL);
+            loop {
+                interval.tick().await;
+                ServerManager::run_scheduled_backups(&ctx).await;
+            }
+        });
+    }
+
+    /// Re-adopt the servers that were running before the operator was restarted
     ///
-    /// Returns an error if:
-    /// - Unable to bind to localhost (network issues, permission problems)
-    /// - Unable to retrieve the local address from the bound socket
-    pub async fn next_available_port(&self) -> Result<u16, error::Error> {
-        let tcp = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
-            .await
-            .map_err(|e| {
-                error::Error::Io(std::io::Error::other(format!(
-                    "Failed to bind TCP listener: {e}"
-                )))
-            })?;
-        let local_addr = tcp.local_addr().map_err(|e| {
-            error::Error::Io(std::io::Error::other(format!(
-                "Failed to get local address: {e}"
-            )))
-        })?;
-        // Close the listener immediately after getting the port
-        drop(tcp);
-        Ok(local_addr.port())
+    /// This should be called once at startup, before any jobs are processed.
+    pub async fn reconcile_servers(&self) -> Result<(), error::Error> {
+        self.server_manager.lock().await.reconcile(self).await
+    }
+
+    /// Register the auth proxy route for a service's servers
+    pub async fn register_server_proxy(
+        &self,
+        service_id: u64,
+        endpoint: &str,
+        owner: &AccountId32,
+        ecdsa_owner: &[u8],
+    ) -> Result<(), error::Error> {
+        let bridge = self.env.bridge().await?;
+        bridge
+            .register_blueprint_service_proxy(
+                service_id,
+                Some("server_"),
+                endpoint,
+                &[
+                    ServiceOwnerModel {
+                        key_type: KeyType::Sr25519 as _,
+                        key_bytes: owner.0.to_vec(),
+                    },
+                    ServiceOwnerModel {
+                        key_type: KeyType::Ecdsa as _,
+                        key_bytes: ecdsa_owner.to_vec(),
+                    },
+                ],
+            )
+            .await?;
+        Ok(())
     }
 }
 
 /// The request parameters for this blueprint
 pub type BlueprintRequestParams = TangleArg<RequestParams>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn server(runtime: ServerRuntime, package: &str, digest: Option<&str>) -> ServerConfig {
+        ServerConfig {
+            runtime,
+            package: package.to_string(),
+            digest: Optional(digest.map(str::to_string)),
+            ..Default::default()
+        }
+    }
+
+    fn validate(config: &ServerConfig) -> Result<(), String> {
+        config
+            .validate(&OperatorConfig::default())
+            .map_err(|e| e.to_string())
+    }
+
+    fn integrity() -> String {
+        format!("sha512-{}==", "A".repeat(86))
+    }
+
+    #[test]
+    fn docker_digests_are_sha256() {
+        assert!(validate(&server(ServerRuntime::Docker, "nginx", Some(SHA256))).is_ok());
+        let uppercase = SHA256.to_uppercase().replace("SHA256", "sha256");
+        for digest in ["sha256:abc", "md5:0123", uppercase.as_str(), &integrity()] {
+            let err = validate(&server(ServerRuntime::Docker, "nginx", Some(digest))).unwrap_err();
+            assert!(err.contains("64 lowercase hex digits"), "{digest}: {err}");
+        }
+    }
+
+    #[test]
+    fn javascript_digests_are_npm_integrities() {
+        let integrity = integrity();
+        assert!(
+            validate(&server(
+                ServerRuntime::Javascript,
+                "left-pad@1.3.0",
+                Some(&integrity)
+            ))
+            .is_ok()
+        );
+        let err = validate(&server(
+            ServerRuntime::Javascript,
+            "left-pad@1.3.0",
+            Some(SHA256),
+        ))
+        .unwrap_err();
+        assert!(err.contains("npm integrity"), "{err}");
+    }
+
+    #[test]
+    fn packages_with_a_digest_need_an_exact_version() {
+        let integrity = integrity();
+        let pinned = [
+            (ServerRuntime::Python, "requests==2.31.0", SHA256),
+            (ServerRuntime::Python, "requests@2.31.0", SHA256),
+            (ServerRuntime::Javascript, "left-pad@1.3.0", &integrity),
+            (
+                ServerRuntime::Javascript,
+                "@scope/pkg@1.0.0-rc.1",
+                &integrity,
+            ),
+        ];
+        for (runtime, package, digest) in pinned {
+            assert!(
+                validate(&server(runtime, package, Some(digest))).is_ok(),
+                "{package}"
+            );
+        }
+        let unpinned = [
+            (ServerRuntime::Python, "requests", SHA256),
+            (ServerRuntime::Python, "requests==2.*", SHA256),
+            (ServerRuntime::Python, "requests@latest", SHA256),
+            (ServerRuntime::Javascript, "left-pad", &integrity),
+            (ServerRuntime::Javascript, "left-pad@^1.3.0", &integrity),
+            (ServerRuntime::Javascript, "@scope/pkg", &integrity),
+        ];
+        for (runtime, package, digest) in unpinned {
+            let err = validate(&server(runtime, package, Some(digest))).unwrap_err();
+            assert!(err.contains("exact version"), "{package}: {err}");
+        }
+        // Without a digest any version is fine
+        assert!(validate(&server(ServerRuntime::Python, "requests", None)).is_ok());
+    }
+
+    #[test]
+    fn built_images_cannot_be_pinned() {
+        let mut config = server(ServerRuntime::Dockerfile, "app", Some(SHA256));
+        config.build = Optional(Some(BuildConfig {
+            dockerfile: Optional(Some("FROM scratch".to_string())),
+            ..Default::default()
+        }));
+        let err = validate(&config).unwrap_err();
+        assert!(
+            err.contains("not supported by the Dockerfile runtime"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn artifacts_need_a_digest() {
+        let package = "https://example.com/app.tar.gz";
+        assert!(validate(&server(ServerRuntime::Binary, package, Some(SHA256))).is_ok());
+        let err = validate(&server(ServerRuntime::Binary, package, None)).unwrap_err();
+        assert!(err.contains("needs a `digest`"), "{err}");
+    }
+}
@@ -0,0 +1,398 @@
+//! Server data backups
+//!
+//! A backup is a gzipped tarball of a server's data, stored next to a `sha256sum`
+//! compatible checksum file in the operator-local backup directory:
+//!
+//! ```text
+//! {dir}/{server}/{backup_id}.tar.gz
+//! {dir}/{server}/{backup_id}.tar.gz.sha256
+//! ```
+//!
+//! where `{server}` is the service id, followed by `.{slot}` for servers outside the
+//! default slot. The backup id is the creation time in milliseconds since the UNIX
+//! epoch, so the backups of a server sort by age. Inside the archive, the Docker volumes are
+//! stored as `volumes/{name}.tar` and the working directory of a process server
+//! under `workdir/`.
+
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::manager::ServerId;
+use crate::manager::status::SharedStatus;
+
+/// The archive directory holding the Docker volumes
+pub const VOLUMES_DIR: &str = "volumes";
+/// The archive directory holding the working directory of a process server
+pub const WORK_DIR: &str = "workdir";
+
+/// Something to put into a backup
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// A file, stored under the given archive path
+    File { name: String, path: PathBuf },
+    /// The contents of a directory, stored under the given archive directory
+    Dir { name: String, path: PathBuf },
+}
+
+/// The backups of all servers
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    dir: PathBuf,
+}
+
+impl BackupStore {
+    /// Create a backup store that keeps the backups under `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory holding the backups of a server
+    pub fn server_dir(&self, id: &ServerId) -> PathBuf {
+        self.dir.join(id.dir_name())
+    }
+
+    fn archive_path(&self, id: &ServerId, backup_id: &str) -> PathBuf {
+        self.server_dir(id).join(format!("{backup_id}.tar.gz"))
+    }
+
+    fn checksum_path(&self, id: &ServerId, backup_id: &str) -> PathBuf {
+        self.server_dir(id)
+            .join(format!("{backup_id}.tar.gz.sha256"))
+    }
+
+    /// A directory for temporary files of the given backup, created empty
+    pub async fn scratch_dir(&self, id: &ServerId, backup_id: &str) -> Result<PathBuf, Error> {
+        let dir = self.server_dir(id).join(format!("{backup_id}.tmp"));
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(dir)
+    }
+
+    /// A new, unique backup id
+    pub fn new_id() -> String {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// The ids of the complete backups of a server, oldest first
+    pub async fn list(&self, id: &ServerId) -> Result<Vec<String>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.server_dir(id)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(backup_id) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".tar.gz.sha256"))
+            else {
+                continue;
+            };
+            if let Ok(ms) = backup_id.parse::<u128>() {
+                ids.push((ms, backup_id.to_string()));
+            }
+        }
+        ids.sort();
+        Ok(ids.into_iter().map(|(_, backup_id)| backup_id).collect())
+    }
+
+    /// Archive the given sources into a new backup of the server
+    ///
+    /// The checksum file is written last, so a backup without one is incomplete.
+    pub async fn create(
+        &self,
+        id: &ServerId,
+        backup_id: &str,
+        sources: Vec<Source>,
+    ) -> Result<(), Error> {
+        let archive = self.archive_path(id, backup_id);
+        let checksum = self.checksum_path(id, backup_id);
+        tokio::fs::create_dir_all(self.server_dir(id)).await?;
+
+        let partial = archive.with_extension("gz.partial");
+        let written = tokio::task::spawn_blocking({
+            let partial = partial.clone();
+            move || write_archive(&partial, &sources)
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+
+        let digest = sha256(partial.clone()).await?;
+        tokio::fs::rename(&partial, &archive).await?;
+        let file_name = archive
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        tokio::fs::write(&checksum, format!("{digest}  {file_name}\n")).await?;
+        Ok(())
+    }
+
+    /// Verify the checksum of a backup and return the path of its archive
+    pub async fn verify(&self, id: &ServerId, backup_id: &str) -> Result<PathBuf, Error> {
+        // Backup ids are timestamps, anything else could point outside the directory
+        if backup_id.parse::<u128>().is_err() {
+            return Err(Error::BackupNotFound(backup_id.to_string()));
+        }
+        let archive = self.archive_path(id, backup_id);
+        let expected = match tokio::fs::read_to_string(self.checksum_path(id, backup_id)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::BackupNotFound(backup_id.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let expected = expected.split_whitespace().next().unwrap_or_default();
+        if sha256(archive.clone()).await? != expected {
+            return Err(Error::BackupCorrupted(backup_id.to_string()));
+        }
+        Ok(archive)
+    }
+
+    /// Delete all but the `keep` newest backups of a server
+    pub async fn prune(&self, id: &ServerId, keep: usize) -> Result<(), Error> {
+        let ids = self.list(id).await?;
+        let excess = ids.len().saturating_sub(keep);
+        for backup_id in &ids[..excess] {
+            // Without its checksum the archive no longer counts as a backup
+            tokio::fs::remove_file(self.checksum_path(id, backup_id)).await?;
+            tokio::fs::remove_file(self.archive_path(id, backup_id)).await?;
+            blueprint_sdk::debug!(server = %id, %backup_id, "Deleted old backup");
+        }
+        Ok(())
+    }
+}
+
+/// Extract the entries under the archive directory `prefix` into `dest`
+///
+/// Returns `false` if the archive has no such directory.
+pub async fn extract(archive: &Path, prefix: &str, dest: &Path) -> Result<bool, Error> {
+    let archive = archive.to_path_buf();
+    let prefix = prefix.to_string();
+    let dest = dest.to_path_buf();
+    tokio::task::spawn_blocking(move || extract_blocking(&archive, &prefix, &dest))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Compute the hex-encoded SHA-256 digest of a file
+async fn sha256(path: PathBuf) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..read]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+fn write_archive(path: &Path, sources: &[Source]) -> Result<(), Error> {
+    let file = std::fs::File::create(path)?;
+    let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    // Keep symlinks as they are, rather than archiving what they point to
+    builder.follow_symlinks(false);
+    for source in sources {
+        match source {
+            Source::File { name, path } => builder.append_path_with_name(path, name)?,
+            Source::Dir { name, path } => builder.append_dir_all(name, path)?,
+        }
+    }
+    let mut file = builder.into_inner()?.finish()?;
+    file.flush()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn extract_blocking(archive: &Path, prefix: &str, dest: &Path) -> Result<bool, Error> {
+    let file = std::fs::File::open(archive)?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    std::fs::create_dir_all(dest)?;
+    let root = dest.canonicalize()?;
+    let mut found = false;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Ok(relative) = path.strip_prefix(prefix) else {
+            continue;
+        };
+        found = true;
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Backup entry {} escapes the archive",
+                path.display()
+            ))));
+        }
+
+        let target = root.join(relative);
+        let parent = target.parent().unwrap_or(&root);
+        std::fs::create_dir_all(parent)?;
+        // Earlier entries must not redirect later ones out of `dest` through a symlink
+        if !parent.canonicalize()?.starts_with(&root) {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Backup entry {} escapes the archive",
+                path.display()
+            ))));
+        }
+        entry.set_preserve_permissions(true);
+        entry.set_preserve_mtime(true);
+        entry.unpack(&target)?;
+    }
+    Ok(found)
+}
+
+/// What a backup needs to know about a server
+///
+/// A snapshot taken from the manager, so the backup can be archived without holding
+/// the manager lock.
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// The server to back up
+    pub id: ServerId,
+    /// The runtime of the server
+    pub runtime: crate::ServerRuntime,
+    /// The volumes of a Docker server
+    pub volumes: Vec<crate::VolumeMount>,
+    /// The container of a Docker server
+    pub container_id: Option<String>,
+    /// The status of the server, holding the pid of a process server
+    pub status: SharedStatus,
+}
+
+/// The backup schedule of a server
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Schedule {
+    /// How to take the scheduled backups
+    pub options: crate::BackupOptions,
+    /// When the last backup was taken, in seconds since the UNIX epoch
+    pub last_backup_at: u64,
+}
+
+impl Schedule {
+    /// Whether the next backup is due at `now`
+    pub fn is_due(&self, now: u64) -> bool {
+        let interval = self.options.interval_hours.saturating_mul(3600);
+        interval != 0 && now.saturating_sub(self.last_backup_at) >= interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp directory
+    fn scratch() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backups-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Append an entry with a raw path, which [`tar::Builder`] would refuse to write
+    fn append_raw<W: Write>(
+        builder: &mut tar::Builder<W>,
+        path: &str,
+        link: Option<&str>,
+        data: &[u8],
+    ) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        match link {
+            Some(link) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            None => header.set_entry_type(tar::EntryType::Regular),
+        }
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    /// Write an archive of the given raw entries, `(path, symlink target, data)`
+    fn archive(dir: &Path, entries: &[(&str, Option<&str>, &[u8])]) -> PathBuf {
+        let path = dir.join("backup.tar.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::fast(),
+        ));
+        for (entry, link, data) in entries {
+            append_raw(&mut builder, entry, *link, data);
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn extracts_the_entries_under_the_prefix() {
+        let dir = scratch();
+        let archive = archive(
+            &dir,
+            &[
+                ("workdir/a/b.txt", None, b"data"),
+                ("volumes/db.tar", None, b"volume"),
+            ],
+        );
+        let dest = dir.join("dest");
+        assert!(extract_blocking(&archive, WORK_DIR, &dest).unwrap());
+        assert_eq!(std::fs::read(dest.join("a/b.txt")).unwrap(), b"data");
+        assert!(!dest.join("volumes").exists());
+        assert!(!extract_blocking(&archive, "missing", &dir.join("other")).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_entries_leaving_the_destination() {
+        let dir = scratch();
+        let archive = archive(&dir, &[("workdir/../escaped.txt", None, b"data")]);
+        let dest = dir.join("dest");
+        assert!(extract_blocking(&archive, WORK_DIR, &dest).is_err());
+        assert!(!dir.join("escaped.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_entries_through_extracted_symlinks() {
+        let dir = scratch();
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        let archive = archive(
+            &dir,
+            &[
+                ("workdir/link", Some(outside.to_str().unwrap()), b""),
+                ("workdir/link/escaped.txt", None, b"data"),
+            ],
+        );
+        let dest = dir.join("dest");
+        assert!(extract_blocking(&archive, WORK_DIR, &dest).is_err());
+        assert!(!outside.join("escaped.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
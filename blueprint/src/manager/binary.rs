@@ -0,0 +1,428 @@
+//! Native binaries
+//!
+//! The Binary runtime runs a prebuilt executable, fetched from a URL or, if the
+//! operator allows it, from a path on the operator. The artifact must match the
+//! SHA-256 `digest` of the server config before anything of it is used. Tar archives,
+//! gzipped or not, and zip archives are unpacked, anything else is run as it is.
+//!
+//! Artifacts are cached per service by their digest, so restarts and the other
+//! slots of the service do not fetch them again. The binary runs under the process
+//! [`supervisor`], like the Python and JavaScript servers. The Wasm runtime fetches
+//! its components the same way.
+
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+
+use crate::error::Error;
+use crate::manager::cgroup;
+use crate::manager::supervisor::{self, ProcessSpec};
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
+
+/// The name a binary that is not in an archive is stored as, if its source names none
+const DEFAULT_NAME: &str = "server";
+
+/// Where an artifact is fetched from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Artifact {
+    /// An `https://` or `http://` URL
+    Url(String),
+    /// An absolute path on the operator
+    File(PathBuf),
+}
+
+impl Artifact {
+    /// The artifact a package refers to, if it is a URL or an absolute path
+    pub fn parse(package: &str) -> Option<Self> {
+        let package = package.trim();
+        if package.starts_with("https://") || package.starts_with("http://") {
+            return Some(Self::Url(package.to_string()));
+        }
+        let path = Path::new(package);
+        path.is_absolute().then(|| Self::File(path.to_path_buf()))
+    }
+
+    /// The file name the artifact is published under, e.g. `server` for `…/server?x=y`
+    fn file_name(&self) -> String {
+        let name = match self {
+            Self::Url(url) => url
+                .split(['?', '#'])
+                .next()
+                .and_then(|url| url.rsplit('/').next())
+                .map(str::to_string),
+            Self::File(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+        };
+        name.filter(|name| {
+            let mut components = Path::new(name).components();
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+        })
+        .unwrap_or_else(|| DEFAULT_NAME.to_string())
+    }
+}
+
+/// Binary runner
+///
+/// This runner fetches a native binary and runs it directly
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct BinaryRunner;
+
+impl BinaryRunner {
+    /// The command that runs the binary
+    fn process_spec(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+        program: &Path,
+    ) -> ProcessSpec {
+        ProcessSpec {
+            program: program.to_string_lossy().into_owned(),
+            args: server.args.clone(),
+            env: server.env_vars.clone(),
+            clear_env: false,
+            work_dir: ctx.work_dir(id),
+            grace_period: ctx.config.stop.grace_period(),
+            cgroup: cgroup::needed(&server.resources)
+                .then(|| cgroup::path(&ctx.config.limits.cgroup_root, id)),
+            limits: server.resources.clone(),
+        }
+    }
+
+    /// The directory the artifact of a server is unpacked to
+    fn artifact_dir(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<PathBuf, Error> {
+        let digest = server
+            .digest
+            .as_deref()
+            .ok_or_else(|| Error::InvalidConfig("the artifact needs a `digest`".to_string()))?;
+        let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+        Ok(ctx.binary_cache(id.service_id).join(hex))
+    }
+
+    /// Fetch, check and unpack the artifact of a server, unless it is cached
+    ///
+    /// Returns the path of the binary to run.
+    pub async fn fetch(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<PathBuf, Error> {
+        let dir = Self::artifact_dir(ctx, id, server)?;
+        if tokio::fs::try_exists(&dir).await? {
+            blueprint_sdk::debug!(%id, dir = %dir.display(), "Artifact is already cached");
+            return Self::locate(&dir, server.binary.as_deref()).await;
+        }
+
+        let artifact = Artifact::parse(&server.package).ok_or_else(|| {
+            Error::InvalidConfig("the artifact must be a URL or an absolute path".to_string())
+        })?;
+        let cache = dir.parent().map(Path::to_path_buf).unwrap_or_default();
+        tokio::fs::create_dir_all(&cache).await?;
+        // Other slots may fetch the same artifact at the same time
+        let scratch = cache.join(format!(".{}", uuid::Uuid::new_v4()));
+        let download = scratch.with_extension("download");
+        let unpacked = async {
+            self.download(ctx, &artifact, &download).await?;
+            Self::verify(
+                &download,
+                &server.package,
+                server.digest.as_deref().unwrap_or_default(),
+            )
+            .await?;
+            let max_bytes = ctx.config.binaries.max_size_bytes();
+            let (archive, target, name) = (download.clone(), scratch.clone(), artifact.file_name());
+            tokio::task::spawn_blocking(move || unpack(&archive, &target, &name, max_bytes))
+                .await
+                .map_err(std::io::Error::other)?
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&download).await;
+        if let Err(e) = unpacked {
+            let _ = tokio::fs::remove_dir_all(&scratch).await;
+            return Err(e);
+        }
+        if tokio::fs::rename(&scratch, &dir).await.is_err() {
+            // Another start got there first, with the same contents
+            let _ = tokio::fs::remove_dir_all(&scratch).await;
+        }
+        blueprint_sdk::info!(%id, package = %server.package, "Fetched artifact");
+        Self::locate(&dir, server.binary.as_deref()).await
+    }
+
+    /// Download the artifact, or copy it if it is a local file
+    ///
+    /// The operator's limits for binaries apply to it.
+    pub async fn download(
+        &self,
+        ctx: &crate::MyContext,
+        artifact: &Artifact,
+        target: &Path,
+    ) -> Result<(), Error> {
+        let limits = &ctx.config.binaries;
+        match artifact {
+            Artifact::Url(url) => {
+                blueprint_sdk::debug!(%url, "Downloading binary artifact");
+                let output = Command::new("curl")
+                    .args(["-fsSL", "--proto", "=https,http"])
+                    .arg("--max-filesize")
+                    .arg(limits.max_size_bytes().to_string())
+                    .arg("--max-time")
+                    .arg(limits.download_timeout_secs.to_string())
+                    .arg("-o")
+                    .arg(target)
+                    .arg(url)
+                    // An aborted start must not leave the download running
+                    .kill_on_drop(true)
+                    .output()
+                    .await?;
+                if !output.status.success() {
+                    return Err(Error::Io(std::io::Error::other(format!(
+                        "Failed to download {url}: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ))));
+                }
+            }
+            Artifact::File(path) => {
+                if tokio::fs::metadata(path).await?.len() > limits.max_size_bytes() {
+                    return Err(Error::InvalidConfig(
+                        "the artifact is larger than the operator allows".to_string(),
+                    ));
+                }
+                tokio::fs::copy(path, target).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the SHA-256 of a downloaded artifact against its digest
+    async fn verify(path: &Path, package: &str, digest: &str) -> Result<(), Error> {
+        let path = path.to_path_buf();
+        let actual = tokio::task::spawn_blocking(move || {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(path)?, &mut hasher)?;
+            Ok::<_, std::io::Error>(format!("sha256:{}", hex::encode(hasher.finalize())))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        if actual != digest {
+            return Err(Error::IntegrityMismatch {
+                subject: format!("artifact {package}"),
+                expected: digest.to_string(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// The binary to run in an unpacked artifact
+    ///
+    /// That is `binary` if it is given, otherwise the only executable file.
+    async fn locate(dir: &Path, binary: Option<&str>) -> Result<PathBuf, Error> {
+        let dir = dir.to_path_buf();
+        let binary = binary.map(str::to_string);
+        tokio::task::spawn_blocking(move || {
+            let root = dir.canonicalize()?;
+            if let Some(binary) = binary {
+                // Symlinks in the archive must not lead out of it
+                let path = root
+                    .join(&binary)
+                    .canonicalize()
+                    .ok()
+                    .filter(|path| path.starts_with(&root) && path.is_file());
+                return path.ok_or_else(|| {
+                    Error::InvalidConfig(format!("the artifact has no file `{binary}`"))
+                });
+            }
+            let mut executables = Vec::new();
+            find_executables(&root, &mut executables)?;
+            match executables.as_slice() {
+                [binary] => Ok(binary.clone()),
+                [] => Err(Error::InvalidConfig(
+                    "the artifact has no executable file".to_string(),
+                )),
+                _ => Err(Error::InvalidConfig(format!(
+                    "the artifact has {} executable files, name the one to run with `binary`",
+                    executables.len()
+                ))),
+            }
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+}
+
+/// Collect the executable regular files below `dir`
+fn find_executables(dir: &Path, executables: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            find_executables(&entry.path(), executables)?;
+        } else if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+            executables.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Unpack an artifact into `target`, or copy it there as `name` if it is no archive
+fn unpack(artifact: &Path, target: &Path, name: &str, max_bytes: u64) -> Result<(), Error> {
+    let too_large = || {
+        Error::InvalidConfig("the unpacked artifact is larger than the operator allows".to_string())
+    };
+    std::fs::create_dir_all(target)?;
+    let mut file = File::open(artifact)?;
+    let mut magic = Vec::with_capacity(512);
+    (&mut file).take(512).read_to_end(&mut magic)?;
+    file.rewind()?;
+
+    let reader: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if magic.get(257..262) == Some(b"ustar") {
+        Box::new(file)
+    } else if magic.starts_with(b"PK\x03\x04") {
+        let mut archive = zip::ZipArchive::new(file).map_err(std::io::Error::other)?;
+        let mut total = 0u64;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(std::io::Error::other)?;
+            // Entries with absolute paths or `..` would be written outside the target
+            let Some(path) = entry.enclosed_name() else {
+                return Err(Error::InvalidConfig(format!(
+                    "the artifact has an invalid path `{}`",
+                    entry.name()
+                )));
+            };
+            total = total.saturating_add(entry.size());
+            if total > max_bytes {
+                return Err(too_large());
+            }
+            let path = target.join(path);
+            if entry.is_dir() {
+                std::fs::create_dir_all(&path)?;
+                continue;
+            }
+            if entry.is_symlink() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let size = entry.size();
+            let mut out = File::create(&path)?;
+            std::io::copy(&mut (&mut entry).take(size), &mut out)?;
+            let mode = entry.unix_mode().unwrap_or(0o644) & 0o777;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        }
+        return Ok(());
+    } else {
+        // A bare binary or component
+        let path = target.join(name);
+        std::fs::copy(artifact, &path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        return Ok(());
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut total = 0u64;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        // Devices and pipes have no place in a server
+        if !(kind.is_file() || kind.is_dir() || kind.is_symlink() || kind.is_hard_link()) {
+            continue;
+        }
+        total = total.saturating_add(entry.size());
+        if total > max_bytes {
+            return Err(too_large());
+        }
+        // Entries that would be written outside the target are skipped
+        entry.unpack_in(target)?;
+    }
+    Ok(())
+}
+
+impl ServerRunner for BinaryRunner {
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "binary")
+    )]
+    async fn start(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
+        let remote = matches!(Artifact::parse(&server.package), Some(Artifact::Url(_)));
+        if remote && !matches!(self.check(ctx).await, Ok(true)) {
+            return Err(Error::Io(std::io::Error::other(
+                "curl is needed to download binaries but is not installed",
+            )));
+        }
+        let program = self.fetch(ctx, id, server).await?;
+
+        blueprint_sdk::debug!(program = %program.display(), "Starting binary server");
+
+        let buffer = ctx.logs.buffer(id).await;
+        // Let go of the port right before the server binds it
+        ctx.ports.release(id);
+        let mut handle = supervisor::spawn(
+            Self::process_spec(ctx, id, server, &program),
+            id.clone(),
+            "Binary",
+            buffer,
+        )
+        .await?;
+        handle.version = server.digest.clone();
+        Ok(handle)
+    }
+
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        match supervisor::adoptable(&server.status.get()).await {
+            Some(pid) => {
+                let dir = Self::artifact_dir(ctx, id, server)?;
+                let program = Self::locate(&dir, server.binary.as_deref()).await?;
+                Ok(Some(supervisor::adopt(
+                    pid,
+                    Self::process_spec(ctx, id, server, &program),
+                    id.clone(),
+                    "Binary",
+                    server.status.clone(),
+                    ctx.logs.buffer(id).await,
+                )))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
+        let status = Command::new("curl")
+            .arg("--version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .map_err(Error::Io)?;
+        Ok(status.success())
+    }
+
+    async fn install(&self, _ctx: &crate::MyContext) -> Result<(), Error> {
+        // The binaries bring everything they need, only curl must be installed
+        Err(Error::Io(std::io::Error::other(
+            "curl must be installed by the operator",
+        )))
+    }
+}
@@ -0,0 +1,193 @@
+//! cgroup v2 resource limits for the process runtimes
+//!
+//! Every limited process server gets its own cgroup below the operator-configured
+//! root. The supervised process moves itself into it before `exec`ing the server, so
+//! everything it spawns is accounted for.
+
+use std::path::{Path, PathBuf};
+
+use crate::ResourceLimits;
+use crate::error::Error;
+use crate::manager::ServerId;
+
+/// The controllers the service cgroups use
+const CONTROLLERS: &str = "+cpu +memory +pids";
+/// The CPU period the CPU quota is expressed in, in microseconds
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Whether the limits need a cgroup to be enforced
+pub fn needed(limits: &ResourceLimits) -> bool {
+    limits.cpu_millis != 0 || limits.cpu_shares != 0 || limits.memory_mb != 0 || limits.pids != 0
+}
+
+/// The cgroup of the given server
+pub fn path(root: &Path, id: &ServerId) -> PathBuf {
+    root.join(id.name())
+}
+
+/// Create the cgroup if needed and apply the limits to it
+pub async fn configure(path: &Path, limits: &ResourceLimits) -> Result<(), Error> {
+    let failed = |e: std::io::Error| {
+        Error::Io(std::io::Error::other(format!(
+            "Failed to configure cgroup {}: {e}",
+            path.display()
+        )))
+    };
+
+    if let Some(root) = path.parent() {
+        tokio::fs::create_dir_all(root).await.map_err(failed)?;
+        // Hand the controllers down to the service cgroups
+        tokio::fs::write(root.join("cgroup.subtree_control"), CONTROLLERS)
+            .await
+            .map_err(failed)?;
+    }
+    tokio::fs::create_dir_all(path).await.map_err(failed)?;
+
+    let mib = |value: u64| value.saturating_mul(1024 * 1024).to_string();
+    let or_max = |value: u64, limit: String| {
+        if value == 0 { "max".to_string() } else { limit }
+    };
+    let cpu_max = format!(
+        "{} {CPU_PERIOD_US}",
+        or_max(
+            limits.cpu_millis,
+            (limits.cpu_millis.saturating_mul(CPU_PERIOD_US) / 1000).to_string()
+        )
+    );
+    // The usual conversion of Docker CPU shares [2, 262144] to weights [1, 10000]
+    let cpu_weight = match limits.cpu_shares {
+        0 => 100,
+        shares => 1 + (shares.saturating_sub(2) * 9999) / 262_142,
+    };
+    let swap_max = if limits.memory_mb == 0 {
+        "max".to_string()
+    } else {
+        mib(limits.swap_mb)
+    };
+
+    let files = [
+        ("cpu.max", cpu_max),
+        ("cpu.weight", cpu_weight.to_string()),
+        (
+            "memory.max",
+            or_max(limits.memory_mb, mib(limits.memory_mb)),
+        ),
+        ("memory.swap.max", swap_max),
+        ("pids.max", or_max(limits.pids, limits.pids.to_string())),
+    ];
+    for (file, value) in files {
+        tokio::fs::write(path.join(file), value)
+            .await
+            .map_err(failed)?;
+    }
+    blueprint_sdk::debug!(path = %path.display(), ?limits, "Configured cgroup");
+    Ok(())
+}
+
+/// Remove the cgroup once all of its processes exited
+pub async fn remove(path: &Path) {
+    if let Err(e) = tokio::fs::remove_dir(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            blueprint_sdk::warn!(?e, path = %path.display(), "Failed to remove cgroup");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configure a cgroup in a plain directory and read back the files written to it
+    async fn configured(limits: ResourceLimits) -> Vec<(&'static str, String)> {
+        let root = std::env::temp_dir().join(format!("cgroup-{}", uuid::Uuid::new_v4()));
+        let path = path(&root, &ServerId::new(1, "default"));
+        configure(&path, &limits).await.unwrap();
+        let mut files = Vec::new();
+        for file in [
+            "cpu.max",
+            "cpu.weight",
+            "memory.max",
+            "memory.swap.max",
+            "pids.max",
+        ] {
+            files.push((
+                file,
+                tokio::fs::read_to_string(path.join(file)).await.unwrap(),
+            ));
+        }
+        let controllers = tokio::fs::read_to_string(root.join("cgroup.subtree_control"))
+            .await
+            .unwrap();
+        assert_eq!(controllers, CONTROLLERS);
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+        files
+    }
+
+    #[tokio::test]
+    async fn unset_limits_are_unlimited() {
+        assert_eq!(
+            configured(ResourceLimits::default()).await,
+            [
+                ("cpu.max", "max 100000".to_string()),
+                ("cpu.weight", "100".to_string()),
+                ("memory.max", "max".to_string()),
+                ("memory.swap.max", "max".to_string()),
+                ("pids.max", "max".to_string()),
+            ]
+        );
+        assert!(!needed(&ResourceLimits::default()));
+    }
+
+    #[tokio::test]
+    async fn limits_are_converted_to_cgroup_units() {
+        let limits = ResourceLimits {
+            cpu_millis: 1500,
+            cpu_shares: 1024,
+            memory_mb: 256,
+            swap_mb: 64,
+            pids: 100,
+            ..Default::default()
+        };
+        assert!(needed(&limits));
+        assert_eq!(
+            configured(limits).await,
+            [
+                ("cpu.max", "150000 100000".to_string()),
+                ("cpu.weight", "39".to_string()),
+                ("memory.max", (256 * 1024 * 1024).to_string()),
+                ("memory.swap.max", (64 * 1024 * 1024).to_string()),
+                ("pids.max", "100".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_minimum_cpu_quota_is_enforceable() {
+        let limits = ResourceLimits {
+            cpu_millis: ResourceLimits::MIN_CPU_MILLIS,
+            ..Default::default()
+        };
+        // The kernel rejects quotas below 1000us
+        assert_eq!(configured(limits).await[0].1, "1000 100000");
+    }
+
+    #[tokio::test]
+    async fn cpu_shares_map_onto_the_weight_range() {
+        for (shares, weight) in [(2, "1"), (262_144, "10000")] {
+            let limits = ResourceLimits {
+                cpu_shares: shares,
+                ..Default::default()
+            };
+            assert_eq!(configured(limits).await[1].1, weight, "{shares}");
+        }
+    }
+
+    #[tokio::test]
+    async fn swap_is_only_limited_with_memory() {
+        let limits = ResourceLimits {
+            swap_mb: 64,
+            ..Default::default()
+        };
+        assert_eq!(configured(limits).await[3].1, "max");
+    }
+}
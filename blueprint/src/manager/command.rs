@@ -0,0 +1,174 @@
+//! Operator-defined command runtimes
+//!
+//! The operator config can define runtimes that run a server with a command, like
+//! `deno run`, `java -jar` or `go run`, from a template of its arguments. Every one
+//! is registered under its name in the [`RuntimeRegistry`] at startup, and its
+//! servers run under the process [`supervisor`], like the Python and JavaScript
+//! servers.
+//!
+//! The processes only get the environment variables of the server and the ones the
+//! runtime passes through from the operator.
+//!
+//! [`RuntimeRegistry`]: crate::manager::runtimes::RuntimeRegistry
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tokio::process::Command;
+
+use crate::config::CommandRuntimeConfig;
+use crate::error::Error;
+use crate::manager::cgroup;
+use crate::manager::supervisor::{self, ProcessSpec};
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
+
+/// The environment variables of the operator every command runtime passes through
+const PASSTHROUGH: [&str; 2] = ["PATH", "HOME"];
+
+/// Command runner
+///
+/// This runner runs the package of a server with a command the operator configured
+#[derive(Debug, Clone)]
+pub struct CommandRunner {
+    name: Arc<str>,
+    config: CommandRuntimeConfig,
+}
+
+impl CommandRunner {
+    /// The runner of the runtime `name`
+    pub fn new(name: &str, config: CommandRuntimeConfig) -> Self {
+        Self {
+            name: name.into(),
+            config,
+        }
+    }
+
+    /// The command that runs the package
+    fn process_spec(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<ProcessSpec, Error> {
+        let work_dir = ctx.work_dir(id);
+        let value = |name: &str| match name {
+            "package" => Some(server.package.clone()),
+            "port" => server.env_vars.get("PORT").cloned(),
+            "work_dir" => Some(work_dir.to_string_lossy().into_owned()),
+            _ => None,
+        };
+        let mut args = Vec::new();
+        let mut server_args = false;
+        for arg in &self.config.args {
+            if arg == CommandRuntimeConfig::ARGS_PLACEHOLDER {
+                args.extend(server.args.iter().cloned());
+                server_args = true;
+            } else {
+                args.push(CommandRuntimeConfig::expand(arg, value)?);
+            }
+        }
+        if !server_args {
+            args.extend(server.args.iter().cloned());
+        }
+
+        let mut env: BTreeMap<String, String> = PASSTHROUGH
+            .into_iter()
+            .chain(self.config.env_passthrough.iter().map(String::as_str))
+            .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+            .collect();
+        env.extend(server.env_vars.clone());
+        Ok(ProcessSpec {
+            program: self.config.program.clone(),
+            args,
+            env,
+            clear_env: true,
+            work_dir,
+            grace_period: ctx.config.stop.grace_period(),
+            cgroup: cgroup::needed(&server.resources)
+                .then(|| cgroup::path(&ctx.config.limits.cgroup_root, id)),
+            limits: server.resources.clone(),
+        })
+    }
+}
+
+impl ServerRunner for CommandRunner {
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = %self.name)
+    )]
+    async fn start(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
+        // The runtime has no way to pin what it runs
+        if server.digest.is_some() {
+            return Err(Error::InvalidConfig(format!(
+                "`digest` is not supported by the {} runtime",
+                self.name
+            )));
+        }
+        // The package must not be taken for an option of the program
+        if server.package.starts_with('-') {
+            return Err(Error::InvalidConfig(
+                "the package must not start with `-`".to_string(),
+            ));
+        }
+        if !matches!(self.check(ctx).await, Ok(true)) {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "the {} runtime is not installed",
+                self.name
+            ))));
+        }
+        let spec = self.process_spec(ctx, id, server)?;
+
+        blueprint_sdk::debug!(program = %spec.program, args = ?spec.args, "Starting command server");
+
+        let buffer = ctx.logs.buffer(id).await;
+        // Let go of the port right before the server binds it
+        ctx.ports.release(id);
+        supervisor::spawn(spec, id.clone(), self.name.clone(), buffer).await
+    }
+
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        match supervisor::adoptable(&server.status.get()).await {
+            Some(pid) => Ok(Some(supervisor::adopt(
+                pid,
+                self.process_spec(ctx, id, server)?,
+                id.clone(),
+                self.name.clone(),
+                server.status.clone(),
+                ctx.logs.buffer(id).await,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
+        let Some((program, args)) = self.config.check.split_first() else {
+            // Without a check, a missing program fails the start instead
+            return Ok(true);
+        };
+        let status = Command::new(program)
+            .args(args)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .map_err(Error::Io)?;
+        Ok(status.success())
+    }
+
+    async fn install(&self, _ctx: &crate::MyContext) -> Result<(), Error> {
+        Err(Error::Io(std::io::Error::other(format!(
+            "the {} runtime must be installed by the operator",
+            self.name
+        ))))
+    }
+}
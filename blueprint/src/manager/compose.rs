@@ -0,0 +1,949 @@
+//! Docker Compose stacks
+//!
+//! The Compose runtime runs a subset of the Compose file format. Every service of
+//! the document is a container on a private network of the stack, where the
+//! services reach each other by their names. Services are started in the order of
+//! their `depends_on`, waiting for the conditions given there. Only services marked
+//! with `x-public: {container_port}` are published and proxied: the first of them
+//! gets the server's port, the others are routed like named HTTP ports. Stopping
+//! the server removes every container and the network. Named volumes are kept like
+//! those of Docker servers.
+//!
+//! The status of the server follows its main container, the first public service
+//! or the last one started if none is public.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+use docktopus::bollard::Docker;
+use docktopus::bollard::container::{
+    Config, CreateContainerOptions, ListContainersOptions, NetworkingConfig,
+    RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
+};
+use docktopus::bollard::errors::Error as DockerError;
+use docktopus::bollard::models::{
+    ContainerStateStatusEnum, EndpointSettings, HealthConfig, HealthStatusEnum, HostConfig,
+    PortBinding,
+};
+use docktopus::bollard::network::CreateNetworkOptions;
+use docktopus::bollard::secret::{RestartPolicy, RestartPolicyNameEnum};
+use serde::de::IgnoredAny;
+
+use crate::error::Error;
+use crate::manager::docker::{DockerRunner, follow_logs};
+use crate::manager::events::{SERVICE_ID_LABEL, SLOT_LABEL};
+use crate::manager::status::{RuntimeStatus, SharedStatus};
+use crate::manager::{
+    PublishedPort, RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle, is_valid_name,
+    registry, volumes,
+};
+use crate::{PortProtocol, RegistryAuth, VolumeMount};
+
+/// The label holding the Compose service a container runs
+pub const COMPOSE_SERVICE_LABEL: &str = "server-blueprint.compose-service";
+/// The longest Compose document accepted, in bytes
+const MAX_DOCUMENT_LEN: usize = 64 * 1024;
+/// How long a service may take to meet the condition another one depends on
+const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often the condition of a dependency is checked
+const DEPENDENCY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The top level of a Compose document
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    services: serde_yaml_ng::Mapping,
+    #[serde(default)]
+    volumes: Option<serde_yaml_ng::Mapping>,
+    #[serde(default, rename = "version")]
+    _version: Option<IgnoredAny>,
+    #[serde(default, rename = "name")]
+    _name: Option<IgnoredAny>,
+}
+
+/// A service of a Compose document
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServiceDef {
+    image: String,
+    #[serde(default)]
+    command: Option<Command>,
+    #[serde(default)]
+    entrypoint: Option<Command>,
+    #[serde(default)]
+    environment: Option<Environment>,
+    #[serde(default)]
+    depends_on: Option<DependsOn>,
+    #[serde(default)]
+    volumes: Vec<String>,
+    #[serde(default)]
+    healthcheck: Option<Healthcheck>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    working_dir: Option<String>,
+    /// Every container is restarted on failure, whatever the document asks for
+    #[serde(default, rename = "restart")]
+    _restart: Option<IgnoredAny>,
+    #[serde(default, rename = "x-public")]
+    public: Option<u16>,
+}
+
+/// A command as a string split on whitespace, or as a list of arguments
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Command {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl Command {
+    fn into_args(self) -> Vec<String> {
+        match self {
+            Self::Line(line) => line.split_whitespace().map(str::to_string).collect(),
+            Self::Args(args) => args,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Environment {
+    Map(BTreeMap<String, serde_yaml_ng::Value>),
+    List(Vec<String>),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum DependsOn {
+    List(Vec<String>),
+    Map(BTreeMap<String, Dependency>),
+}
+
+#[derive(serde::Deserialize)]
+struct Dependency {
+    #[serde(default)]
+    condition: Condition,
+}
+
+/// What a service waits for before it starts after one it depends on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+enum Condition {
+    /// The dependency was started
+    #[default]
+    #[serde(rename = "service_started")]
+    Started,
+    /// The healthcheck of the dependency passes
+    #[serde(rename = "service_healthy")]
+    Healthy,
+    /// The dependency exited with code 0
+    #[serde(rename = "service_completed_successfully")]
+    Completed,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Started => "service_started",
+            Self::Healthy => "service_healthy",
+            Self::Completed => "service_completed_successfully",
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Healthcheck {
+    #[serde(default)]
+    test: Option<Command>,
+    #[serde(default)]
+    interval: Option<String>,
+    #[serde(default)]
+    timeout: Option<String>,
+    #[serde(default)]
+    retries: Option<i64>,
+    #[serde(default)]
+    start_period: Option<String>,
+    #[serde(default)]
+    disable: bool,
+}
+
+impl Healthcheck {
+    fn into_config(self) -> Result<HealthConfig, String> {
+        let nanos = |value: Option<String>| {
+            value
+                .map(|value| {
+                    parse_duration(&value)
+                        .map(|duration| i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX))
+                        .ok_or_else(|| format!("invalid duration `{value}`"))
+                })
+                .transpose()
+        };
+        let test = match (self.disable, self.test) {
+            (true, _) => Some(vec!["NONE".to_string()]),
+            // A string is run by the container's shell, like in Compose
+            (false, Some(Command::Line(line))) => Some(vec!["CMD-SHELL".to_string(), line]),
+            (false, Some(Command::Args(args))) => Some(args),
+            (false, None) => None,
+        };
+        Ok(HealthConfig {
+            test,
+            interval: nanos(self.interval)?,
+            timeout: nanos(self.timeout)?,
+            retries: self.retries,
+            start_period: nanos(self.start_period)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Parse a Compose duration like `1m30s` or `500ms`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let duration = match &rest[..unit] {
+            "h" => Duration::from_secs(amount.checked_mul(3600)?),
+            "m" => Duration::from_secs(amount.checked_mul(60)?),
+            "s" => Duration::from_secs(amount),
+            "ms" => Duration::from_millis(amount),
+            "us" => Duration::from_micros(amount),
+            _ => return None,
+        };
+        total = total.checked_add(duration)?;
+        rest = &rest[unit..];
+    }
+    Some(total)
+}
+
+/// A service of a stack, checked
+#[derive(Debug, Clone)]
+struct StackService {
+    name: String,
+    image: String,
+    command: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    env: BTreeMap<String, String>,
+    depends_on: Vec<(String, Condition)>,
+    mounts: Vec<VolumeMount>,
+    healthcheck: Option<HealthConfig>,
+    user: Option<String>,
+    working_dir: Option<String>,
+    public: Option<u16>,
+}
+
+/// A checked Compose document, with its services in start order
+#[derive(Debug, Clone)]
+pub struct Stack {
+    services: Vec<StackService>,
+    volumes: Vec<String>,
+}
+
+impl Stack {
+    /// Parse and check a Compose document
+    pub fn parse(document: &str) -> Result<Self, Error> {
+        let invalid =
+            |reason: String| Error::InvalidConfig(format!("invalid compose document: {reason}"));
+        if document.len() > MAX_DOCUMENT_LEN {
+            return Err(invalid(format!(
+                "it is larger than {} KiB",
+                MAX_DOCUMENT_LEN / 1024
+            )));
+        }
+        let mut value: serde_yaml_ng::Value =
+            serde_yaml_ng::from_str(document).map_err(|e| invalid(e.to_string()))?;
+        value.apply_merge().map_err(|e| invalid(e.to_string()))?;
+        // Extension fields only hold anchors for the rest of the document
+        if let Some(top) = value.as_mapping_mut() {
+            top.retain(|key, _| !key.as_str().is_some_and(|key| key.starts_with("x-")));
+        }
+        let document: Document =
+            serde_yaml_ng::from_value(value).map_err(|e| invalid(e.to_string()))?;
+
+        let volumes = document
+            .volumes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, _)| {
+                name.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| invalid("volume names must be strings".to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut services = Vec::with_capacity(document.services.len());
+        for (name, definition) in document.services {
+            let Some(name) = name.as_str().map(str::to_string) else {
+                return Err(invalid("service names must be strings".to_string()));
+            };
+            let valid_name = (1..=63).contains(&name.len())
+                && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+                && name.chars().all(|c| {
+                    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '.' | '-')
+                });
+            if !valid_name {
+                return Err(invalid(format!("invalid service name `{name}`")));
+            }
+            let definition: ServiceDef = serde_yaml_ng::from_value(definition)
+                .map_err(|e| invalid(format!("service `{name}`: {e}")))?;
+            services.push(StackService::new(name, definition, &volumes).map_err(invalid)?);
+        }
+        if services.is_empty() {
+            return Err(invalid("it has no services".to_string()));
+        }
+
+        for service in &services {
+            for (dependency, _) in &service.depends_on {
+                if !services.iter().any(|other| &other.name == dependency) {
+                    return Err(invalid(format!(
+                        "service `{}` depends on the unknown service `{dependency}`",
+                        service.name
+                    )));
+                }
+            }
+        }
+        // Start every service after its dependencies, otherwise in the order of the document
+        let mut ordered: Vec<StackService> = Vec::with_capacity(services.len());
+        while !services.is_empty() {
+            let Some(next) = services.iter().position(|service| {
+                service
+                    .depends_on
+                    .iter()
+                    .all(|(dependency, _)| ordered.iter().any(|done| &done.name == dependency))
+            }) else {
+                let names: Vec<&str> = services
+                    .iter()
+                    .map(|service| service.name.as_str())
+                    .collect();
+                return Err(invalid(format!(
+                    "the services {} depend on each other",
+                    names.join(", ")
+                )));
+            };
+            ordered.push(services.remove(next));
+        }
+
+        Ok(Self {
+            services: ordered,
+            volumes,
+        })
+    }
+
+    /// The images of the services
+    pub fn images(&self) -> impl Iterator<Item = &str> {
+        self.services.iter().map(|service| service.image.as_str())
+    }
+
+    /// The names of the named volumes
+    pub fn volumes(&self) -> &[String] {
+        &self.volumes
+    }
+
+    /// The names of the public services
+    pub fn public_services(&self) -> impl Iterator<Item = &str> {
+        self.services
+            .iter()
+            .filter(|service| service.public.is_some())
+            .map(|service| service.name.as_str())
+    }
+
+    /// The index of the main service, whose container the server status follows
+    fn main(&self) -> usize {
+        self.services
+            .iter()
+            .position(|service| service.public.is_some())
+            .unwrap_or(self.services.len() - 1)
+    }
+
+    /// Whether a service only has to run to completion
+    fn is_one_shot(&self, name: &str) -> bool {
+        self.services.iter().any(|service| {
+            service.depends_on.iter().any(|(dependency, condition)| {
+                dependency == name && *condition == Condition::Completed
+            })
+        })
+    }
+}
+
+impl StackService {
+    fn new(name: String, definition: ServiceDef, volumes: &[String]) -> Result<Self, String> {
+        if definition.image.trim().is_empty() || definition.image.contains('\0') {
+            return Err(format!("service `{name}` needs an image"));
+        }
+        if let Some(port) = definition.public {
+            if port == 0 {
+                return Err(format!("service `{name}` has an invalid public port"));
+            }
+            // Public services are routed like named ports
+            if !is_valid_name(&name) {
+                return Err(format!(
+                    "public service `{name}` must be named with up to 32 lowercase letters, digits and `_`"
+                ));
+            }
+        }
+
+        let env = match definition.environment {
+            None => BTreeMap::new(),
+            Some(Environment::Map(map)) => map
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let value = match value {
+                        serde_yaml_ng::Value::String(value) => value,
+                        serde_yaml_ng::Value::Number(value) => value.to_string(),
+                        serde_yaml_ng::Value::Bool(value) => value.to_string(),
+                        // Unset variables would come from the shell running Compose
+                        _ => return None,
+                    };
+                    Some((key, value))
+                })
+                .collect(),
+            Some(Environment::List(list)) => list
+                .into_iter()
+                .filter_map(|entry| {
+                    entry
+                        .split_once('=')
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                })
+                .collect(),
+        };
+        if env
+            .iter()
+            .any(|(key, value)| key.is_empty() || key.contains('\0') || value.contains('\0'))
+        {
+            return Err(format!(
+                "service `{name}` has an invalid environment variable"
+            ));
+        }
+
+        let depends_on = match definition.depends_on {
+            None => Vec::new(),
+            Some(DependsOn::List(names)) => names
+                .into_iter()
+                .map(|name| (name, Condition::default()))
+                .collect(),
+            Some(DependsOn::Map(map)) => map
+                .into_iter()
+                .map(|(name, dependency)| (name, dependency.condition))
+                .collect(),
+        };
+
+        let mut mounts = Vec::with_capacity(definition.volumes.len());
+        for spec in definition.volumes {
+            let mut parts = spec.splitn(3, ':');
+            let (Some(volume), Some(path)) = (parts.next(), parts.next()) else {
+                return Err(format!(
+                    "service `{name}` mounts `{spec}`, expected `volume:/path[:ro]`"
+                ));
+            };
+            let read_only = match parts.next() {
+                None | Some("rw") => false,
+                Some("ro") => true,
+                Some(mode) => return Err(format!("service `{name}` mounts with mode `{mode}`")),
+            };
+            // Bind mounts would expose the operator's file system
+            if !volumes.iter().any(|declared| declared == volume) {
+                return Err(format!(
+                    "service `{name}` mounts `{volume}`, which is not a declared named volume"
+                ));
+            }
+            let mount = VolumeMount {
+                name: volume.to_string(),
+                path: path.to_string(),
+                read_only,
+            };
+            mount
+                .validate()
+                .map_err(|e| format!("service `{name}`: {e}"))?;
+            mounts.push(mount);
+        }
+
+        Ok(Self {
+            image: definition.image,
+            command: definition.command.map(Command::into_args),
+            entrypoint: definition.entrypoint.map(Command::into_args),
+            env,
+            depends_on,
+            mounts,
+            healthcheck: definition
+                .healthcheck
+                .map(Healthcheck::into_config)
+                .transpose()
+                .map_err(|e| format!("service `{name}`: {e}"))?,
+            user: definition.user,
+            working_dir: definition.working_dir,
+            public: definition.public,
+            name,
+        })
+    }
+}
+
+/// The name of the container of a stack service
+fn container_name(id: &ServerId, service: &str) -> String {
+    format!("{}-{service}", id.name())
+}
+
+/// The name of the private network of a stack
+fn network_name(id: &ServerId) -> String {
+    id.name()
+}
+
+/// Runs the services of a Compose document as a stack of containers
+#[derive(Debug, Clone)]
+pub struct ComposeRunner;
+
+impl ComposeRunner {
+    /// Pull the images of a stack that are not available locally
+    pub async fn pull(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        stack: &Stack,
+        registry_auth: Option<&RegistryAuth>,
+    ) -> Result<(), Error> {
+        let credentials = registry_auth
+            .map(|auth| {
+                registry::credentials(&ctx.config, ctx.operator_key.as_ref(), id.service_id, auth)
+            })
+            .transpose()?;
+        for image in stack.images() {
+            DockerRunner
+                .ensure_image_available(ctx, id, image, credentials.clone(), None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the containers and the network left over from a previous run of the stack
+    async fn remove_stale(&self, docker: &Docker, id: &ServerId) -> Result<(), Error> {
+        let options = ListContainersOptions::<String> {
+            all: true,
+            filters: HashMap::from([(
+                "label".to_string(),
+                vec![
+                    format!("{SERVICE_ID_LABEL}={}", id.service_id),
+                    format!("{SLOT_LABEL}={}", id.slot),
+                    COMPOSE_SERVICE_LABEL.to_string(),
+                ],
+            )]),
+            ..Default::default()
+        };
+        let stale = docker.list_containers(Some(options)).await.map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to list Docker containers: {e}"
+            )))
+        })?;
+        let containers: Vec<String> = stale.into_iter().filter_map(|c| c.id).collect();
+        if !containers.is_empty() {
+            blueprint_sdk::debug!(?containers, "Removing stale stack containers");
+        }
+        teardown(docker, &containers, &network_name(id), Duration::ZERO).await;
+        Ok(())
+    }
+
+    /// Create and start the containers of the services, in order
+    ///
+    /// The ids of the started containers are pushed to `containers`, so they can
+    /// be torn down if a later service fails.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_services(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+        stack: &Stack,
+        env_vars: &BTreeMap<String, String>,
+        host_ports: &HashMap<&str, u16>,
+        containers: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        let network = network_name(id);
+        for service in &stack.services {
+            for (dependency, condition) in &service.depends_on {
+                let index = stack
+                    .services
+                    .iter()
+                    .position(|other| &other.name == dependency)
+                    .unwrap_or_default();
+                wait_for(&ctx.docker, dependency, &containers[index], *condition).await?;
+            }
+
+            let mounts = volumes::create(&ctx.docker, id, &service.mounts).await?;
+            // The server config applies to every service, over the document
+            let env: Vec<String> = service
+                .env
+                .iter()
+                .chain(env_vars)
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            let published = service.public.zip(host_ports.get(service.name.as_str()));
+            let port_key = |port: u16| format!("{port}/{}", PortProtocol::Tcp);
+            let config = Config {
+                image: Some(service.image.clone()),
+                cmd: service.command.clone(),
+                entrypoint: service.entrypoint.clone(),
+                env: Some(env),
+                user: service.user.clone(),
+                working_dir: service.working_dir.clone(),
+                healthcheck: service.healthcheck.clone(),
+                exposed_ports: published
+                    .map(|(port, _)| HashMap::from([(port_key(port), HashMap::new())])),
+                labels: Some(HashMap::from([
+                    (SERVICE_ID_LABEL.to_string(), id.service_id.to_string()),
+                    (SLOT_LABEL.to_string(), id.slot.clone()),
+                    (COMPOSE_SERVICE_LABEL.to_string(), service.name.clone()),
+                ])),
+                host_config: Some(HostConfig {
+                    port_bindings: published.map(|(port, host_port)| {
+                        HashMap::from([(
+                            port_key(port),
+                            Some(vec![PortBinding {
+                                host_ip: Some("127.0.0.1".to_string()),
+                                host_port: Some(host_port.to_string()),
+                            }]),
+                        )])
+                    }),
+                    mounts: (!mounts.is_empty()).then_some(mounts),
+                    network_mode: Some(network.clone()),
+                    restart_policy: Some(RestartPolicy {
+                        name: Some(RestartPolicyNameEnum::ON_FAILURE),
+                        maximum_retry_count: None,
+                    }),
+                    ..DockerRunner::resource_limits(&server.resources)
+                }),
+                networking_config: Some(NetworkingConfig {
+                    endpoints_config: HashMap::from([(
+                        network.clone(),
+                        EndpointSettings {
+                            aliases: Some(vec![service.name.clone()]),
+                            ..Default::default()
+                        },
+                    )]),
+                }),
+                ..Default::default()
+            };
+
+            let name = container_name(id, &service.name);
+            let created = ctx
+                .docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: name.clone(),
+                        platform: None,
+                    }),
+                    config,
+                )
+                .await
+                .map_err(|e| {
+                    Error::Io(std::io::Error::other(format!(
+                        "Failed to create Docker container {name}: {e}"
+                    )))
+                })?;
+            containers.push(created.id.clone());
+            ctx.docker
+                .start_container(&created.id, None::<StartContainerOptions<String>>)
+                .await
+                .map_err(|e| {
+                    Error::Io(std::io::Error::other(format!(
+                        "Failed to start Docker container {name}: {e}"
+                    )))
+                })?;
+            blueprint_sdk::debug!(service = %service.name, container_id = %created.id, "Started stack service");
+        }
+        Ok(())
+    }
+
+    /// Track the main container, capture the output of all of them and spawn a task
+    /// that tears the stack down once the server is shut down
+    async fn watch(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        containers: Vec<String>,
+        main: String,
+        status: SharedStatus,
+    ) -> ShutdownHandle {
+        let docker = ctx.docker.clone();
+        let events = ctx.docker_events.clone();
+        let buffer = ctx.logs.buffer(id).await;
+        let grace_period = ctx.config.stop.grace_period();
+        let network = network_name(id);
+        events.track(&main, status);
+
+        ShutdownHandle::spawn(|ct| async move {
+            let logs = futures::future::join_all(
+                containers
+                    .iter()
+                    .map(|container_id| follow_logs(&docker, container_id, buffer.clone())),
+            );
+            tokio::select! {
+                () = ct.cancelled() => {}
+                _ = logs => ct.cancelled().await,
+            }
+            // The containers are about to go away on purpose
+            events.untrack(&main);
+            blueprint_sdk::debug!(%network, "Stopping stack");
+            teardown(&docker, &containers, &network, grace_period).await;
+        })
+    }
+}
+
+/// Wait until a started dependency meets the condition
+async fn wait_for(
+    docker: &Docker,
+    service: &str,
+    container_id: &str,
+    condition: Condition,
+) -> Result<(), Error> {
+    if condition == Condition::Started {
+        return Ok(());
+    }
+    let waited = tokio::time::timeout(DEPENDENCY_TIMEOUT, async {
+        loop {
+            let info = docker
+                .inspect_container(container_id, None)
+                .await
+                .map_err(|e| e.to_string())?;
+            let state = info.state.unwrap_or_default();
+            let exited = matches!(
+                state.status,
+                Some(ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD)
+            );
+            match condition {
+                Condition::Healthy => match state.health.and_then(|health| health.status) {
+                    Some(HealthStatusEnum::HEALTHY) => return Ok(()),
+                    Some(HealthStatusEnum::UNHEALTHY) => return Err("it is unhealthy".to_string()),
+                    Some(HealthStatusEnum::STARTING) => {}
+                    _ => return Err("it has no healthcheck".to_string()),
+                },
+                Condition::Completed if exited => {
+                    return match state.exit_code {
+                        Some(0) => Ok(()),
+                        code => Err(format!("it exited with code {}", code.unwrap_or(-1))),
+                    };
+                }
+                _ => {}
+            }
+            tokio::time::sleep(DEPENDENCY_POLL_INTERVAL).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err(format!("timed out after {}s", DEPENDENCY_TIMEOUT.as_secs())));
+    waited.map_err(|reason| {
+        Error::Io(std::io::Error::other(format!(
+            "Stack service {service} did not meet `{condition}`: {reason}"
+        )))
+    })
+}
+
+/// Stop and remove the containers of a stack, the last started first, and its network
+async fn teardown(docker: &Docker, containers: &[String], network: &str, grace_period: Duration) {
+    for container_id in containers.iter().rev() {
+        if let Err(e) = docker
+            .stop_container(
+                container_id,
+                Some(StopContainerOptions {
+                    t: i64::try_from(grace_period.as_secs()).unwrap_or(i64::MAX),
+                }),
+            )
+            .await
+        {
+            blueprint_sdk::debug!(?e, %container_id, "Failed to stop Docker container");
+        }
+        // `v` only removes anonymous volumes, the named volumes of the stack are kept
+        if let Err(e) = docker
+            .remove_container(
+                container_id,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    v: true,
+                    link: false,
+                }),
+            )
+            .await
+        {
+            blueprint_sdk::error!(?e, %container_id, "Failed to remove Docker container");
+        }
+    }
+    match docker.remove_network(network).await {
+        Ok(())
+        | Err(DockerError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {}
+        Err(e) => blueprint_sdk::error!(?e, %network, "Failed to remove Docker network"),
+    }
+}
+
+impl ServerRunner for ComposeRunner {
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "compose")
+    )]
+    async fn start(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
+        let document = server.compose.as_deref().ok_or_else(|| {
+            Error::InvalidConfig("the Compose runtime needs a `compose` document".to_string())
+        })?;
+        let stack = Stack::parse(document)?;
+        DockerRunner.ensure_installed(ctx).await?;
+        self.pull(ctx, id, &stack, server.registry_auth.as_ref())
+            .await?;
+        self.remove_stale(&ctx.docker, id).await?;
+
+        let mut env_vars = server.env_vars.clone();
+        let allocated_port = env_vars
+            .remove("PORT")
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or(Error::MissingPortBinding)?;
+
+        // The first public service gets the allocated port, every other one a further one
+        let public: Vec<(&str, u16)> = stack
+            .services
+            .iter()
+            .filter_map(|service| service.public.map(|port| (service.name.as_str(), port)))
+            .collect();
+        let extra_ports = ctx
+            .ports
+            .reserve_extra(id, public.len().saturating_sub(1))
+            .await?;
+        let host_ports: HashMap<&str, u16> = public
+            .iter()
+            .map(|(name, _)| *name)
+            .zip(std::iter::once(allocated_port).chain(extra_ports))
+            .collect();
+        let published_ports: Vec<PublishedPort> = public
+            .iter()
+            .map(|(name, container_port)| PublishedPort {
+                container_port: *container_port,
+                protocol: PortProtocol::Tcp,
+                host_port: host_ports[name],
+                name: Some(name.to_string()),
+                http: true,
+            })
+            .collect();
+        blueprint_sdk::debug!(?published_ports, "Configuring port mapping");
+
+        let network = network_name(id);
+        ctx.docker
+            .create_network(CreateNetworkOptions {
+                name: network.clone(),
+                driver: "bridge".to_string(),
+                labels: HashMap::from([
+                    (SERVICE_ID_LABEL.to_string(), id.service_id.to_string()),
+                    (SLOT_LABEL.to_string(), id.slot.clone()),
+                ]),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| {
+                Error::Io(std::io::Error::other(format!(
+                    "Failed to create Docker network {network}: {e}"
+                )))
+            })?;
+
+        // Let go of the ports right before Docker binds them
+        ctx.ports.release(id);
+
+        let mut containers = Vec::with_capacity(stack.services.len());
+        let started = self
+            .start_services(
+                ctx,
+                id,
+                server,
+                &stack,
+                &env_vars,
+                &host_ports,
+                &mut containers,
+            )
+            .await;
+        if let Err(e) = started {
+            teardown(
+                &ctx.docker,
+                &containers,
+                &network,
+                ctx.config.stop.grace_period(),
+            )
+            .await;
+            return Err(e);
+        }
+
+        let main = stack.main();
+        let version = DockerRunner
+            .image_digest(&ctx.docker, &stack.services[main].image)
+            .await?;
+        let main = containers[main].clone();
+        let mut status = RuntimeStatus::default();
+        status.mark_started();
+        let status = SharedStatus::new(status);
+        Ok(RuntimeHandle {
+            shutdown: Self::watch(ctx, id, containers, main.clone(), status.clone()).await,
+            container_id: Some(main),
+            status,
+            port: (!public.is_empty()).then_some(allocated_port),
+            published_ports,
+            logs: None,
+            version,
+        })
+    }
+
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        let Some(document) = server.compose.as_deref() else {
+            return Ok(None);
+        };
+        let stack = Stack::parse(document)?;
+        let mut containers = Vec::with_capacity(stack.services.len());
+        for service in &stack.services {
+            let name = container_name(id, &service.name);
+            let info = match ctx.docker.inspect_container(&name, None).await {
+                Ok(info) => info,
+                Err(DockerError::DockerResponseServerError {
+                    status_code: 404, ..
+                }) => return Ok(None),
+                Err(e) => {
+                    return Err(Error::Io(std::io::Error::other(format!(
+                        "Failed to inspect Docker container {name}: {e}"
+                    ))));
+                }
+            };
+            let state = info.state.unwrap_or_default();
+            let running = state.running.unwrap_or(false);
+            let completed = stack.is_one_shot(&service.name) && state.exit_code == Some(0);
+            if !running && !completed {
+                blueprint_sdk::debug!(container = %name, "Stack container is not running anymore");
+                return Ok(None);
+            }
+            containers.push(info.id.unwrap_or(name));
+        }
+
+        let main = containers[stack.main()].clone();
+        Ok(Some(
+            Self::watch(ctx, id, containers, main, server.status.clone()).await,
+        ))
+    }
+
+    async fn check(&self, ctx: &crate::MyContext) -> Result<bool, Error> {
+        DockerRunner.check(ctx).await
+    }
+
+    async fn install(&self, ctx: &crate::MyContext) -> Result<(), Error> {
+        DockerRunner.install(ctx).await
+    }
+}
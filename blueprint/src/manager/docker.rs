@@ -1,13 +1,99 @@
-use docktopus::bollard::image::{CreateImageOptions, ListImagesOptions};
-use docktopus::bollard::models::PortBinding;
+use docktopus::bollard::auth::DockerCredentials;
+use docktopus::bollard::container::{
+    LogOutput, LogsOptions, RemoveContainerOptions, StopContainerOptions,
+};
+use docktopus::bollard::errors::Error as DockerError;
+use docktopus::bollard::image::ListImagesOptions;
+use docktopus::bollard::models::{HostConfig, PortBinding};
 use docktopus::bollard::secret::{RestartPolicy, RestartPolicyNameEnum};
-// use futures::StreamExt;
-use std::collections::{BTreeMap, HashMap};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::time::Duration;
 use tokio::process::Command;
-use tokio_util::sync::CancellationToken;
 
 use crate::error::Error;
-use crate::manager::ServerRunner;
+use crate::manager::compose::{ComposeRunner, Stack};
+use crate::manager::dockerfile::DockerfileRunner;
+use crate::manager::events::{SERVICE_ID_LABEL, SLOT_LABEL};
+use crate::manager::logs::{LogBuffer, LogStream};
+use crate::manager::status::{RuntimeStatus, SharedStatus};
+use crate::manager::{
+    PublishedPort, RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle, registry, volumes,
+};
+use crate::{PortProtocol, ServerConfig, ServerRuntime};
+
+/// How long to wait before following the logs again after the stream ended,
+/// e.g. because the container is restarting
+const LOG_REFOLLOW_DELAY: Duration = Duration::from_secs(2);
+
+/// The name of the container that runs the given server
+fn container_name(id: &ServerId) -> String {
+    id.name()
+}
+
+/// Capture the output of a container into `buffer` until the task is cancelled
+///
+/// The logs are followed again whenever the stream ends, so output after a restart
+/// is captured too. Docker timestamps are used to skip lines that were already seen.
+pub async fn follow_logs(
+    docker_client: &docktopus::bollard::Docker,
+    container_id: &str,
+    buffer: LogBuffer,
+) {
+    // Lines captured by a previous run of the operator are already in the buffer
+    let mut since = buffer
+        .last_timestamp_ms()
+        .map(|ms| i64::try_from(ms / 1000).unwrap_or_default())
+        .unwrap_or_default();
+    let mut last_seen = String::new();
+
+    loop {
+        let options = LogsOptions::<String> {
+            follow: true,
+            stdout: true,
+            stderr: true,
+            since,
+            timestamps: true,
+            ..Default::default()
+        };
+        let mut logs = docker_client.logs(container_id, Some(options));
+        while let Some(chunk) = logs.next().await {
+            let (stream, message) = match chunk {
+                Ok(LogOutput::StdErr { message }) => (LogStream::Stderr, message),
+                Ok(LogOutput::StdOut { message } | LogOutput::Console { message }) => {
+                    (LogStream::Stdout, message)
+                }
+                Ok(LogOutput::StdIn { .. }) => continue,
+                Err(DockerError::DockerResponseServerError {
+                    status_code: 404, ..
+                }) => return,
+                Err(e) => {
+                    blueprint_sdk::debug!(?e, ?container_id, "Docker logs stream failed");
+                    break;
+                }
+            };
+            for record in String::from_utf8_lossy(&message).lines() {
+                // Every line is prefixed with a fixed-width RFC 3339 timestamp
+                let (timestamp, line) = record.split_once(' ').unwrap_or(("", record));
+                if !timestamp.is_empty() {
+                    if timestamp <= last_seen.as_str() {
+                        continue;
+                    }
+                    last_seen = timestamp.to_string();
+                }
+                buffer.push(stream, line);
+            }
+        }
+
+        tokio::time::sleep(LOG_REFOLLOW_DELAY).await;
+        // Only re-read the last moments, the timestamps take care of the overlap
+        since = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| i64::try_from(d.as_secs()).unwrap_or_default())
+            .unwrap_or_default()
+            - i64::try_from(LOG_REFOLLOW_DELAY.as_secs() * 2).unwrap_or_default();
+    }
+}
 
 /// Docker runner
 #[derive(Debug, Clone)]
@@ -43,9 +129,7 @@ impl DockerRunner {
             "linux" => self.install_docker_linux().await,
             _ => Err(Error::Io(std::io::Error::new(
                 std::io::ErrorKind::Unsupported,
-                format!(
-                    "Docker installation only supported on Linux, detected platform: {os}"
-                ),
+                format!("Docker installation only supported on Linux, detected platform: {os}"),
             ))),
         }
     }
@@ -100,7 +184,7 @@ impl DockerRunner {
     /// * `Err(Error)` if there was an error communicating with the Docker daemon
     ///
     /// # Examples
-    /// ```rust
+    /// ```rust,ignore
     /// let exists = runner.check_image_exists(&docker_client, "nginx:latest").await?;
     /// if exists {
     ///     println!("Image is already available locally");
@@ -133,121 +217,173 @@ impl DockerRunner {
         Ok(!images.is_empty())
     }
 
-    /// Pull a Docker image from a registry using the bollard API
-    ///
-    /// This method downloads a Docker image from a registry (Docker Hub by default)
-    /// to the local Docker daemon. It uses Docker's create_image API which streams
-    /// the download progress and handles layers efficiently.
-    ///
-    /// # Arguments
-    /// * `docker_client` - A reference to the bollard Docker client for API communication
-    /// * `image` - The Docker image name/tag to pull (e.g., "nginx:latest", "ubuntu:20.04")
-    ///
-    /// # Returns
-    /// * `Ok(())` if the image was successfully pulled
-    /// * `Err(Error)` if there was an error during the pull operation
-    ///
-    /// # Behavior
-    /// - Streams the download progress and logs status updates
-    /// - Handles Docker registry authentication if configured
-    /// - Automatically retries failed layer downloads (handled by Docker daemon)
-    /// - Validates image integrity during download
-    ///
-    /// # Examples
-    /// ```rust
-    /// runner.pull_image(&docker_client, "nginx:latest").await?;
-    /// println!("Image pulled successfully");
-    /// ```
-    async fn pull_image(
-        &self,
-        docker_client: &docktopus::bollard::Docker,
-        image: &str,
-    ) -> Result<(), Error> {
-        blueprint_sdk::debug!(?image, "Pulling Docker image");
-
-        use futures::StreamExt;
-
-        // Configure the image pull request
-        // from_image specifies the image name and tag to pull
-        let options = CreateImageOptions {
-            from_image: image,
-            ..Default::default()
-        };
-
-        // Create a stream for the image pull operation
-        // The Docker API returns progress updates as a stream of events
-        let mut stream = docker_client.create_image(Some(options), None, None);
-
-        // Process each event in the pull stream
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(info) => {
-                    // Check if the pull operation encountered an error
-                    if let Some(error) = info.error {
-                        return Err(Error::Io(std::io::Error::other(format!(
-                            "Failed to pull Docker image {image}: {error}"
-                        ))));
-                    }
-                    // Log progress updates for debugging and monitoring
-                    if let Some(status) = info.status {
-                        blueprint_sdk::debug!(?image, status, "Image pull progress");
-                    }
-                }
-                Err(e) => {
-                    // Handle stream errors (network issues, Docker daemon problems, etc.)
-                    return Err(Error::Io(std::io::Error::other(format!(
-                        "Failed to pull Docker image {image}: {e}"
-                    ))));
-                }
-            }
-        }
-
-        blueprint_sdk::debug!(?image, "Docker image pulled successfully");
-        Ok(())
-    }
-
     /// Ensure a Docker image is available locally, pulling it if necessary
     ///
     /// This is a convenience method that combines image existence checking and pulling.
     /// It first checks if the image exists locally, and only pulls it if it's not found.
-    /// This approach is efficient and avoids unnecessary network operations.
+    /// Images from private registries are pulled for every start though, so a caller
+    /// never starts a private image without credentials that give access to it.
     ///
     /// # Arguments
-    /// * `docker_client` - A reference to the bollard Docker client for API communication
+    /// * `ctx` - The context, whose image puller pulls the image
+    /// * `id` - The server the image is pulled for
     /// * `image` - The Docker image name/tag to ensure is available (e.g., "nginx:latest")
+    /// * `credentials` - The credentials to pull the image with, if it needs any
+    /// * `digest` - The digest the image is pinned to, if any (e.g., "sha256:…")
     ///
     /// # Returns
     /// * `Ok(())` if the image is available (either was already present or successfully pulled)
+    /// * `Err(Error::IntegrityMismatch)` if the image does not have the pinned digest
     /// * `Err(Error)` if there was an error checking for or pulling the image
     ///
     /// # Workflow
     /// 1. Check if the image exists locally using `check_image_exists()`
-    /// 2. If image is not found, pull it using `pull_image()`
-    /// 3. If image already exists, skip the pull operation
+    /// 2. If image is not found, is given credentials or was pulled with credentials
+    ///    before, pull it with the [`ImagePuller`], which shares the pull with other
+    ///    servers starting with the same image and credentials
+    /// 3. Otherwise skip the pull operation
+    /// 4. If the image is pinned, compare its digests, pulling a local image that does
+    ///    not match once more in case its tag is out of date
     ///
     /// # Examples
-    /// ```rust
+    /// ```rust,ignore
     /// // This will only pull if the image isn't already present
-    /// runner.ensure_image_available(&docker_client, "nginx:latest").await?;
+    /// runner.ensure_image_available(&ctx, &id, "nginx:latest", None, None).await?;
     /// println!("Image is now available for use");
     /// ```
-    async fn ensure_image_available(
+    ///
+    /// [`ImagePuller`]: crate::manager::images::ImagePuller
+    pub async fn ensure_image_available(
         &self,
-        docker_client: &docktopus::bollard::Docker,
+        ctx: &crate::MyContext,
+        id: &ServerId,
         image: &str,
+        credentials: Option<DockerCredentials>,
+        digest: Option<&str>,
     ) -> Result<(), Error> {
+        let docker_client = &ctx.docker;
         // First check if the image is already available locally
-        if !self.check_image_exists(docker_client, image).await? {
+        let existed = self.check_image_exists(docker_client, image).await?;
+        // A private image is pulled for every start, so the registry checks the
+        // credentials of the caller
+        let private = credentials.is_some()
+            || (existed && ctx.images.is_private(docker_client, image).await?);
+        if !existed {
             // Image not found locally, need to pull it from registry
             blueprint_sdk::debug!(?image, "Image not found locally, pulling");
-            self.pull_image(docker_client, image).await?;
+            ctx.images
+                .pull(docker_client, id, image, credentials.clone())
+                .await?;
+        } else if private {
+            blueprint_sdk::debug!(
+                ?image,
+                "Image is private, pulling with the given credentials"
+            );
+            ctx.images
+                .pull(docker_client, id, image, credentials.clone())
+                .await?;
         } else {
             // Image already exists, no action needed
             blueprint_sdk::debug!(?image, "Image already exists locally");
         }
+
+        let Some(expected) = digest else {
+            return Ok(());
+        };
+        let mut digests = self.repo_digests(docker_client, image).await?;
+        if existed && !private && !digests.iter().any(|digest| digest == expected) {
+            blueprint_sdk::debug!(?image, "Local image does not match its digest, pulling");
+            ctx.images
+                .pull(docker_client, id, image, credentials)
+                .await?;
+            digests = self.repo_digests(docker_client, image).await?;
+        }
+        if !digests.iter().any(|digest| digest == expected) {
+            return Err(Error::IntegrityMismatch {
+                subject: format!("image {image}"),
+                expected: expected.to_string(),
+                actual: digests
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| "none".to_string()),
+            });
+        }
         Ok(())
     }
 
+    /// Pull or build the images of a server ahead of its start
+    ///
+    /// The start jobs call this before they lock the server manager, so other jobs
+    /// are not blocked while the image downloads or builds. The configuration is
+    /// checked first, so nothing is pulled for a server that would be rejected anyway.
+    /// Does nothing for other runtimes, or if Docker still needs to be installed.
+    pub async fn prefetch(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        config: &ServerConfig,
+    ) -> Result<(), Error> {
+        if !config.runtime.is_container() && !matches!(config.runtime, ServerRuntime::Compose) {
+            return Ok(());
+        }
+        config.validate(&ctx.config)?;
+        ctx.policy.check(config)?;
+        if !matches!(self.check(ctx).await, Ok(true)) {
+            return Ok(());
+        }
+        if let (ServerRuntime::Dockerfile, Some(build)) = (&config.runtime, &config.build.0) {
+            DockerfileRunner
+                .build(ctx, id, build, config.registry_auth.0.as_ref())
+                .await?;
+            return Ok(());
+        }
+        if let (ServerRuntime::Compose, Some(document)) = (&config.runtime, &config.compose.0) {
+            let stack = Stack::parse(document)?;
+            return ComposeRunner
+                .pull(ctx, id, &stack, config.registry_auth.0.as_ref())
+                .await;
+        }
+        let credentials = config
+            .registry_auth
+            .0
+            .as_ref()
+            .map(|auth| {
+                registry::credentials(&ctx.config, ctx.operator_key.as_ref(), id.service_id, auth)
+            })
+            .transpose()?;
+        self.ensure_image_available(
+            ctx,
+            id,
+            &config.package,
+            credentials,
+            config.digest.0.as_deref(),
+        )
+        .await
+    }
+
+    /// The digests the registries know a local image by, e.g. `sha256:…`
+    async fn repo_digests(
+        &self,
+        docker_client: &docktopus::bollard::Docker,
+        image: &str,
+    ) -> Result<Vec<String>, Error> {
+        let image_info = docker_client.inspect_image(image).await.map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to inspect Docker image {image}: {e}"
+            )))
+        })?;
+        Ok(image_info
+            .repo_digests
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|reference| {
+                reference
+                    .split_once('@')
+                    .map(|(_, digest)| digest.to_string())
+            })
+            .collect())
+    }
+
     /// Inspect a Docker image and extract exposed ports
     ///
     /// This method queries the Docker daemon to get the image configuration
@@ -258,14 +394,14 @@ impl DockerRunner {
     /// * `image` - The Docker image name/tag to inspect
     ///
     /// # Returns
-    /// * `Ok(Vec<u16>)` - List of exposed ports (empty if no ports exposed)
+    /// * `Ok(Vec<(u16, PortProtocol)>)` - List of exposed ports with their protocol (empty if no ports exposed)
     /// * `Err(Error)` - If there was an error inspecting the image
     #[tracing::instrument(skip(self, docker_client))]
     async fn get_exposed_ports(
         &self,
         docker_client: &docktopus::bollard::Docker,
         image: &str,
-    ) -> Result<Vec<u16>, Error> {
+    ) -> Result<Vec<(u16, PortProtocol)>, Error> {
         blueprint_sdk::debug!(?image, "Inspecting Docker image for exposed ports");
 
         // Inspect the image to get its configuration
@@ -281,11 +417,22 @@ impl DockerRunner {
         if let Some(config) = image_info.config {
             if let Some(exposed_ports_map) = config.exposed_ports {
                 for port_spec in exposed_ports_map.keys() {
-                    // Port specs are in format "3000/tcp" or "8080/udp"
-                    if let Some(port_str) = port_spec.split('/').next() {
-                        if let Ok(port) = port_str.parse::<u16>() {
-                            exposed_ports.push(port);
+                    // Port specs are in format "3000/tcp" or "8080/udp", TCP being the default
+                    let (port_str, protocol) =
+                        port_spec.split_once('/').unwrap_or((port_spec, "tcp"));
+                    let protocol = match protocol {
+                        "tcp" => PortProtocol::Tcp,
+                        "udp" => PortProtocol::Udp,
+                        _ => {
+                            blueprint_sdk::debug!(
+                                ?port_spec,
+                                "Skipping port with unsupported protocol"
+                            );
+                            continue;
                         }
+                    };
+                    if let Ok(port) = port_str.parse::<u16>() {
+                        exposed_ports.push((port, protocol));
                     }
                 }
             }
@@ -297,19 +444,124 @@ impl DockerRunner {
         blueprint_sdk::debug!(?exposed_ports, "Discovered exposed ports from image");
         Ok(exposed_ports)
     }
-}
 
-impl ServerRunner for DockerRunner {
-    #[tracing::instrument(skip(self, ctx), fields(%package, args, service_id, env_vars, runtime = "docker"))]
-    async fn start(
+    /// Resolve the digest of a local image, e.g. `nginx@sha256:...`
+    ///
+    /// Falls back to the image id for images that were never pushed to or pulled
+    /// from a registry.
+    pub async fn image_digest(
         &self,
+        docker_client: &docktopus::bollard::Docker,
+        image: &str,
+    ) -> Result<Option<String>, Error> {
+        let image_info = docker_client.inspect_image(image).await.map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to inspect Docker image {image}: {e}"
+            )))
+        })?;
+        Ok(image_info
+            .repo_digests
+            .and_then(|digests| digests.into_iter().next())
+            .or(image_info.id))
+    }
+
+    /// The host configuration enforcing the resource limits
+    pub fn resource_limits(limits: &crate::ResourceLimits) -> HostConfig {
+        let non_zero = |value: u64| (value != 0).then(|| i64::try_from(value).unwrap_or(i64::MAX));
+        let mib = |value: u64| non_zero(value.saturating_mul(1024 * 1024));
+        let memory = mib(limits.memory_mb);
+        HostConfig {
+            nano_cpus: non_zero(limits.cpu_millis.saturating_mul(1_000_000)),
+            cpu_shares: non_zero(limits.cpu_shares),
+            memory,
+            // Docker's swap limit includes the memory
+            memory_swap: memory
+                .map(|memory| memory.saturating_add(mib(limits.swap_mb).unwrap_or_default())),
+            pids_limit: non_zero(limits.pids),
+            storage_opt: (limits.disk_mb != 0)
+                .then(|| HashMap::from([("size".to_string(), format!("{}M", limits.disk_mb))])),
+            ..Default::default()
+        }
+    }
+
+    /// Force-remove a container with the given name if one exists
+    async fn remove_stale_container(
+        &self,
+        docker_client: &docktopus::bollard::Docker,
+        name: &str,
+    ) -> Result<(), Error> {
+        let options = RemoveContainerOptions {
+            force: true,
+            v: true,
+            link: false,
+        };
+        match docker_client.remove_container(name, Some(options)).await {
+            Ok(()) => {
+                blueprint_sdk::debug!(?name, "Removed stale Docker container");
+                Ok(())
+            }
+            Err(DockerError::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(e) => Err(Error::Io(std::io::Error::other(format!(
+                "Failed to remove stale Docker container {name}: {e}"
+            )))),
+        }
+    }
+
+    /// Track the container status, capture its output and spawn a task that stops
+    /// and removes the container once the server is shut down
+    async fn watch(
         ctx: &crate::MyContext,
-        service_id: u64,
-        package: String,
-        args: Vec<String>,
-        mut env_vars: BTreeMap<String, String>,
-    ) -> Result<CancellationToken, Error> {
-        // Ensure Docker is available
+        id: &ServerId,
+        container_id: String,
+        status: SharedStatus,
+    ) -> ShutdownHandle {
+        let docker_client = ctx.docker.clone();
+        let events = ctx.docker_events.clone();
+        let buffer = ctx.logs.buffer(id).await;
+        let grace_period = ctx.config.stop.grace_period();
+        events.track(&container_id, status);
+
+        ShutdownHandle::spawn(|ct| async move {
+            tokio::select! {
+                () = ct.cancelled() => {}
+                () = follow_logs(&docker_client, &container_id, buffer) => ct.cancelled().await,
+            }
+            // The container is about to go away on purpose
+            events.untrack(&container_id);
+            blueprint_sdk::debug!(?container_id, "Stopping Docker container");
+
+            if let Err(e) = docker_client
+                .stop_container(
+                    &container_id,
+                    Some(StopContainerOptions {
+                        t: i64::try_from(grace_period.as_secs()).unwrap_or(i64::MAX),
+                    }),
+                )
+                .await
+            {
+                blueprint_sdk::error!(?e, ?container_id, "Failed to stop Docker container");
+            }
+            // `v` only removes anonymous volumes, the named volumes of the server are kept
+            if let Err(e) = docker_client
+                .remove_container(
+                    &container_id,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        v: true,
+                        link: false,
+                    }),
+                )
+                .await
+            {
+                blueprint_sdk::error!(?e, ?container_id, "Failed to remove Docker container");
+            }
+        })
+    }
+
+    /// Make sure Docker is available, installing it if it is not
+    pub async fn ensure_installed(&self, ctx: &crate::MyContext) -> Result<(), Error> {
         let mut checked = self.check(ctx).await;
         blueprint_sdk::debug!(?checked, "Checking if Docker is available");
         if !matches!(checked, Ok(true)) {
@@ -324,78 +576,170 @@ impl ServerRunner for DockerRunner {
                 )));
             }
         }
+        Ok(())
+    }
+
+    /// Run a server in a container of the given image
+    ///
+    /// This is the container path of both Docker runtimes: `image` is the server's
+    /// package for prebuilt images, and the image built for the server otherwise.
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "docker")
+    )]
+    pub async fn run(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+        image: &str,
+    ) -> Result<RuntimeHandle, Error> {
+        let package = image.to_string();
+        let mut env_vars = server.env_vars.clone();
+
+        self.ensure_installed(ctx).await?;
 
         let allocated_port = env_vars
             .remove("PORT")
             .and_then(|p| p.parse::<u16>().ok())
             .ok_or(Error::MissingPortBinding)?;
 
-        // Use the struct's docker client
-        let docker_client = ctx.docker.clone();
+        let credentials = server
+            .registry_auth
+            .as_ref()
+            .map(|auth| {
+                registry::credentials(&ctx.config, ctx.operator_key.as_ref(), id.service_id, auth)
+            })
+            .transpose()?;
 
         // Ensure the Docker image is available locally (pull if not present)
-        self.ensure_image_available(&docker_client, &package)
+        self.ensure_image_available(ctx, id, &package, credentials, server.digest.as_deref())
             .await?;
 
-        // Discover exposed ports from the image
-        let exposed_ports = self.get_exposed_ports(&docker_client, &package).await?;
+        // Use the struct's docker client
+        let docker_client = ctx.docker.clone();
+
+        let version = self.image_digest(&docker_client, &package).await?;
+
+        // Discover exposed ports from the image, and add the ones named in the config
+        let mut container_ports = self.get_exposed_ports(&docker_client, &package).await?;
+        container_ports.extend(server.ports.iter().map(|port| (port.port, port.protocol)));
+        container_ports.sort();
+        container_ports.dedup();
 
         // Since docktopus v0.3.0 doesn't support port bindings in Container API,
         // we need to create the container manually using bollard Config
         use docktopus::bollard::container::{
-            Config, CreateContainerOptions, RemoveContainerOptions,
-            StartContainerOptions, StopContainerOptions,
+            Config, CreateContainerOptions, StartContainerOptions,
         };
-        use docktopus::bollard::models::HostConfig;
 
-        // Only configure port bindings if the image exposes ports
-        let port_bindings_map = if let Some(&container_port) = exposed_ports.first() {
-            blueprint_sdk::debug!(%container_port, %allocated_port, "Configuring port mapping");
-        // Set the PORT environment variable for the container that will be used by the server
-            env_vars.insert("PORT".to_string(), container_port.to_string());
+        // The lowest TCP port gets the allocated port, every other port a further one
+        let main_port = container_ports
+            .iter()
+            .copied()
+            .find(|(_, protocol)| *protocol == PortProtocol::Tcp);
+        let others: Vec<(u16, PortProtocol)> = container_ports
+            .iter()
+            .copied()
+            .filter(|port| Some(*port) != main_port)
+            .collect();
+        let extra_ports = ctx.ports.reserve_extra(id, others.len()).await?;
+        let host_ports = main_port
+            .map(|port| (port, allocated_port))
+            .into_iter()
+            .chain(others.into_iter().zip(extra_ports));
+
+        let mut published_ports = Vec::with_capacity(container_ports.len());
+        for ((container_port, protocol), host_port) in host_ports {
+            let named = server
+                .ports
+                .iter()
+                .find(|port| port.port == container_port && port.protocol == protocol);
+            published_ports.push(PublishedPort {
+                container_port,
+                protocol,
+                host_port,
+                name: named.map(|port| port.name.clone()),
+                http: named.is_some_and(|port| port.http),
+            });
+        }
 
-            let mut port_bindings_map: HashMap<String, Option<Vec<PortBinding>>> = HashMap::new();
-            let port_binding = PortBinding {
-                host_ip: Some("127.0.0.1".to_string()),
-                host_port: Some(allocated_port.to_string()),
-            };
-            port_bindings_map.insert(format!("{container_port}/tcp"), Some(vec![port_binding]));
-            Some(port_bindings_map)
+        if let Some((container_port, _)) = main_port {
+            // Set the PORT environment variable for the container that will be used by the server
+            env_vars.insert("PORT".to_string(), container_port.to_string());
         } else {
-            blueprint_sdk::debug!(?package, "No exposed ports found, skipping port mapping");
-            None
-        };
+            blueprint_sdk::debug!(
+                ?package,
+                "No exposed TCP ports found, not publishing the allocated port"
+            );
+        }
+        blueprint_sdk::debug!(?published_ports, "Configuring port mapping");
+
+        // Only configure port bindings if the image exposes ports
+        let port_bindings_map = (!published_ports.is_empty()).then(|| {
+            published_ports
+                .iter()
+                .map(|published| {
+                    let port_binding = PortBinding {
+                        host_ip: Some("127.0.0.1".to_string()),
+                        host_port: Some(published.host_port.to_string()),
+                    };
+                    (
+                        format!("{}/{}", published.container_port, published.protocol),
+                        Some(vec![port_binding]),
+                    )
+                })
+                .collect::<HashMap<String, Option<Vec<PortBinding>>>>()
+        });
+        // Ports named in the config may not be exposed by the image
+        let exposed_ports = port_bindings_map.as_ref().map(|bindings| {
+            bindings
+                .keys()
+                .map(|port| (port.clone(), HashMap::new()))
+                .collect()
+        });
+
+        let published_port = main_port.map(|_| allocated_port);
+
+        let mounts = volumes::create(&docker_client, id, &server.volumes).await?;
 
         // Convert environment variables to Vec<String> format
-        let env: Vec<String> = env_vars
-            .iter()
-            .map(|(k, v)| format!("{k}={v}"))
-            .collect();
+        let env: Vec<String> = env_vars.iter().map(|(k, v)| format!("{k}={v}")).collect();
 
         // Create container configuration with port bindings
         let config = Config {
             image: Some(package.clone()),
-            cmd: Some(args),
+            cmd: Some(server.args.clone()),
             env: Some(env),
+            exposed_ports,
+            labels: Some(HashMap::from([
+                (SERVICE_ID_LABEL.to_string(), id.service_id.to_string()),
+                (SLOT_LABEL.to_string(), id.slot.clone()),
+            ])),
             attach_stdin: Some(true),
             attach_stdout: Some(true),
+            attach_stderr: Some(true),
             host_config: Some(HostConfig {
                 port_bindings: port_bindings_map,
+                mounts: (!mounts.is_empty()).then_some(mounts),
                 restart_policy: Some(RestartPolicy {
                     name: Some(RestartPolicyNameEnum::ON_FAILURE),
                     maximum_retry_count: None,
                 }),
-                // TODO: Add more host configuration options as needed
-                ..Default::default()
+                ..Self::resource_limits(&server.resources)
             }),
             ..Default::default()
         };
 
+        // A container left over from a previous run would make the create fail on a name collision
+        let name = container_name(id);
+        self.remove_stale_container(&docker_client, &name).await?;
+
         // Create the container directly using bollard
         let create_response = docker_client
             .create_container(
                 Some(CreateContainerOptions {
-                    name: format!("server-{service_id}"),
+                    name,
                     platform: None,
                 }),
                 config,
@@ -410,6 +754,9 @@ impl ServerRunner for DockerRunner {
         let container_id = create_response.id;
         blueprint_sdk::debug!(?container_id, "Created Docker container");
 
+        // Let go of the port right before Docker binds it
+        ctx.ports.release(id);
+
         // Start the container
         docker_client
             .start_container(&container_id, None::<StartContainerOptions<String>>)
@@ -422,44 +769,67 @@ impl ServerRunner for DockerRunner {
 
         blueprint_sdk::debug!(?container_id, "Started Docker container");
 
-        // Docker containers run directly without transport conversion
-        let ct = CancellationToken::new();
-
-        // Create cleanup task that will stop the container when cancelled
-        let stop_docker_client = docker_client.clone();
-        let cleanup_container_id = container_id.clone();
-        let cleanup_ct = ct.clone();
+        let mut status = RuntimeStatus::default();
+        status.mark_started();
+        let status = SharedStatus::new(status);
+        Ok(RuntimeHandle {
+            shutdown: Self::watch(ctx, id, container_id.clone(), status.clone()).await,
+            container_id: Some(container_id),
+            status,
+            port: published_port,
+            published_ports,
+            logs: None,
+            version,
+        })
+    }
+}
 
-        tokio::spawn(async move {
-            cleanup_ct.cancelled().await;
-            blueprint_sdk::debug!(?cleanup_container_id, "Stopping Docker container");
+impl ServerRunner for DockerRunner {
+    async fn start(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
+        self.run(ctx, id, server, &server.package).await
+    }
 
-            if let Err(e) = stop_docker_client
-                .stop_container(&cleanup_container_id, Some(StopContainerOptions { t: 10 }))
-                .await
-            {
-                blueprint_sdk::error!(?e, ?cleanup_container_id, "Failed to stop Docker container");
-            }
-            if let Err(e) = stop_docker_client
-                .remove_container(
-                    &cleanup_container_id,
-                    Some(RemoveContainerOptions {
-                        force: true,
-                        v: true,
-                        link: false,
-                    }),
-                )
-                .await
-            {
-                blueprint_sdk::error!(
-                    ?e,
-                    ?cleanup_container_id,
-                    "Failed to remove Docker container"
-                );
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        let container = server
+            .container_id
+            .clone()
+            .unwrap_or_else(|| container_name(id));
+        let info = match ctx.docker.inspect_container(&container, None).await {
+            Ok(info) => info,
+            Err(DockerError::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(e) => {
+                return Err(Error::Io(std::io::Error::other(format!(
+                    "Failed to inspect Docker container {container}: {e}"
+                ))));
             }
-        });
+        };
+
+        let running = info
+            .state
+            .as_ref()
+            .and_then(|state| state.running)
+            .unwrap_or(false);
+        if !running {
+            blueprint_sdk::debug!(?container, "Container is not running anymore");
+            return Ok(None);
+        }
 
-        Ok(ct)
+        let container_id = info.id.unwrap_or(container);
+        Ok(Some(
+            Self::watch(ctx, id, container_id, server.status.clone()).await,
+        ))
     }
 
     async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
@@ -478,5 +848,3 @@ impl ServerRunner for DockerRunner {
         self.install_docker().await
     }
 }
-
-
@@ -0,0 +1,392 @@
+//! Builds the images of the Dockerfile runtime
+//!
+//! The image of a server is built from its inline Dockerfile, its build context,
+//! or both, under the operator's build limits, and tagged per service and slot.
+//! The build is skipped if the tag already holds an image of the same inputs. The
+//! image is then run by the [`DockerRunner`], like a prebuilt one.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use docktopus::bollard::image::BuildImageOptions;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::manager::docker::DockerRunner;
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle, registry};
+use crate::{BuildConfig, RegistryAuth};
+
+/// The label holding the hash of the inputs an image was built from
+const BUILD_HASH_LABEL: &str = "server-blueprint.build-hash";
+/// The name an inline Dockerfile is added to the build context as
+const INLINE_DOCKERFILE: &str = ".server-blueprint.Dockerfile";
+/// The address Docker Hub credentials are keyed by
+const DOCKER_HUB_ADDRESS: &str = "https://index.docker.io/v1/";
+/// The CFS period the CPU limit of a build is enforced over, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+/// Builds an image from a Dockerfile and runs it with the [`DockerRunner`]
+#[derive(Debug, Clone)]
+pub struct DockerfileRunner;
+
+impl DockerfileRunner {
+    /// The tag the image of a server is built as
+    pub fn image_tag(id: &ServerId) -> String {
+        format!("server-blueprint/service-{}:{}", id.service_id, id.slot)
+    }
+
+    /// Build the image of a server, unless its tag already holds an image of the same build
+    ///
+    /// Returns the tag of the image. `registry_auth` is used to pull the base images.
+    pub async fn build(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        build: &BuildConfig,
+        registry_auth: Option<&RegistryAuth>,
+    ) -> Result<String, Error> {
+        let tag = Self::image_tag(id);
+        let hash = hex::encode(Sha256::digest(serde_json::to_vec(build)?));
+        if self.built_hash(ctx, &tag).await.as_deref() == Some(hash.as_str()) {
+            blueprint_sdk::debug!(%id, ?tag, "Image is already built");
+            return Ok(tag);
+        }
+
+        let limits = &ctx.config.builds;
+        let (context, dockerfile) = build_context(build, limits.max_context_bytes()).await?;
+        let credentials = registry_auth
+            .map(|auth| {
+                registry::credentials(&ctx.config, ctx.operator_key.as_ref(), id.service_id, auth)
+            })
+            .transpose()?
+            .map(|credentials| {
+                let address = credentials
+                    .serveraddress
+                    .clone()
+                    .unwrap_or_else(|| DOCKER_HUB_ADDRESS.to_string());
+                HashMap::from([(address, credentials)])
+            });
+        let non_zero = |value: u64| (value != 0).then_some(value);
+        let memory = non_zero(limits.memory_mb.saturating_mul(1024 * 1024));
+        let options = BuildImageOptions {
+            dockerfile,
+            t: tag.clone(),
+            rm: true,
+            forcerm: true,
+            memory,
+            // No swap on top of the memory
+            memswap: memory.map(|memory| i64::try_from(memory).unwrap_or(i64::MAX)),
+            cpuperiod: non_zero(limits.cpu_millis).map(|_| CPU_PERIOD),
+            cpuquota: non_zero(limits.cpu_millis.saturating_mul(CPU_PERIOD) / 1000),
+            buildargs: build
+                .args
+                .0
+                .iter()
+                .flat_map(|args| args.0.iter().cloned())
+                .collect(),
+            labels: HashMap::from([(BUILD_HASH_LABEL.to_string(), hash)]),
+            target: build.target.0.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+
+        blueprint_sdk::info!(%id, ?tag, "Building Docker image");
+        // Dropping the stream on a timeout disconnects from the daemon, which cancels the build
+        let mut stream = ctx
+            .docker
+            .build_image(options, credentials, Some(context.into()));
+        let built = tokio::time::timeout(limits.timeout(), async {
+            while let Some(info) = stream.next().await {
+                let info = info.map_err(|e| e.to_string())?;
+                if let Some(error) = info.error {
+                    return Err(error);
+                }
+                if let Some(line) = info.stream.as_deref().map(str::trim_end) {
+                    if !line.is_empty() {
+                        blueprint_sdk::debug!(%id, "{line}");
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(format!(
+                "the build timed out after {}s",
+                limits.timeout_secs
+            ))
+        });
+        built.map_err(|reason| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to build the image of server {id}: {reason}"
+            )))
+        })?;
+
+        blueprint_sdk::info!(%id, ?tag, "Built Docker image");
+        Ok(tag)
+    }
+
+    /// The build hash of the image a tag holds, if it exists
+    async fn built_hash(&self, ctx: &crate::MyContext, tag: &str) -> Option<String> {
+        ctx.docker
+            .inspect_image(tag)
+            .await
+            .ok()?
+            .config?
+            .labels?
+            .remove(BUILD_HASH_LABEL)
+    }
+}
+
+/// The images a build pulls, if they can be told from its inline Dockerfile
+///
+/// These are the images of the `FROM` instructions and the ones `COPY --from`, `ADD
+/// --from` and `RUN --mount` read from. Stages and `scratch` are skipped, and the
+/// build arguments are substituted. `None` if the Dockerfile is in the context, or
+/// an image depends on an argument without a value.
+pub fn base_images(build: &BuildConfig) -> Option<Vec<String>> {
+    let dockerfile = build.dockerfile.0.as_deref()?;
+    let overrides: HashMap<&str, &str> = build
+        .args
+        .0
+        .iter()
+        .flat_map(|args| args.0.iter())
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    // Only the arguments declared before the first stage apply to `FROM`
+    let mut args = HashMap::new();
+    let mut stages = Vec::new();
+    let mut images = Vec::new();
+    for line in instructions(dockerfile) {
+        let mut words = line.split_whitespace();
+        let Some(instruction) = words.next() else {
+            continue;
+        };
+        let mut image = |reference: &str, stages: &[String]| {
+            let reference = substitute(reference, &args)?;
+            let lowercase = reference.to_ascii_lowercase();
+            let stage = lowercase == "scratch"
+                || stages.contains(&lowercase)
+                || reference.bytes().all(|b| b.is_ascii_digit());
+            if !stage {
+                images.push(reference);
+            }
+            Some(())
+        };
+        match instruction.to_ascii_uppercase().as_str() {
+            "ARG" if stages.is_empty() => {
+                for declaration in words {
+                    let (name, default) = match declaration.split_once('=') {
+                        Some((name, default)) => (name, Some(default)),
+                        None => (declaration, None),
+                    };
+                    if let Some(value) = overrides.get(name).copied().or(default) {
+                        args.insert(name.to_string(), value.trim_matches('"').to_string());
+                    }
+                }
+            }
+            "FROM" => {
+                let mut operands = words.filter(|word| !word.starts_with("--"));
+                image(operands.next()?, &stages)?;
+                let alias = match (operands.next(), operands.next()) {
+                    (Some(keyword), Some(alias)) if keyword.eq_ignore_ascii_case("as") => {
+                        alias.to_ascii_lowercase()
+                    }
+                    _ => String::new(),
+                };
+                stages.push(alias);
+            }
+            "COPY" | "ADD" => {
+                for from in words.filter_map(|word| word.strip_prefix("--from=")) {
+                    image(from, &stages)?;
+                }
+            }
+            "RUN" => {
+                let mounts = words.filter_map(|word| word.strip_prefix("--mount="));
+                for from in mounts.flat_map(|mount| {
+                    mount
+                        .split(',')
+                        .filter_map(|option| option.strip_prefix("from="))
+                }) {
+                    image(from, &stages)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(images)
+}
+
+/// The instructions of a Dockerfile, with their continuation lines joined and
+/// without comments
+fn instructions(dockerfile: &str) -> Vec<String> {
+    let mut instructions = Vec::new();
+    let mut current = String::new();
+    for line in dockerfile.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        match line.strip_suffix('\\') {
+            Some(line) => {
+                current.push_str(line);
+                current.push(' ');
+            }
+            None => {
+                current.push_str(line);
+                instructions.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    instructions.push(current);
+    instructions
+}
+
+/// Substitute the `$NAME` and `${NAME}` arguments in a word, `None` if one has no value
+fn substitute(word: &str, args: &HashMap<String, String>) -> Option<String> {
+    let mut substituted = String::new();
+    let mut rest = word;
+    while let Some(dollar) = rest.find('$') {
+        substituted.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced.find('}')?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            None => {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        substituted.push_str(args.get(name)?);
+        rest = after;
+    }
+    substituted.push_str(rest);
+    Some(substituted)
+}
+
+/// The build context to send to Docker, and the path of the Dockerfile in it
+async fn build_context(build: &BuildConfig, max_bytes: u64) -> Result<(Vec<u8>, String), Error> {
+    let context = build
+        .context
+        .0
+        .as_ref()
+        .map(|context| BASE64.decode(context))
+        .transpose()
+        .map_err(|_| Error::InvalidConfig("the build context is not base64".to_string()))?;
+    match (context, build.dockerfile.0.clone()) {
+        (Some(context), None) => {
+            let path = build
+                .dockerfile_path
+                .0
+                .clone()
+                .unwrap_or_else(|| "Dockerfile".to_string());
+            let context = tokio::task::spawn_blocking(move || unpacked(&context, max_bytes))
+                .await
+                .map_err(std::io::Error::other)??;
+            Ok((context, path))
+        }
+        (context, Some(dockerfile)) => {
+            let context = tokio::task::spawn_blocking(move || {
+                with_dockerfile(context.as_deref(), &dockerfile, max_bytes)
+            })
+            .await
+            .map_err(std::io::Error::other)??;
+            Ok((context, INLINE_DOCKERFILE.to_string()))
+        }
+        (None, None) => Err(Error::InvalidConfig(
+            "a build needs a `dockerfile` or a `context`".to_string(),
+        )),
+    }
+}
+
+/// The tarball of a context, unzipped if it is gzipped, as long as it is at most
+/// `max_bytes` large
+fn unpacked(context: &[u8], max_bytes: u64) -> Result<Vec<u8>, Error> {
+    // Contexts may be gzipped, which Docker detects by the same magic bytes
+    let mut reader: Box<dyn Read + '_> = if context.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(context))
+    } else {
+        Box::new(context)
+    };
+    let mut tarball = Vec::new();
+    (&mut reader)
+        .take(max_bytes + 1)
+        .read_to_end(&mut tarball)?;
+    if tarball.len() as u64 > max_bytes {
+        return Err(Error::InvalidConfig(
+            "the unpacked build context is larger than the operator allows".to_string(),
+        ));
+    }
+    Ok(tarball)
+}
+
+/// A tarball of the context, or of an empty one, with the inline Dockerfile added
+fn with_dockerfile(
+    context: Option<&[u8]>,
+    dockerfile: &str,
+    max_bytes: u64,
+) -> Result<Vec<u8>, Error> {
+    let mut tarball = Vec::new();
+    if let Some(context) = context {
+        tarball = unpacked(context, max_bytes)?;
+        // Cut off the end-of-archive blocks, so the Dockerfile is appended as it is
+        let mut end = 0;
+        for entry in tar::Archive::new(tarball.as_slice()).entries()? {
+            let entry = entry?;
+            end = entry.raw_file_position() + entry.header().entry_size()?.div_ceil(512) * 512;
+        }
+        tarball.truncate(usize::try_from(end).unwrap_or(usize::MAX));
+    }
+
+    let mut builder = tar::Builder::new(tarball);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(dockerfile.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, INLINE_DOCKERFILE, dockerfile.as_bytes())?;
+    Ok(builder.into_inner()?)
+}
+
+impl ServerRunner for DockerfileRunner {
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "dockerfile")
+    )]
+    async fn start(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
+        let build = server.build.as_ref().ok_or_else(|| {
+            Error::InvalidConfig("the Dockerfile runtime needs a `build`".to_string())
+        })?;
+        DockerRunner.ensure_installed(ctx).await?;
+        let image = self
+            .build(ctx, id, build, server.registry_auth.as_ref())
+            .await?;
+        DockerRunner.run(ctx, id, server, &image).await
+    }
+
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        DockerRunner.adopt(ctx, id, server).await
+    }
+
+    async fn check(&self, ctx: &crate::MyContext) -> Result<bool, Error> {
+        DockerRunner.check(ctx).await
+    }
+
+    async fn install(&self, ctx: &crate::MyContext) -> Result<(), Error> {
+        DockerRunner.install(ctx).await
+    }
+}
@@ -0,0 +1,176 @@
+//! Docker events watcher
+//!
+//! Subscribes to the Docker events API for the containers managed by this blueprint
+//! and keeps the [`SharedStatus`] of each tracked container in sync, so crashes,
+//! OOM kills, restarts and manual removals are noticed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use docktopus::bollard::Docker;
+use docktopus::bollard::errors::Error as DockerError;
+use docktopus::bollard::models::ContainerStateStatusEnum;
+use docktopus::bollard::system::EventsOptions;
+use futures::StreamExt;
+
+use crate::manager::status::{ServerState, SharedStatus};
+
+/// The label set on every container created by this blueprint
+pub const SERVICE_ID_LABEL: &str = "server-blueprint.service-id";
+/// The label holding the slot of the server a container runs
+pub const SLOT_LABEL: &str = "server-blueprint.slot";
+
+/// How long to wait before resubscribing after the events stream broke
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps the status of the tracked containers in sync with the Docker daemon
+#[derive(Debug, Clone)]
+pub struct DockerEventsWatcher {
+    docker: Arc<Docker>,
+    tracked: Arc<Mutex<HashMap<String, SharedStatus>>>,
+}
+
+impl DockerEventsWatcher {
+    /// Create the watcher and spawn its background subscription
+    pub fn spawn(docker: Arc<Docker>) -> Self {
+        let watcher = Self {
+            docker,
+            tracked: Arc::default(),
+        };
+        tokio::spawn(watcher.clone().run());
+        watcher
+    }
+
+    /// Start keeping `status` in sync with the container
+    pub fn track(&self, container_id: &str, status: SharedStatus) {
+        self.tracked().insert(container_id.to_string(), status);
+    }
+
+    /// Stop tracking the container, e.g. because it is being stopped on request
+    pub fn untrack(&self, container_id: &str) {
+        self.tracked().remove(container_id);
+    }
+
+    fn tracked(&self) -> std::sync::MutexGuard<'_, HashMap<String, SharedStatus>> {
+        self.tracked.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn status_of(&self, container_id: &str) -> Option<SharedStatus> {
+        self.tracked().get(container_id).cloned()
+    }
+
+    async fn run(self) {
+        loop {
+            let options = EventsOptions::<String> {
+                filters: HashMap::from([
+                    ("type".to_string(), vec!["container".to_string()]),
+                    ("label".to_string(), vec![SERVICE_ID_LABEL.to_string()]),
+                ]),
+                ..Default::default()
+            };
+            let mut events = self.docker.events(Some(options));
+
+            // Events may have been missed while (re)subscribing
+            self.refresh_all().await;
+
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        blueprint_sdk::warn!(?e, "Docker events stream failed");
+                        break;
+                    }
+                };
+                let Some(container_id) = event.actor.and_then(|actor| actor.id) else {
+                    continue;
+                };
+                let Some(status) = self.status_of(&container_id) else {
+                    continue;
+                };
+                let action = event.action.unwrap_or_default();
+                blueprint_sdk::debug!(%container_id, %action, "Docker container event");
+                if action == "oom" {
+                    status.update(|s| s.oom_killed = true);
+                }
+                self.refresh(&container_id, &status).await;
+            }
+
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    /// Refresh the status of every tracked container
+    async fn refresh_all(&self) {
+        let tracked: Vec<_> = self
+            .tracked()
+            .iter()
+            .map(|(id, status)| (id.clone(), status.clone()))
+            .collect();
+        for (container_id, status) in tracked {
+            self.refresh(&container_id, &status).await;
+        }
+    }
+
+    /// Update the status from the current container state
+    async fn refresh(&self, container_id: &str, status: &SharedStatus) {
+        let info = match self.docker.inspect_container(container_id, None).await {
+            Ok(info) => info,
+            Err(DockerError::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                blueprint_sdk::warn!(%container_id, "Tracked container was removed");
+                status.update(|s| s.state = ServerState::Failed);
+                self.untrack(container_id);
+                return;
+            }
+            Err(e) => {
+                blueprint_sdk::debug!(%container_id, ?e, "Failed to inspect container");
+                return;
+            }
+        };
+
+        let restart_count = info.restart_count.unwrap_or_default();
+        let state = info.state.unwrap_or_default();
+        let exit_code = state.exit_code;
+        let server_state = match state.status {
+            Some(ContainerStateStatusEnum::RESTARTING) => ServerState::Restarting,
+            Some(ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD) => {
+                if exit_code == Some(0) {
+                    ServerState::Exited
+                } else {
+                    ServerState::Failed
+                }
+            }
+            Some(ContainerStateStatusEnum::REMOVING) => ServerState::Stopped,
+            _ => ServerState::Running,
+        };
+
+        let previous = status.get();
+        if previous.state != server_state {
+            blueprint_sdk::info!(
+                %container_id,
+                state = ?server_state,
+                ?exit_code,
+                "Container state changed"
+            );
+        }
+        let restart_count = u32::try_from(restart_count).unwrap_or_default();
+        status.update(|s| {
+            // Restarts can be quicker than the events, so also look at the restart count
+            if server_state == ServerState::Running
+                && (previous.state != ServerState::Running
+                    || previous.restart_count != restart_count)
+            {
+                s.mark_started();
+            }
+            s.state = server_state;
+            s.restart_count = restart_count;
+            // Keep the details of the last exit while the container is running again
+            if server_state != ServerState::Running {
+                s.exit_code = exit_code;
+                s.oom_killed = state.oom_killed.unwrap_or(s.oom_killed);
+            }
+        });
+    }
+}
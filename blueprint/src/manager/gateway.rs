@@ -0,0 +1,286 @@
+//! Routes the requests of a service to the servers in its slots
+//!
+//! The auth proxy forwards all requests of a service to a single endpoint, the
+//! gateway of the service, which picks the port by the longest path prefix it has
+//! a route for: with a route for `/{slot}`, `/{slot}/rest` is forwarded as
+//! `/rest` with an `X-Forwarded-Prefix: /{slot}` header. Requests no prefix matches
+//! go to the route for the empty prefix, unchanged.
+//!
+//! The gateway also answers the token checks of the tunnels itself and never
+//! forwards them, so no server learns the nonce of a check, see
+//! [`tunnel`](crate::manager::tunnel).
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::Error;
+use crate::manager::ShutdownHandle;
+use crate::manager::tunnel::{TokenVerifier, VERIFY_PATH};
+
+/// The maximum size of a request head
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// How long to back off after failing to accept a connection
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The ports of the servers of a service, by path prefix
+type Routes = Arc<Mutex<BTreeMap<String, u16>>>;
+
+/// A gateway in front of the servers of a service
+#[derive(Debug)]
+pub struct Gateway {
+    port: u16,
+    routes: Routes,
+    shutdown: ShutdownHandle,
+}
+
+impl Gateway {
+    /// Start a gateway on the given local port, routing to the given server ports
+    pub async fn start(
+        port: u16,
+        service_id: u64,
+        routes: BTreeMap<String, u16>,
+        verifier: TokenVerifier,
+    ) -> Result<Self, Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let routes = Arc::new(Mutex::new(routes));
+        let shutdown = ShutdownHandle::spawn({
+            let routes = routes.clone();
+            move |token| async move {
+                loop {
+                    let accepted = tokio::select! {
+                        () = token.cancelled() => break,
+                        accepted = listener.accept() => accepted,
+                    };
+                    let client = match accepted {
+                        Ok((client, _)) => client,
+                        Err(e) => {
+                            blueprint_sdk::warn!(?e, %port, "Gateway failed to accept a connection");
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let routes = routes.clone();
+                    let verifier = verifier.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = forward(client, service_id, &routes, &verifier).await {
+                            blueprint_sdk::debug!(?e, %port, "Gateway failed to forward a request");
+                        }
+                    });
+                }
+            }
+        });
+        blueprint_sdk::debug!(%port, "Started gateway");
+        Ok(Self {
+            port,
+            routes,
+            shutdown,
+        })
+    }
+
+    /// The local port the gateway listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Replace the server ports the gateway routes to
+    pub fn set_routes(&self, routes: BTreeMap<String, u16>) {
+        *self.routes.lock().unwrap_or_else(|e| e.into_inner()) = routes;
+    }
+
+    /// Stop accepting connections
+    ///
+    /// Requests that are being forwarded are not interrupted.
+    pub async fn shutdown(self) {
+        self.shutdown.shutdown().await;
+    }
+}
+
+/// Forward a single request, and whatever follows on an upgraded connection
+async fn forward(
+    mut client: TcpStream,
+    service_id: u64,
+    routes: &Mutex<BTreeMap<String, u16>>,
+    verifier: &TokenVerifier,
+) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(4096);
+    let head_len = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return respond(&mut client, "431 Request Header Fields Too Large").await;
+        }
+        if client.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    };
+    let Ok(head) = std::str::from_utf8(&buf[..head_len]) else {
+        return respond(&mut client, "400 Bad Request").await;
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().splitn(3, ' ');
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return respond(&mut client, "400 Bad Request").await;
+    };
+
+    // Only requests with a valid token of this service make it through the auth proxy
+    if let Some(nonce) = target.strip_prefix(VERIFY_PATH) {
+        return if verifier.confirm(service_id, nonce) {
+            respond(&mut client, "204 No Content").await
+        } else {
+            respond(&mut client, "404 Not Found").await
+        };
+    }
+
+    let route = route(target, &routes.lock().unwrap_or_else(|e| e.into_inner()));
+    let Some((port, target, prefix)) = route else {
+        return respond(&mut client, "404 Not Found").await;
+    };
+
+    let headers: Vec<&str> = lines.filter(|line| !line.is_empty()).collect();
+    let is_header = |line: &str, name: &str| {
+        line.split_once(':')
+            .is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+    };
+    // Only upgraded connections are kept open, anything else is one request per connection
+    let upgrade = headers.iter().any(|line| is_header(line, "upgrade"));
+    let mut upstream_head = format!("{method} {target} {version}\r\n");
+    for line in headers {
+        let hop_by_hop = is_header(line, "connection") || is_header(line, "keep-alive");
+        if is_header(line, "x-forwarded-prefix") || (hop_by_hop && !upgrade) {
+            continue;
+        }
+        upstream_head.push_str(line);
+        upstream_head.push_str("\r\n");
+    }
+    if !upgrade {
+        upstream_head.push_str("Connection: close\r\n");
+    }
+    if let Some(prefix) = prefix {
+        upstream_head.push_str(&format!("X-Forwarded-Prefix: {prefix}\r\n"));
+    }
+    upstream_head.push_str("\r\n");
+
+    let Ok(mut upstream) = TcpStream::connect(("127.0.0.1", port)).await else {
+        return respond(&mut client, "502 Bad Gateway").await;
+    };
+    upstream.write_all(upstream_head.as_bytes()).await?;
+    upstream.write_all(&buf[head_len..]).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Pick the port for a request target
+///
+/// Returns the port, the target to send it and the stripped prefix.
+fn route(target: &str, routes: &BTreeMap<String, u16>) -> Option<(u16, String, Option<String>)> {
+    let path = target.split('?').next().unwrap_or_default();
+    // Prefixes only match whole path segments
+    let matched = routes
+        .iter()
+        .filter(|(prefix, _)| {
+            !prefix.is_empty()
+                && path
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(prefix, _)| prefix.len());
+    if let Some((prefix, port)) = matched {
+        let rest = &target[prefix.len()..];
+        let rest = if rest.starts_with('/') {
+            rest.to_string()
+        } else {
+            format!("/{rest}")
+        };
+        return Some((*port, rest, Some(prefix.clone())));
+    }
+    routes.get("").map(|port| (*port, target.to_string(), None))
+}
+
+/// Answer a request the gateway cannot forward
+async fn respond(client: &mut TcpStream, status: &str) -> Result<(), Error> {
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(routes: &[(&str, u16)]) -> BTreeMap<String, u16> {
+        routes
+            .iter()
+            .map(|(prefix, port)| (prefix.to_string(), *port))
+            .collect()
+    }
+
+    fn routed(
+        target: &str,
+        routes: &BTreeMap<String, u16>,
+    ) -> Option<(u16, String, Option<String>)> {
+        route(target, routes)
+    }
+
+    #[test]
+    fn the_longest_prefix_wins() {
+        let routes = routes(&[
+            ("", 1),
+            ("/default", 1),
+            ("/default/metrics", 2),
+            ("/db", 3),
+        ]);
+        assert_eq!(
+            routed("/default/metrics/cpu", &routes),
+            Some((2, "/cpu".to_string(), Some("/default/metrics".to_string())))
+        );
+        assert_eq!(
+            routed("/default/api", &routes),
+            Some((1, "/api".to_string(), Some("/default".to_string())))
+        );
+        assert_eq!(
+            routed("/db", &routes),
+            Some((3, "/".to_string(), Some("/db".to_string())))
+        );
+    }
+
+    #[test]
+    fn prefixes_only_match_whole_segments() {
+        let routes = routes(&[("", 1), ("/db", 3)]);
+        assert_eq!(
+            routed("/dbx/a", &routes),
+            Some((1, "/dbx/a".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn the_query_is_kept_but_not_matched() {
+        let routes = routes(&[("/db", 3)]);
+        assert_eq!(
+            routed("/db?x=/db/y", &routes),
+            Some((3, "/?x=/db/y".to_string(), Some("/db".to_string())))
+        );
+        assert_eq!(
+            routed("/db/a?b", &routes),
+            Some((3, "/a?b".to_string(), Some("/db".to_string())))
+        );
+    }
+
+    #[test]
+    fn unmatched_targets_need_a_default_route() {
+        assert_eq!(
+            routed("/other", &routes(&[("", 1)])),
+            Some((1, "/other".to_string(), None))
+        );
+        assert_eq!(routed("/other", &routes(&[("/db", 3)])), None);
+    }
+}
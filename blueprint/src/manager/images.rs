@@ -0,0 +1,364 @@
+//! Docker image pulls
+//!
+//! Pulls are shared: servers that start with the same image and the same
+//! credentials while it is being pulled wait for the same download instead of
+//! starting their own. A pull is aborted once it takes longer than the operator's
+//! pull timeout, or once no start waits for it anymore, e.g. because the job that
+//! requested it was aborted.
+//!
+//! Images pulled with credentials are remembered as private. Only a pull proves
+//! access to them, so they are pulled again for every start that uses them.
+//!
+//! The progress of a pull is tracked in bytes, summed over the layers Docker
+//! reports, so the status job can show how far a starting server's image is.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use docktopus::bollard::Docker;
+use docktopus::bollard::auth::DockerCredentials;
+use docktopus::bollard::image::CreateImageOptions;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Error;
+use crate::manager::ServerId;
+
+/// How far a pull is
+#[derive(Debug, Clone, Default)]
+pub struct PullProgress {
+    /// The image being pulled
+    pub image: String,
+    /// The bytes of the layers downloaded so far
+    pub downloaded_bytes: u64,
+    /// The size of the layers Docker has reported so far
+    ///
+    /// It grows while the pull runs, as Docker only reports the size of a layer
+    /// once it starts downloading it.
+    pub total_bytes: u64,
+    /// When the pull started, in seconds since the Unix epoch
+    pub started_at: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PullState {
+    progress: PullProgress,
+    /// Set once the pull has finished, with the reason it failed
+    result: Option<Result<(), String>>,
+}
+
+/// What pulls are shared by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PullKey {
+    image: String,
+    /// A hash of the credentials, so a start never waits for a pull made with the
+    /// credentials of another
+    credentials: Option<[u8; 32]>,
+}
+
+impl PullKey {
+    fn new(image: &str, credentials: Option<&DockerCredentials>) -> Self {
+        let credentials = credentials.map(|credentials| {
+            let json = serde_json::to_vec(credentials).unwrap_or_default();
+            Sha256::digest(json).into()
+        });
+        Self {
+            image: image.to_string(),
+            credentials,
+        }
+    }
+}
+
+/// A running pull
+#[derive(Debug)]
+struct Pull {
+    /// Tells entries of earlier pulls of the same image apart
+    generation: u64,
+    /// The servers waiting for the pull, once per start
+    waiters: Vec<ServerId>,
+    cancel: CancellationToken,
+    state: watch::Receiver<PullState>,
+}
+
+#[derive(Debug, Default)]
+struct Pulls {
+    next_generation: u64,
+    running: HashMap<PullKey, Pull>,
+}
+
+/// Pulls Docker images, sharing the pulls of the same image
+#[derive(Debug, Clone)]
+pub struct ImagePuller {
+    pulls: Arc<Mutex<Pulls>>,
+    timeout: Duration,
+    /// The ids of the images pulled with credentials
+    private: Arc<tokio::sync::Mutex<BTreeSet<String>>>,
+    /// Where the private images are persisted
+    private_path: PathBuf,
+}
+
+impl ImagePuller {
+    /// A puller aborting pulls that take longer than `timeout`, persisting the
+    /// private images at `private_path`
+    pub async fn load(timeout: Duration, private_path: PathBuf) -> Result<Self, Error> {
+        let private = match tokio::fs::read(&private_path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        Ok(Self {
+            pulls: Arc::default(),
+            timeout,
+            private: Arc::new(tokio::sync::Mutex::new(private)),
+            private_path,
+        })
+    }
+
+    fn pulls(&self) -> std::sync::MutexGuard<'_, Pulls> {
+        self.pulls.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether the local image was pulled with credentials
+    pub async fn is_private(&self, docker: &Docker, image: &str) -> Result<bool, Error> {
+        let image_id = image_id(docker, image).await?;
+        Ok(self.private.lock().await.contains(&image_id))
+    }
+
+    /// Remember the local image as private, so it is never used without a pull
+    async fn mark_private(&self, docker: &Docker, image: &str) -> Result<(), Error> {
+        let image_id = image_id(docker, image).await?;
+        let mut private = self.private.lock().await;
+        if !private.insert(image_id) {
+            return Ok(());
+        }
+        if let Some(dir) = self.private_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = self.private_path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&*private)?).await?;
+        tokio::fs::rename(&tmp, &self.private_path).await?;
+        Ok(())
+    }
+
+    /// Pull an image for the given server, or wait for the running pull of it with
+    /// the same credentials
+    ///
+    /// Dropping the returned future stops waiting for the pull, which aborts it
+    /// if no other start waits for it.
+    pub async fn pull(
+        &self,
+        docker: &Docker,
+        id: &ServerId,
+        image: &str,
+        credentials: Option<DockerCredentials>,
+    ) -> Result<(), Error> {
+        let key = PullKey::new(image, credentials.as_ref());
+        let authenticated = credentials.is_some();
+        let (generation, mut state) = {
+            let mut pulls = self.pulls();
+            let pulls = &mut *pulls;
+            let pull = match pulls.running.get_mut(&key) {
+                Some(pull) => {
+                    blueprint_sdk::debug!(%id, ?image, "Waiting for the running pull of the image");
+                    pull
+                }
+                None => {
+                    pulls.next_generation += 1;
+                    let pull = self.spawn(
+                        docker.clone(),
+                        key.clone(),
+                        credentials,
+                        pulls.next_generation,
+                    );
+                    pulls.running.entry(key.clone()).or_insert(pull)
+                }
+            };
+            pull.waiters.push(id.clone());
+            (pull.generation, pull.state.clone())
+        };
+        let waiter = Waiter {
+            puller: self,
+            key: &key,
+            id,
+            generation,
+        };
+
+        let result = match state.wait_for(|state| state.result.is_some()).await {
+            Ok(state) => state.result.clone().unwrap_or(Ok(())),
+            Err(_) => Err("the pull stopped unexpectedly".to_string()),
+        };
+        drop(waiter);
+        result.map_err(|reason| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to pull Docker image {image}: {reason}"
+            )))
+        })?;
+        if authenticated {
+            self.mark_private(docker, image).await?;
+        }
+        Ok(())
+    }
+
+    /// The progress of the pull the given server waits for, if any
+    pub fn progress(&self, id: &ServerId) -> Option<PullProgress> {
+        self.pulls()
+            .running
+            .values()
+            .find(|pull| pull.waiters.contains(id))
+            .map(|pull| pull.state.borrow().progress.clone())
+    }
+
+    /// Start pulling an image in the background
+    fn spawn(
+        &self,
+        docker: Docker,
+        key: PullKey,
+        credentials: Option<DockerCredentials>,
+        generation: u64,
+    ) -> Pull {
+        let image = key.image.clone();
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let (sender, state) = watch::channel(PullState {
+            progress: PullProgress {
+                image: image.clone(),
+                started_at,
+                ..Default::default()
+            },
+            result: None,
+        });
+        let cancel = CancellationToken::new();
+
+        let pulls = self.pulls.clone();
+        let timeout = self.timeout;
+        let cancelled = cancel.clone();
+        tokio::spawn(async move {
+            blueprint_sdk::info!(?image, "Pulling Docker image");
+            let result = tokio::select! {
+                () = cancelled.cancelled() => Err("the pull was cancelled".to_string()),
+                pulled = tokio::time::timeout(timeout, download(&docker, &image, credentials, &sender)) => {
+                    pulled.unwrap_or_else(|_| {
+                        Err(format!("the pull timed out after {}s", timeout.as_secs()))
+                    })
+                }
+            };
+            match &result {
+                Ok(()) => blueprint_sdk::info!(?image, "Pulled Docker image"),
+                Err(reason) => blueprint_sdk::warn!(?image, reason, "Failed to pull Docker image"),
+            }
+
+            {
+                let mut pulls = pulls.lock().unwrap_or_else(|e| e.into_inner());
+                if pulls
+                    .running
+                    .get(&key)
+                    .is_some_and(|pull| pull.generation == generation)
+                {
+                    pulls.running.remove(&key);
+                }
+            }
+            sender.send_modify(|state| state.result = Some(result));
+        });
+
+        Pull {
+            generation,
+            waiters: Vec::new(),
+            cancel,
+            state,
+        }
+    }
+}
+
+/// A start waiting for a pull, which aborts the pull when the last one is dropped
+struct Waiter<'a> {
+    puller: &'a ImagePuller,
+    key: &'a PullKey,
+    id: &'a ServerId,
+    generation: u64,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut pulls = self.puller.pulls();
+        let Some(pull) = pulls
+            .running
+            .get_mut(self.key)
+            .filter(|pull| pull.generation == self.generation)
+        else {
+            return;
+        };
+        if let Some(waiter) = pull.waiters.iter().position(|id| id == self.id) {
+            pull.waiters.swap_remove(waiter);
+        }
+        if pull.waiters.is_empty() {
+            blueprint_sdk::debug!(image = ?self.key.image, "No start waits for the pull anymore, aborting it");
+            pull.cancel.cancel();
+            pulls.running.remove(self.key);
+        }
+    }
+}
+
+/// The id of a local image
+async fn image_id(docker: &Docker, image: &str) -> Result<String, Error> {
+    let info = docker.inspect_image(image).await.map_err(|e| {
+        Error::Io(std::io::Error::other(format!(
+            "Failed to inspect Docker image {image}: {e}"
+        )))
+    })?;
+    Ok(info.id.unwrap_or_default())
+}
+
+/// Download an image, reporting the progress to `state`
+async fn download(
+    docker: &Docker,
+    image: &str,
+    credentials: Option<DockerCredentials>,
+    state: &watch::Sender<PullState>,
+) -> Result<(), String> {
+    let options = CreateImageOptions {
+        from_image: image,
+        ..Default::default()
+    };
+    let mut stream = docker.create_image(Some(options), None, credentials);
+
+    // The downloaded and total bytes of every layer
+    let mut layers: HashMap<String, (u64, u64)> = HashMap::new();
+    while let Some(info) = stream.next().await {
+        let info = info.map_err(|e| e.to_string())?;
+        if let Some(error) = info.error {
+            return Err(error);
+        }
+        let (Some(layer), Some(status)) = (info.id, info.status) else {
+            continue;
+        };
+        let detail = info.progress_detail.unwrap_or_default();
+        let (current, total) = (
+            u64::try_from(detail.current.unwrap_or_default()).unwrap_or_default(),
+            u64::try_from(detail.total.unwrap_or_default()).unwrap_or_default(),
+        );
+        // Extraction reports progress too, which must not count as downloaded
+        match status.as_str() {
+            "Downloading" if total > 0 => {
+                layers.insert(layer.clone(), (current, total));
+            }
+            "Download complete" => {
+                if let Some((downloaded, total)) = layers.get_mut(&layer) {
+                    *downloaded = *total;
+                }
+            }
+            _ => continue,
+        }
+        blueprint_sdk::trace!(?image, layer, status, "Image pull progress");
+        state.send_modify(|state| {
+            state.progress.downloaded_bytes = layers.values().map(|(current, _)| current).sum();
+            state.progress.total_bytes = layers.values().map(|(_, total)| total).sum();
+        });
+    }
+    Ok(())
+}
@@ -1,9 +1,21 @@
-use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha2::{Digest, Sha512};
 use tokio::process::Command;
-use tokio_util::sync::CancellationToken;
 
 use crate::error::Error;
-use crate::manager::ServerRunner;
+use crate::manager::binary::{Artifact, BinaryRunner};
+use crate::manager::cgroup;
+use crate::manager::supervisor::{self, ProcessSpec};
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
+
+/// The tarball of a pinned package, in the working directory
+const TARBALL_FILE: &str = ".server-blueprint-package.tgz";
+/// The directory a pinned package is installed to, in the working directory
+const INSTALL_DIR: &str = ".server-blueprint-package";
 
 /// JavaScript runner
 ///
@@ -11,16 +23,169 @@ use crate::manager::ServerRunner;
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct JsRunner;
 
+impl JsRunner {
+    /// The command that runs the package with `bunx`, or the installed `entry` of a
+    /// pinned package with `bun`
+    fn process_spec(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+        entry: Option<&Path>,
+    ) -> ProcessSpec {
+        let (program, args) = match entry {
+            Some(entry) => ("bun", vec![entry.to_string_lossy().into_owned()]),
+            None => (
+                "bunx",
+                vec!["-y".to_string(), server.package.clone(), "--".to_string()],
+            ),
+        };
+        ProcessSpec {
+            program: program.to_string(),
+            args: args
+                .into_iter()
+                .chain(server.args.iter().cloned())
+                .collect(),
+            env: server.env_vars.clone(),
+            clear_env: false,
+            work_dir: ctx.work_dir(id),
+            grace_period: ctx.config.stop.grace_period(),
+            cgroup: cgroup::needed(&server.resources)
+                .then(|| cgroup::path(&ctx.config.limits.cgroup_root, id)),
+            limits: server.resources.clone(),
+        }
+    }
+
+    /// The exact version the package is pinned to, e.g. `1.2.3` for `@scope/pkg@1.2.3`
+    pub fn pinned_version(package: &str) -> Option<String> {
+        // Skip the leading `@` of scoped packages
+        let (_, version) = package.get(1..)?.rsplit_once('@')?;
+        super::exact_version(version)
+    }
+
+    /// The name of the package, e.g. `@scope/pkg` for `@scope/pkg@1.2.3`
+    fn name(package: &str) -> &str {
+        match package.get(1..).and_then(|rest| rest.rsplit_once('@')) {
+            Some((name, _)) => &package[..name.len() + 1],
+            None => package,
+        }
+    }
+
+    /// Download the tarball of the package, check it against its digest and install it
+    ///
+    /// The registry only names the tarball, its integrity is computed from the
+    /// downloaded file, and that file is what is installed. Returns the entry of the
+    /// package to run.
+    async fn install_pinned(
+        ctx: &crate::MyContext,
+        work_dir: &Path,
+        package: &str,
+        digest: &str,
+    ) -> Result<PathBuf, Error> {
+        let output = Command::new("bun")
+            .args(["pm", "view", package, "dist.tarball"])
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Failed to look up package {package}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let url = stdout.trim().trim_matches(['"', '\'']);
+        let Some(Artifact::Url(url)) = Artifact::parse(url) else {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "The registry names no tarball for package {package}"
+            ))));
+        };
+
+        tokio::fs::create_dir_all(work_dir).await?;
+        let tarball = work_dir.join(TARBALL_FILE);
+        BinaryRunner
+            .download(ctx, &Artifact::Url(url), &tarball)
+            .await?;
+        Self::verify(&tarball, package, digest).await?;
+
+        let dir = work_dir.join(INSTALL_DIR);
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await?;
+        let manifest = serde_json::json!({
+            "private": true,
+            "dependencies": {
+                Self::name(package): format!("file:{}", tarball.display()),
+            },
+        });
+        tokio::fs::write(dir.join("package.json"), serde_json::to_vec(&manifest)?).await?;
+        let output = Command::new("bun")
+            .args(["install", "--production"])
+            .current_dir(&dir)
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Failed to install package {package}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))));
+        }
+        Self::entry(work_dir, package).await
+    }
+
+    /// Check the npm integrity of a downloaded tarball against its digest
+    async fn verify(path: &Path, package: &str, digest: &str) -> Result<(), Error> {
+        let path = path.to_path_buf();
+        let actual = tokio::task::spawn_blocking(move || {
+            let mut hasher = Sha512::new();
+            std::io::copy(&mut File::open(path)?, &mut hasher)?;
+            Ok::<_, std::io::Error>(format!("sha512-{}", BASE64.encode(hasher.finalize())))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        if actual != digest {
+            return Err(Error::IntegrityMismatch {
+                subject: format!("package {package}"),
+                expected: digest.to_string(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// The executable of an installed pinned package, like `bunx` would pick it
+    ///
+    /// That is the only one the package declares, or the one named after the package.
+    async fn entry(work_dir: &Path, package: &str) -> Result<PathBuf, Error> {
+        let name = Self::name(package);
+        let installed = work_dir.join(INSTALL_DIR).join("node_modules");
+        let manifest = tokio::fs::read(installed.join(name).join("package.json")).await?;
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest)?;
+        let unscoped = name.rsplit('/').next().unwrap_or(name);
+        let bin = match manifest.get("bin") {
+            Some(serde_json::Value::String(_)) => Some(unscoped.to_string()),
+            Some(serde_json::Value::Object(bins)) if bins.len() == 1 => bins.keys().next().cloned(),
+            Some(serde_json::Value::Object(bins)) => {
+                bins.contains_key(unscoped).then(|| unscoped.to_string())
+            }
+            _ => None,
+        };
+        let bin = bin.ok_or_else(|| {
+            Error::InvalidConfig(format!("package {package} has no executable to run"))
+        })?;
+        Ok(installed.join(".bin").join(bin))
+    }
+}
+
 impl ServerRunner for JsRunner {
-    #[tracing::instrument(skip(self, ctx), fields(%package, args, runtime = "js"))]
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "js")
+    )]
     async fn start(
         &self,
         ctx: &crate::MyContext,
-        service_id: u64,
-        package: String,
-        args: Vec<String>,
-        env_vars: BTreeMap<String, String>,
-    ) -> Result<CancellationToken, Error> {
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
         // Ensure bun is installed
         let mut checked = self.check(ctx).await;
         blueprint_sdk::debug!(?checked, "Checking if bun is installed");
@@ -37,40 +202,52 @@ impl ServerRunner for JsRunner {
             }
         }
 
-        blueprint_sdk::debug!("Starting JavaScript server with bunx");
-
-        // Start the JavaScript process directly
-        let mut cmd = Command::new("bunx");
-        cmd.arg("-y")
-            .arg(&package)
-            .arg("--")
-            .args(&args)
-            .envs(&env_vars)
-            .kill_on_drop(true);
-
-        let child = cmd.spawn().map_err(Error::Io)?;
-        
-        // Create cancellation token for cleanup
-        let ct = CancellationToken::new();
-        let cleanup_ct = ct.clone();
-        let child_id = child.id();
-
-        // Spawn cleanup task
-        tokio::spawn(async move {
-            cleanup_ct.cancelled().await;
-            if let Some(pid) = child_id {
-                blueprint_sdk::debug!(?pid, "Terminating JavaScript process");
-                #[cfg(unix)]
-                {
-                    let _ = tokio::process::Command::new("kill")
-                        .arg(pid.to_string())
-                        .status()
-                        .await;
-                }
+        let entry = match &server.digest {
+            Some(digest) => {
+                Some(Self::install_pinned(ctx, &ctx.work_dir(id), &server.package, digest).await?)
             }
-        });
+            None => None,
+        };
+
+        blueprint_sdk::debug!("Starting JavaScript server with bun");
 
-        Ok(ct)
+        let version = Self::pinned_version(&server.package);
+        let buffer = ctx.logs.buffer(id).await;
+        // Let go of the port right before the server binds it
+        ctx.ports.release(id);
+        let mut handle = supervisor::spawn(
+            Self::process_spec(ctx, id, server, entry.as_deref()),
+            id.clone(),
+            "JavaScript",
+            buffer,
+        )
+        .await?;
+        handle.version = version;
+        Ok(handle)
+    }
+
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        let Some(pid) = supervisor::adoptable(&server.status.get()).await else {
+            return Ok(None);
+        };
+        // A restart runs the package that was installed and checked at the start
+        let entry = match &server.digest {
+            Some(_) => Some(Self::entry(&ctx.work_dir(id), &server.package).await?),
+            None => None,
+        };
+        Ok(Some(supervisor::adopt(
+            pid,
+            Self::process_spec(ctx, id, server, entry.as_deref()),
+            id.clone(),
+            "JavaScript",
+            server.status.clone(),
+            ctx.logs.buffer(id).await,
+        )))
     }
 
     async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
@@ -0,0 +1,345 @@
+//! Server log capture
+//!
+//! Every server gets a bounded in-memory buffer of its most recent output lines,
+//! backed by a size-rotated log file so the history survives operator restarts.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::manager::ServerId;
+
+/// The maximum length of a single captured line, longer lines are truncated
+const MAX_LINE_LEN: usize = 16 * 1024;
+/// How many lines are kept in memory per server
+const BUFFER_LINES: usize = 10_000;
+/// The size at which the log file is rotated
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+/// How many rotated log files are kept next to the current one
+const ROTATED_FILES: usize = 3;
+/// The name of the current log file inside a server's log directory
+const LOG_FILE: &str = "server.log";
+
+/// The output stream a line was written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+impl LogStream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+/// A single captured output line
+#[derive(Clone, Debug)]
+pub struct LogLine {
+    /// When the line was captured, in milliseconds since the UNIX epoch
+    pub timestamp_ms: u64,
+    /// The stream the line was written to
+    pub stream: LogStream,
+    /// The line itself, without the trailing newline
+    pub line: String,
+}
+
+impl LogLine {
+    /// Parse a line of the log file, as written by its [`Display`](std::fmt::Display) impl
+    fn parse(record: &str) -> Option<Self> {
+        let (timestamp_ms, rest) = record.split_once(' ')?;
+        let (stream, line) = rest.split_once(' ').unwrap_or((rest, ""));
+        let stream = match stream {
+            "stdout" => LogStream::Stdout,
+            "stderr" => LogStream::Stderr,
+            _ => return None,
+        };
+        Some(Self {
+            timestamp_ms: timestamp_ms.parse().ok()?,
+            stream,
+            line: line.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.timestamp_ms,
+            self.stream.as_str(),
+            self.line
+        )
+    }
+}
+
+/// The log buffers of all servers
+#[derive(Debug, Clone)]
+pub struct LogStore {
+    dir: PathBuf,
+    buffers: Arc<tokio::sync::Mutex<HashMap<ServerId, LogBuffer>>>,
+}
+
+impl LogStore {
+    /// Create a log store that keeps the log files under `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            buffers: Arc::default(),
+        }
+    }
+
+    /// Get the log buffer of a server, opening it if needed
+    ///
+    /// A newly opened buffer is seeded with the tail of the existing log file, so
+    /// the history survives operator restarts.
+    pub async fn buffer(&self, id: &ServerId) -> LogBuffer {
+        let mut buffers = self.buffers.lock().await;
+        if let Some(buffer) = buffers.get(id) {
+            return buffer.clone();
+        }
+        let buffer = LogBuffer::open(self.dir.join(id.dir_name())).await;
+        buffers.insert(id.clone(), buffer.clone());
+        buffer
+    }
+
+    /// Get the log buffer of a server, if it was opened
+    pub async fn get(&self, id: &ServerId) -> Option<LogBuffer> {
+        self.buffers.lock().await.get(id).cloned()
+    }
+
+    /// Close the log buffer of a removed server and delete its log files
+    ///
+    /// The writer stops once the last process holding the buffer is gone.
+    pub async fn remove(&self, id: &ServerId) {
+        self.buffers.lock().await.remove(id);
+        let dir = self.dir.join(id.dir_name());
+        match tokio::fs::remove_dir_all(&dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                blueprint_sdk::warn!(server = %id, ?e, "Failed to remove the log files");
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A bounded buffer of the most recent output lines of a server
+#[derive(Debug, Clone)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<LogLine>>>,
+    writer: mpsc::UnboundedSender<LogLine>,
+}
+
+impl LogBuffer {
+    async fn open(dir: PathBuf) -> Self {
+        let mut lines = VecDeque::with_capacity(BUFFER_LINES);
+        if let Ok(existing) = tokio::fs::read_to_string(dir.join(LOG_FILE)).await {
+            let previous: Vec<&str> = existing.lines().collect();
+            let start = previous.len().saturating_sub(BUFFER_LINES);
+            lines.extend(
+                previous[start..]
+                    .iter()
+                    .filter_map(|record| LogLine::parse(record)),
+            );
+        }
+
+        let lines = Arc::new(Mutex::new(lines));
+        let (writer, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_log_file(dir, rx));
+        Self { lines, writer }
+    }
+
+    fn lines(&self) -> std::sync::MutexGuard<'_, VecDeque<LogLine>> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Capture a line of output
+    pub fn push(&self, stream: LogStream, line: &str) {
+        let mut line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.len() > MAX_LINE_LEN {
+            let mut end = MAX_LINE_LEN;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let line = LogLine {
+            timestamp_ms,
+            stream,
+            line,
+        };
+
+        {
+            let mut lines = self.lines();
+            if lines.len() == BUFFER_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line.clone());
+        }
+        let _ = self.writer.send(line);
+    }
+
+    /// The capture time of the most recent line, if any
+    pub fn last_timestamp_ms(&self) -> Option<u64> {
+        self.lines().back().map(|line| line.timestamp_ms)
+    }
+
+    /// Get the last `tail` lines captured between `since_ms` and `until_ms`
+    ///
+    /// A `tail` of zero returns every line in the range.
+    pub fn query(&self, tail: usize, since_ms: Option<u64>, until_ms: Option<u64>) -> Vec<LogLine> {
+        let lines = self.lines();
+        let mut matching: Vec<LogLine> = lines
+            .iter()
+            .rev()
+            .filter(|line| until_ms.is_none_or(|until| line.timestamp_ms <= until))
+            .take_while(|line| since_ms.is_none_or(|since| line.timestamp_ms >= since))
+            .take(if tail == 0 { usize::MAX } else { tail })
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+/// Append the captured lines to the log file, rotating it when it grows too large
+async fn write_log_file(dir: PathBuf, mut rx: mpsc::UnboundedReceiver<LogLine>) {
+    let path = dir.join(LOG_FILE);
+    if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+        blueprint_sdk::warn!(?e, dir = %dir.display(), "Failed to create log directory");
+        return;
+    }
+
+    let mut file = None;
+    let mut size = 0;
+    while let Some(line) = rx.recv().await {
+        if file.is_none() || size >= MAX_FILE_SIZE {
+            if file.take().is_some() {
+                rotate(&dir).await;
+            }
+            match tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+            {
+                Ok(f) => {
+                    size = f.metadata().await.map(|m| m.len()).unwrap_or_default();
+                    file = Some(f);
+                }
+                Err(e) => {
+                    blueprint_sdk::warn!(?e, path = %path.display(), "Failed to open log file");
+                    continue;
+                }
+            }
+        }
+
+        let record = format!("{line}\n");
+        if let Some(f) = file.as_mut() {
+            if let Err(e) = f.write_all(record.as_bytes()).await {
+                blueprint_sdk::warn!(?e, path = %path.display(), "Failed to write log file");
+                file = None;
+                continue;
+            }
+            size += record.len() as u64;
+        }
+    }
+}
+
+/// Shift `server.log.N` to `server.log.N+1`, dropping the oldest one
+async fn rotate(dir: &Path) {
+    let rotated = |n: usize| dir.join(format!("{LOG_FILE}.{n}"));
+    let _ = tokio::fs::remove_file(rotated(ROTATED_FILES)).await;
+    for n in (1..ROTATED_FILES).rev() {
+        let _ = tokio::fs::rename(rotated(n), rotated(n + 1)).await;
+    }
+    let _ = tokio::fs::rename(dir.join(LOG_FILE), rotated(1)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer with a line at each of the timestamps, and no log file behind it
+    fn buffer(timestamps: &[u64]) -> LogBuffer {
+        let lines = timestamps
+            .iter()
+            .map(|&timestamp_ms| LogLine {
+                timestamp_ms,
+                stream: LogStream::Stdout,
+                line: timestamp_ms.to_string(),
+            })
+            .collect();
+        let (writer, _) = mpsc::unbounded_channel();
+        LogBuffer {
+            lines: Arc::new(Mutex::new(lines)),
+            writer,
+        }
+    }
+
+    fn timestamps(lines: Vec<LogLine>) -> Vec<u64> {
+        lines.iter().map(|line| line.timestamp_ms).collect()
+    }
+
+    #[test]
+    fn query_returns_the_last_lines_in_order() {
+        let buffer = buffer(&[10, 20, 30, 40]);
+        assert_eq!(timestamps(buffer.query(0, None, None)), [10, 20, 30, 40]);
+        assert_eq!(timestamps(buffer.query(2, None, None)), [30, 40]);
+        assert_eq!(timestamps(buffer.query(10, None, None)), [10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn query_bounds_are_inclusive() {
+        let buffer = buffer(&[10, 20, 30, 40]);
+        assert_eq!(timestamps(buffer.query(0, Some(20), None)), [20, 30, 40]);
+        assert_eq!(timestamps(buffer.query(0, None, Some(30))), [10, 20, 30]);
+        assert_eq!(timestamps(buffer.query(0, Some(20), Some(30))), [20, 30]);
+        assert!(buffer.query(0, Some(50), None).is_empty());
+    }
+
+    #[test]
+    fn query_tails_the_range() {
+        let buffer = buffer(&[10, 20, 30, 40]);
+        assert_eq!(timestamps(buffer.query(1, Some(10), Some(30))), [30]);
+    }
+
+    #[test]
+    fn pushed_lines_are_trimmed_and_truncated() {
+        let buffer = buffer(&[]);
+        buffer.push(LogStream::Stderr, "hello\r\n");
+        buffer.push(LogStream::Stdout, &"é".repeat(MAX_LINE_LEN));
+        let lines = buffer.query(0, None, None);
+        assert_eq!(lines[0].line, "hello");
+        assert_eq!(lines[0].stream, LogStream::Stderr);
+        assert_eq!(lines[1].line.len(), MAX_LINE_LEN);
+        assert_eq!(buffer.last_timestamp_ms(), Some(lines[1].timestamp_ms));
+    }
+
+    #[test]
+    fn log_lines_round_trip_through_the_file_format() {
+        let line = LogLine {
+            timestamp_ms: 42,
+            stream: LogStream::Stderr,
+            line: "a b  c".to_string(),
+        };
+        let parsed = LogLine::parse(&line.to_string()).unwrap();
+        assert_eq!(parsed.timestamp_ms, 42);
+        assert_eq!(parsed.stream, LogStream::Stderr);
+        assert_eq!(parsed.line, "a b  c");
+        assert!(LogLine::parse("42 stdin x").is_none());
+        assert!(LogLine::parse("now stdout x").is_none());
+    }
+}
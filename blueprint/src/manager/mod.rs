@@ -7,33 +7,197 @@
 //! 1. Python (using uvx)
 //! 2. Javascript (using bunx)
 //! 3. Docker (using docker)
+//! 4. Native binaries (fetched by URL)
+//! 5. WebAssembly components (served in a sandbox, with the `wasm` feature)
 //!
 //! The servers can be run in the background and the endpoint will be returned
 //! to the caller.
+//!
+//! A service can run several servers, each in its own named slot with its own
+//! configuration and port. The server started from the service request runs in
+//! the [`DEFAULT_SLOT`].
+//!
+//! The manager state is persisted through a [`state::StateStore`] so that running
+//! servers can be re-adopted after the operator restarts.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
+use blueprint_sdk::tangle::extract::{List, Optional};
 use blueprint_sdk::tangle_subxt::subxt::utils::AccountId32;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
-use crate::error::Error;
 use crate::ServerRuntime;
+use crate::error::Error;
+use crate::manager::status::{ServerState, SharedStatus};
 
+/// Backups of the server data
+pub mod backups;
+/// Fetches native binaries and runs them
+pub mod binary;
+/// Enforces the resource limits of the process runtimes
+pub mod cgroup;
+/// Runs the command runtimes of the operator config
+pub mod command;
+/// Runs Docker Compose stacks
+pub mod compose;
 /// TBD
 pub mod docker;
+/// Builds Docker images from Dockerfiles and runs them
+pub mod dockerfile;
+/// Keeps the status of the Docker containers in sync
+pub mod events;
+/// Routes the requests of a service to the servers in its slots
+pub mod gateway;
+/// Pulls the Docker images of the servers
+pub mod images;
 /// Uses bunx to run the server
 pub mod js;
+/// Captures the output of the servers
+pub mod logs;
+/// The operator policy for the servers
+pub mod policy;
+/// Allocates the host ports of the servers
+pub mod ports;
 /// Uses uvx to run the server
 pub mod python;
+/// Readiness probes for starting servers
+pub mod readiness;
+/// Credentials for private Docker registries
+pub mod registry;
+/// The runtimes the servers can run on, by name
+pub mod runtimes;
+/// On-disk persistence of the manager state
+pub mod state;
+/// Runtime status of the servers
+pub mod status;
+/// Supervises the processes of the process runtimes
+pub mod supervisor;
+/// Authenticated TCP tunnels to the servers
+pub mod tunnel;
+/// Named Docker volumes of the servers
+pub mod volumes;
+/// Serves WebAssembly components in a sandbox
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// The slot of the server started from the service request
+pub const DEFAULT_SLOT: &str = "default";
+/// The slot the gateway port of a service is leased to, not a valid slot name
+const GATEWAY_SLOT: &str = "@gateway";
+
+/// Whether `name` is a valid slot or port name
+///
+/// Names are up to 32 lowercase ASCII letters, digits and `_`, so they can be used
+/// as a path segment as they are.
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Identifies a server by its service and its slot within that service
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServerId {
+    /// The service the server belongs to
+    pub service_id: u64,
+    /// The slot of the server within its service
+    pub slot: String,
+}
+
+impl ServerId {
+    /// The server in the given slot of a service
+    pub fn new(service_id: u64, slot: impl Into<String>) -> Self {
+        Self {
+            service_id,
+            slot: slot.into(),
+        }
+    }
+
+    /// The server in the given slot, or in the default slot if none is given
+    pub fn from_slot(service_id: u64, slot: Option<String>) -> Result<Self, Error> {
+        let slot = slot.unwrap_or_else(|| DEFAULT_SLOT.to_string());
+        if !is_valid_name(&slot) {
+            return Err(Error::InvalidConfig(format!("invalid slot name `{slot}`")));
+        }
+        Ok(Self::new(service_id, slot))
+    }
+
+    /// The id the gateway port of a service is leased to
+    pub fn gateway(service_id: u64) -> Self {
+        Self::new(service_id, GATEWAY_SLOT)
+    }
+
+    /// The name of the server's container, cgroup and volumes
+    ///
+    /// Servers in the default slot keep the names from before slots existed.
+    pub fn name(&self) -> String {
+        if self.slot == DEFAULT_SLOT {
+            format!("server-{}", self.service_id)
+        } else {
+            format!("server-{}.{}", self.service_id, self.slot)
+        }
+    }
+
+    /// The name of the server's log, working and backup directories
+    pub fn dir_name(&self) -> String {
+        if self.slot == DEFAULT_SLOT {
+            self.service_id.to_string()
+        } else {
+            format!("{}.{}", self.service_id, self.slot)
+        }
+    }
+}
+
+impl fmt::Display for ServerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.service_id, self.slot)
+    }
+}
 
 #[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
 pub struct ServerManager {
-    /// Service Id to the Server mapping
-    pub servers: BTreeMap<u64, Server>,
+    /// Service Id to the Servers mapping, by slot
+    pub servers: BTreeMap<u64, BTreeMap<String, Server>>,
     /// Mapping of service id to the owner
     pub owners: BTreeMap<u64, AccountId32>,
-    /// Mapping of service id to the endpoint
+    /// Mapping of service id to the owner's ECDSA public key
+    #[serde(default)]
+    pub ecdsa_owners: BTreeMap<u64, Vec<u8>>,
+    /// Mapping of service id to the endpoint registered with the auth proxy
     pub endpoints: BTreeMap<u64, String>,
+    /// Mapping of service id to the port of its gateway, if it needs one
+    #[serde(default)]
+    pub gateway_ports: BTreeMap<u64, u16>,
+    /// Docker volumes no server uses, with when they were first seen unused
+    #[serde(default)]
+    pub orphaned_volumes: BTreeMap<String, u64>,
+    /// Mapping of service id to the backup schedules of its servers, by slot
+    #[serde(default)]
+    pub backup_schedules: BTreeMap<u64, BTreeMap<String, backups::Schedule>>,
+
+    /// The running gateways, by service id
+    #[serde(skip)]
+    gateways: BTreeMap<u64, gateway::Gateway>,
+    /// The running tunnels, by server
+    #[serde(skip)]
+    tunnels: BTreeMap<ServerId, tunnel::Tunnel>,
+    /// Checks the access tokens of tunnel clients, confirmed by the gateways
+    #[serde(skip)]
+    verifier: tunnel::TokenVerifier,
+    /// Services whose proxy route could not be registered on startup, until it is
+    #[serde(skip)]
+    degraded: BTreeSet<u64>,
+    /// Servers whose data is being replaced by a backup, which nothing else may touch
+    #[serde(skip)]
+    restoring: BTreeSet<ServerId>,
+
+    /// Where the manager state is persisted, if anywhere
+    #[serde(skip)]
+    store: Option<state::StateStore>,
 }
 
 #[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
@@ -48,47 +212,749 @@ pub struct Server {
     /// Environment variables to pass to the server
     #[serde(default)]
     pub env_vars: BTreeMap<String, String>,
+    /// The readiness probe the server was started with, if any
+    #[serde(default)]
+    pub readiness: Option<crate::ReadinessConfig>,
+    /// The resource limits the server runs with
+    #[serde(default)]
+    pub resources: crate::ResourceLimits,
+    /// The named volumes mounted into the server
+    #[serde(default)]
+    pub volumes: Vec<crate::VolumeMount>,
+    /// The names of the container ports
+    #[serde(default)]
+    pub ports: Vec<crate::PortConfig>,
+    /// The container ports published on host ports
+    #[serde(default)]
+    pub published_ports: Vec<PublishedPort>,
+    /// The TCP tunnel to the server, if it has one
+    #[serde(default)]
+    pub tunnel: Option<crate::TunnelConfig>,
+    /// The port the tunnel listens on, once one was leased
+    #[serde(default)]
+    pub tunnel_port: Option<u16>,
+    /// The credentials to pull the image with, still encrypted if the owner sent them
+    #[serde(default)]
+    pub registry_auth: Option<crate::RegistryAuth>,
+    /// The digest the image or package is pinned to, if any
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// What the image is built from, if the server runs on the Dockerfile runtime
+    #[serde(default)]
+    pub build: Option<crate::BuildConfig>,
+    /// The Compose document, if the server runs on the Compose runtime
+    #[serde(default)]
+    pub compose: Option<String>,
+    /// The path of the binary in the artifact, if the server runs on the Binary runtime
+    #[serde(default)]
+    pub binary: Option<String>,
+    /// The Docker container id, if the server runs in a container
+    #[serde(default)]
+    pub container_id: Option<String>,
+    /// The image digest or package version that is actually running, if known
+    #[serde(default)]
+    pub version: Option<String>,
+    /// The runtime status of the server
+    #[serde(default)]
+    pub status: SharedStatus,
 
-    /// The cancellation token for the server
+    /// Stops the server
     #[serde(skip)]
-    pub cancellation_token: Option<CancellationToken>,
+    pub shutdown: Option<ShutdownHandle>,
+}
+
+impl Server {
+    /// The port allocated to the server
+    pub fn port(&self) -> Option<u16> {
+        self.env_vars.get("PORT").and_then(|port| port.parse().ok())
+    }
+
+    /// The further host ports allocated to the server
+    pub fn extra_ports(&self) -> Vec<u16> {
+        self.published_ports
+            .iter()
+            .map(|published| published.host_port)
+            .filter(|port| Some(*port) != self.port())
+            .collect()
+    }
+
+    /// The configuration the server runs with
+    pub fn config(&self) -> crate::ServerConfig {
+        let env = self
+            .env_vars
+            .iter()
+            .filter(|(key, _)| *key != "PORT")
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        crate::ServerConfig {
+            runtime: self.runtime.clone(),
+            package: self.package.clone(),
+            args: Optional(Some(List(self.args.clone()))),
+            env: Optional(Some(List(env))),
+            readiness: Optional(self.readiness.clone()),
+            resources: Optional(Some(self.resources.clone())),
+            volumes: Optional(Some(List(self.volumes.clone()))),
+            ports: Optional(Some(List(self.ports.clone()))),
+            tunnel: Optional(self.tunnel.clone()),
+            registry_auth: Optional(self.registry_auth.clone()),
+            digest: Optional(self.digest.clone()),
+            build: Optional(self.build.clone()),
+            compose: Optional(self.compose.clone()),
+            binary: Optional(self.binary.clone()),
+        }
+    }
+
+    /// The host port the tunnel leads to
+    pub fn tunnel_backend(&self) -> Option<u16> {
+        let tunnel = self.tunnel.as_ref()?;
+        match &tunnel.port.0 {
+            Some(name) => self
+                .published_ports
+                .iter()
+                .find(|published| published.name.as_ref() == Some(name))
+                .map(|published| published.host_port),
+            None => self.port(),
+        }
+    }
+}
+
+/// A container port published on a host port
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PublishedPort {
+    /// The port inside the container
+    pub container_port: u16,
+    /// The protocol of the port
+    pub protocol: crate::PortProtocol,
+    /// The host port it is published on, bound to the loopback interface
+    pub host_port: u16,
+    /// The name of the port, if it was named in the server config
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Whether the port serves HTTP and has its own proxy route
+    #[serde(default)]
+    pub http: bool,
+}
+
+/// The path prefix the proxy routes to a named HTTP port of the server in a slot
+pub fn port_path(slot: &str, name: &str) -> String {
+    format!("/{slot}/{name}")
+}
+
+/// Stops a server by cancelling the task that owns its runtime
+#[derive(Debug)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl ShutdownHandle {
+    /// Spawn the task that owns a server runtime
+    ///
+    /// The task is expected to tear the runtime down once the token is cancelled.
+    pub fn spawn<F, Fut>(f: F) -> Self
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let task = tokio::spawn(f(token.clone()));
+        Self { token, task }
+    }
+
+    /// Stop the server and wait until its runtime is torn down
+    pub async fn shutdown(self) {
+        self.token.cancel();
+        if let Err(e) = self.task.await {
+            blueprint_sdk::error!(?e, "Server runtime task failed");
+        }
+    }
+}
+
+/// A handle to a server started by a [`ServerRunner`]
+#[derive(Debug)]
+pub struct RuntimeHandle {
+    /// Stops the server
+    pub shutdown: ShutdownHandle,
+    /// The Docker container id, if the server runs in a container
+    pub container_id: Option<String>,
+    /// The runtime status of the server
+    pub status: SharedStatus,
+    /// The host port the server is reachable on, if it publishes one
+    pub port: Option<u16>,
+    /// The container ports published on host ports
+    pub published_ports: Vec<PublishedPort>,
+    /// The output lines of the server, if they are captured by the runner
+    pub logs: Option<broadcast::Receiver<String>>,
+    /// The image digest or package version that is actually running, if known
+    pub version: Option<String>,
+}
+
+/// Returns `version` if it is an exact version rather than a range or a tag like `latest`
+fn exact_version(version: &str) -> Option<String> {
+    let exact = version.starts_with(|c: char| c.is_ascii_digit())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'));
+    exact.then(|| version.to_string())
 }
 
+/// A runtime the servers can run on
+///
+/// Runners are implemented with `async fn`. Implement this trait and add the runner
+/// with [`RuntimeRegistry::register`] to make it available to the servers. The
+/// manager only ever calls runners through the registry, as [`DynServerRunner`]s,
+/// where the built-in runtimes are kept as well.
+///
+/// [`RuntimeRegistry::register`]: runtimes::RuntimeRegistry::register
+/// [`DynServerRunner`]: runtimes::DynServerRunner
 pub trait ServerRunner {
     /// Start the server
-    /// Returns (CancellationToken, endpoint)
-    async fn start(
+    ///
+    /// `server` describes what to run. Its runtime state, like the status and the
+    /// container id, is not set yet.
+    fn start(
         &self,
         ctx: &crate::MyContext,
-        service_id: u64,
-        package: String,
-        args: Vec<String>,
-        env_vars: BTreeMap<String, String>,
-    ) -> Result<CancellationToken, Error>;
+        id: &ServerId,
+        server: &Server,
+    ) -> impl Future<Output = Result<RuntimeHandle, Error>> + Send;
+
+    /// Re-adopt a server that was started by a previous run of the operator
+    ///
+    /// Returns `None` if the server runtime no longer exists.
+    fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> impl Future<Output = Result<Option<ShutdownHandle>, Error>> + Send;
 
     /// Check if the runtime is installed and available
-    async fn check(&self, ctx: &crate::MyContext) -> Result<bool, Error>;
+    fn check(&self, ctx: &crate::MyContext) -> impl Future<Output = Result<bool, Error>> + Send;
 
     /// Install the runtime if not present
-    async fn install(&self, ctx: &crate::MyContext) -> Result<(), Error>;
+    fn install(&self, ctx: &crate::MyContext) -> impl Future<Output = Result<(), Error>> + Send;
 }
 
 impl ServerManager {
-    #[tracing::instrument(skip(self, ctx, config), fields(service_id, %owner))]
-    pub async fn start_server(
+    /// Load the manager from the given store, or start empty if nothing was persisted
+    pub async fn load(store: state::StateStore) -> Result<Self, Error> {
+        let mut manager = store.load().await?.unwrap_or_default();
+        blueprint_sdk::debug!(
+            servers = manager.server_ids().len(),
+            dir = %store.dir().display(),
+            "Loaded server manager state"
+        );
+        manager.store = Some(store);
+        Ok(manager)
+    }
+
+    /// Persist the manager state, if a store is configured
+    async fn persist(&self) -> Result<(), Error> {
+        match &self.store {
+            Some(store) => store.save(self).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Ensure that `caller` owns the servers of the given service
+    pub fn check_owner(&self, service_id: u64, caller: &AccountId32) -> Result<(), Error> {
+        if self.owners.get(&service_id) == Some(caller) {
+            Ok(())
+        } else {
+            Err(Error::NotOwner(service_id))
+        }
+    }
+
+    /// The server with the given id
+    pub fn server(&self, id: &ServerId) -> Result<&Server, Error> {
+        self.servers
+            .get(&id.service_id)
+            .and_then(|slots| slots.get(&id.slot))
+            .ok_or_else(|| Error::ServerNotFound(id.clone()))
+    }
+
+    /// Whether the proxy route of a service could not be registered, so its servers
+    /// may be unreachable
+    pub fn is_degraded(&self, service_id: u64) -> bool {
+        self.degraded.contains(&service_id)
+    }
+
+    /// Fail if the server is being restored
+    fn check_not_restoring(&self, id: &ServerId) -> Result<(), Error> {
+        if self.restoring.contains(id) {
+            return Err(Error::Restoring(id.clone()));
+        }
+        Ok(())
+    }
+
+    /// The ids of all servers
+    pub fn server_ids(&self) -> Vec<ServerId> {
+        self.servers
+            .iter()
+            .flat_map(|(service_id, slots)| {
+                slots.keys().map(|slot| ServerId::new(*service_id, slot))
+            })
+            .collect()
+    }
+
+    /// The slots of the servers of a service
+    pub fn slots(&self, service_id: u64) -> Vec<String> {
+        self.servers
+            .get(&service_id)
+            .map(|slots| slots.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn server_mut(&mut self, id: &ServerId) -> Option<&mut Server> {
+        self.servers.get_mut(&id.service_id)?.get_mut(&id.slot)
+    }
+
+    fn insert_server(&mut self, id: &ServerId, server: Server) {
+        self.servers
+            .entry(id.service_id)
+            .or_default()
+            .insert(id.slot.clone(), server);
+    }
+
+    fn take_server(&mut self, id: &ServerId) -> Option<Server> {
+        self.servers.get_mut(&id.service_id)?.remove(&id.slot)
+    }
+
+    /// Reconcile the persisted servers with what is actually running
+    ///
+    /// Live containers and processes are re-adopted and their auth proxy routes are
+    /// registered again. Servers whose runtime disappeared are marked as failed, and
+    /// services whose route cannot be registered are marked as degraded.
+    #[tracing::instrument(skip_all)]
+    pub async fn reconcile(&mut self, ctx: &crate::MyContext) -> Result<(), Error> {
+        // Every server and gateway keeps its port, even if it is not running anymore
+        for id in self.server_ids() {
+            let server = self.server(&id)?;
+            if let Some(port) = server.port() {
+                ctx.ports.restore(&id, port);
+            }
+            ctx.ports.restore_extra(&id, server.extra_ports());
+            if let Some(port) = server.tunnel_port {
+                ctx.tunnel_ports.restore(&id, port);
+            }
+        }
+        for (service_id, port) in &self.gateway_ports {
+            ctx.ports.restore(&ServerId::gateway(*service_id), *port);
+        }
+
+        let ids: Vec<ServerId> = self
+            .server_ids()
+            .into_iter()
+            .filter(|id| {
+                self.server(id).is_ok_and(|server| {
+                    matches!(
+                        server.status.get().state,
+                        ServerState::Running | ServerState::Restarting
+                    )
+                })
+            })
+            .collect();
+
+        for id in ids {
+            let server = self.server(&id)?;
+            // Servers of runtimes that are not registered anymore cannot be adopted
+            let adopted = match ctx.runtimes.get(&server.runtime) {
+                Ok(runner) => runner.adopt(ctx, &id, server).await,
+                Err(_) => Ok(None),
+            };
+
+            let shutdown = match adopted {
+                Ok(Some(shutdown)) => shutdown,
+                Ok(None) => {
+                    blueprint_sdk::warn!(server = %id, "Server is gone, marking as failed");
+                    self.mark_failed(&id);
+                    continue;
+                }
+                Err(e) => {
+                    blueprint_sdk::warn!(server = %id, ?e, "Failed to adopt server, marking as failed");
+                    self.mark_failed(&id);
+                    continue;
+                }
+            };
+
+            if let Some(server) = self.server_mut(&id) {
+                server.shutdown = Some(shutdown);
+            }
+            blueprint_sdk::info!(server = %id, "Re-adopted server");
+        }
+
+        let service_ids: BTreeSet<u64> = self
+            .servers
+            .keys()
+            .chain(self.gateway_ports.keys())
+            .copied()
+            .collect();
+        for service_id in service_ids {
+            // One service must not keep the operator from serving all others
+            if let Err(e) = self.sync_proxy(ctx, service_id).await {
+                blueprint_sdk::error!(
+                    %service_id,
+                    ?e,
+                    "Failed to register the proxy route, marking the service as degraded"
+                );
+                self.degraded.insert(service_id);
+            }
+        }
+
+        self.persist().await
+    }
+
+    /// Mark a server as failed, which drops it from the proxy routes on the next sync
+    fn mark_failed(&mut self, id: &ServerId) {
+        if let Some(server) = self.server_mut(id) {
+            server.status.update(|s| s.state = ServerState::Failed);
+            server.shutdown = None;
+        }
+    }
+
+    /// Point the auth proxy route and the tunnels of a service at its live servers
+    ///
+    /// Every live server is routed at `/{slot}`, and its named HTTP ports at
+    /// `/{slot}/{name}`. The route always goes through a [`gateway::Gateway`], which
+    /// picks the server by the longest matching path prefix. The gateway answers the
+    /// token checks of the tunnels itself, so their nonces never reach a server.
+    async fn sync_proxy(&mut self, ctx: &crate::MyContext, service_id: u64) -> Result<(), Error> {
+        self.sync_tunnels(ctx, service_id).await;
+        let mut routes = BTreeMap::new();
+        let live = self
+            .servers
+            .get(&service_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, server)| server.status.get().state != ServerState::Failed);
+        for (slot, server) in live {
+            if let Some(port) = server.port() {
+                routes.insert(format!("/{slot}"), port);
+                if slot == DEFAULT_SLOT {
+                    routes.insert(String::new(), port);
+                }
+            }
+            for published in server.published_ports.iter().filter(|port| port.http) {
+                if let Some(name) = &published.name {
+                    routes.insert(port_path(slot, name), published.host_port);
+                }
+            }
+        }
+
+        let port = if routes.is_empty() {
+            None
+        } else {
+            Some(self.start_gateway(ctx, service_id, routes).await?)
+        };
+
+        match (port, self.owners.get(&service_id)) {
+            (Some(port), Some(owner)) => {
+                let endpoint = format!("http://127.0.0.1:{port}");
+                let ecdsa_owner = self
+                    .ecdsa_owners
+                    .get(&service_id)
+                    .cloned()
+                    .unwrap_or_default();
+                ctx.register_server_proxy(service_id, &endpoint, owner, &ecdsa_owner)
+                    .await?;
+                blueprint_sdk::debug!(%service_id, %endpoint, "Registered proxy route");
+                self.endpoints.insert(service_id, endpoint);
+            }
+            _ => {
+                self.stop_gateway(ctx, service_id).await;
+                if self.endpoints.remove(&service_id).is_some() {
+                    let bridge = ctx.env.bridge().await?;
+                    bridge
+                        .unregister_blueprint_service_proxy(service_id)
+                        .await?;
+                    blueprint_sdk::debug!(%service_id, "Unregistered proxy route");
+                }
+            }
+        }
+        self.degraded.remove(&service_id);
+        Ok(())
+    }
+
+    /// Route the requests of a service through its gateway, starting it if needed
+    ///
+    /// Returns the port of the gateway.
+    async fn start_gateway(
+        &mut self,
+        ctx: &crate::MyContext,
+        service_id: u64,
+        routes: BTreeMap<String, u16>,
+    ) -> Result<u16, Error> {
+        if let Some(gateway) = self.gateways.get(&service_id) {
+            gateway.set_routes(routes);
+            return Ok(gateway.port());
+        }
+        let id = ServerId::gateway(service_id);
+        let port = ctx.ports.reserve(&id).await?;
+        ctx.ports.release(&id);
+        let verifier = self.verifier.clone();
+        let gateway = match gateway::Gateway::start(port, service_id, routes, verifier).await {
+            Ok(gateway) => gateway,
+            Err(e) => {
+                ctx.ports.free(&id);
+                self.gateway_ports.remove(&service_id);
+                return Err(e);
+            }
+        };
+        self.gateway_ports.insert(service_id, port);
+        self.gateways.insert(service_id, gateway);
+        Ok(port)
+    }
+
+    /// Stop the gateway of a service, if it has one
+    async fn stop_gateway(&mut self, ctx: &crate::MyContext, service_id: u64) {
+        if let Some(gateway) = self.gateways.remove(&service_id) {
+            gateway.shutdown().await;
+        }
+        if self.gateway_ports.remove(&service_id).is_some() {
+            ctx.ports.free(&ServerId::gateway(service_id));
+        }
+    }
+
+    /// Start, move or stop the tunnels of a service to match its live servers
+    ///
+    /// A tunnel keeps its port for as long as its server has one configured, even
+    /// while the server is down.
+    async fn sync_tunnels(&mut self, ctx: &crate::MyContext, service_id: u64) {
+        let mut wanted = BTreeMap::new();
+        let mut unconfigured = Vec::new();
+        for (slot, server) in self.servers.get(&service_id).into_iter().flatten() {
+            let id = ServerId::new(service_id, slot.clone());
+            if server.tunnel.is_none() {
+                unconfigured.push(id);
+            } else if server.status.get().state != ServerState::Failed {
+                if let Some(backend) = server.tunnel_backend() {
+                    wanted.insert(id, backend);
+                }
+            }
+        }
+
+        let running: Vec<ServerId> = self
+            .tunnels
+            .keys()
+            .filter(|id| id.service_id == service_id)
+            .cloned()
+            .collect();
+        for id in running {
+            let current = self.tunnels.get(&id).map(tunnel::Tunnel::backend_port);
+            if current != wanted.get(&id).copied() {
+                if let Some(tunnel) = self.tunnels.remove(&id) {
+                    tunnel.shutdown().await;
+                    blueprint_sdk::debug!(server = %id, "Stopped tunnel");
+                }
+            }
+        }
+        // A new configuration without a tunnel gives up the port of the previous one
+        for id in unconfigured {
+            ctx.tunnel_ports.free(&id);
+        }
+
+        for (id, backend) in wanted {
+            if self.tunnels.contains_key(&id) {
+                continue;
+            }
+            let port = match ctx.tunnel_ports.reserve(&id).await {
+                Ok(port) => port,
+                Err(e) => {
+                    blueprint_sdk::error!(server = %id, ?e, "Failed to lease a tunnel port");
+                    continue;
+                }
+            };
+            ctx.tunnel_ports.release(&id);
+            if let Some(server) = self.server_mut(&id) {
+                server.tunnel_port = Some(port);
+            }
+            let address = ctx.config.tunnels.bind_address;
+            let verifier = self.verifier.clone();
+            match tunnel::Tunnel::start(address, port, service_id, backend, verifier).await {
+                Ok(tunnel) => {
+                    self.tunnels.insert(id, tunnel);
+                }
+                Err(e) => blueprint_sdk::error!(server = %id, ?e, "Failed to start tunnel"),
+            }
+        }
+    }
+
+    /// Start the servers of a service, each in its own slot
+    ///
+    /// The slots must not be taken yet. If any server fails to start, the servers
+    /// started before it are stopped again.
+    #[tracing::instrument(skip(self, ctx, ecdsa_owner, configs), fields(service_id, %owner))]
+    pub async fn start_servers(
         &mut self,
         ctx: &crate::MyContext,
         service_id: u64,
         owner: AccountId32,
+        ecdsa_owner: Vec<u8>,
+        configs: Vec<(Option<String>, crate::ServerConfig)>,
+    ) -> Result<(), Error> {
+        let mut ids: Vec<ServerId> = Vec::with_capacity(configs.len());
+        for (slot, config) in &configs {
+            let id = ServerId::from_slot(service_id, slot.clone())?;
+            ctx.runtimes.get(&config.runtime)?;
+            config.validate(&ctx.config)?;
+            ctx.policy.check(config)?;
+            if ids.contains(&id) {
+                return Err(Error::InvalidConfig(format!(
+                    "slot `{}` is configured twice",
+                    id.slot
+                )));
+            }
+            if self.server(&id).is_ok() {
+                return Err(Error::ServerExists(id));
+            }
+            ids.push(id);
+        }
+
+        self.owners.insert(service_id, owner);
+        self.ecdsa_owners.insert(service_id, ecdsa_owner);
+        let mut started: Vec<ServerId> = Vec::with_capacity(ids.len());
+        for (id, (_, config)) in ids.into_iter().zip(configs) {
+            if let Err(e) = self.start_server(ctx, &id, config).await {
+                blueprint_sdk::warn!(server = %id, ?e, "Server failed to start, stopping the others");
+                for id in started.iter().rev() {
+                    self.remove_server(ctx, id).await;
+                }
+                self.forget_if_empty(service_id);
+                self.sync_proxy(ctx, service_id).await?;
+                self.persist().await?;
+                return Err(e);
+            }
+            started.push(id);
+        }
+
+        self.sync_proxy(ctx, service_id).await?;
+        self.persist().await
+    }
+
+    /// Start a server in a free slot
+    async fn start_server(
+        &mut self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
         config: crate::ServerConfig,
-    ) -> Result<String, Error> {
-        use crate::manager::docker::DockerRunner;
-        use crate::manager::js::JsRunner;
-        use crate::manager::python::PythonRunner;
+    ) -> Result<(), Error> {
+        let config = ctx.policy.enforce(config)?;
+        let allocated_port = ctx.ports.reserve(id).await?;
+        let server = match Self::launch(ctx, id, config, allocated_port).await {
+            Ok(server) => server,
+            Err(e) => {
+                ctx.ports.free(id);
+                return Err(e);
+            }
+        };
+        self.insert_server(id, server);
+        blueprint_sdk::debug!(
+            server = %id,
+            port = allocated_port,
+            "Server started"
+        );
+        Ok(())
+    }
 
-        let args = config.args.0.unwrap_or_default().0.clone();
+    /// Restart a server with its current configuration
+    ///
+    /// The server keeps its port, so its proxy route stays valid.
+    #[tracing::instrument(skip(self, ctx), fields(server = %id))]
+    pub async fn restart_server(
+        &mut self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+    ) -> Result<(), Error> {
+        let config = self.server(id)?.config();
+        self.reconfigure_server(ctx, id, config).await
+    }
 
-        let allocated_port = ctx.next_available_port().await?;
+    /// Replace a server with one running the new configuration
+    ///
+    /// The new server gets the same port, so its proxy route stays valid. If it fails
+    /// to start, the previous configuration is started again. A free slot of a
+    /// running service gets a new server.
+    #[tracing::instrument(skip(self, ctx, config), fields(server = %id))]
+    pub async fn reconfigure_server(
+        &mut self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        config: crate::ServerConfig,
+    ) -> Result<(), Error> {
+        self.check_not_restoring(id)?;
+        ctx.runtimes.get(&config.runtime)?;
+        config.validate(&ctx.config)?;
+        let config = ctx.policy.enforce(config)?;
+        let Some(mut previous) = self.take_server(id) else {
+            if !self.servers.contains_key(&id.service_id) {
+                return Err(Error::ServerNotFound(id.clone()));
+            }
+            self.start_server(ctx, id, config).await?;
+            self.sync_proxy(ctx, id.service_id).await?;
+            return self.persist().await;
+        };
+        if let Some(shutdown) = previous.shutdown.take() {
+            shutdown.shutdown().await;
+        }
+        let port = match ctx.ports.reserve(id).await {
+            Ok(port) => port,
+            Err(e) => {
+                previous.status.update(|s| s.state = ServerState::Failed);
+                self.insert_server(id, previous);
+                self.sync_proxy(ctx, id.service_id).await?;
+                self.persist().await?;
+                return Err(e);
+            }
+        };
+
+        let server = match Self::launch(ctx, id, config, port).await {
+            Ok(server) => server,
+            Err(e) => {
+                blueprint_sdk::warn!(?e, "New configuration failed, restoring the previous one");
+                let restored = match ctx.ports.reserve(id).await {
+                    Ok(port) => Self::launch(ctx, id, previous.config(), port).await,
+                    Err(e) => Err(e),
+                };
+                match restored {
+                    Ok(server) => {
+                        self.insert_server(id, server);
+                    }
+                    Err(e) => {
+                        blueprint_sdk::error!(?e, "Failed to restore the previous configuration");
+                        previous.status.update(|s| s.state = ServerState::Failed);
+                        self.insert_server(id, previous);
+                    }
+                }
+                self.sync_proxy(ctx, id.service_id).await?;
+                self.persist().await?;
+                return Err(e);
+            }
+        };
+
+        self.insert_server(id, server);
+        self.sync_proxy(ctx, id.service_id).await?;
+        self.persist().await?;
+        blueprint_sdk::debug!("Server reconfigured");
+        Ok(())
+    }
+
+    /// Start the runtime of a server on the given port and wait until it is ready
+    async fn launch(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        config: crate::ServerConfig,
+        port: u16,
+    ) -> Result<Server, Error> {
+        let args = config.args.0.unwrap_or_default().0.clone();
+        let readiness = config.readiness.0;
+        let volumes = config.volumes.0.unwrap_or_default().0;
+        let ports = config.ports.0.unwrap_or_default().0;
+        let resources = config
+            .resources
+            .0
+            .unwrap_or_default()
+            .with_defaults(&ctx.config.limits, &config.runtime);
 
         let env_vars: BTreeMap<String, String> = config
             .env
@@ -96,91 +962,520 @@ impl ServerManager {
             .unwrap_or_default()
             .0
             .into_iter()
-            .chain(std::iter::once((
-                "PORT".to_string(),
-                allocated_port.to_string(),
-            )))
+            .chain(std::iter::once(("PORT".to_string(), port.to_string())))
             .collect();
 
-        blueprint_sdk::debug!(
-            ?args,
-            ?env_vars,
-            package = %config.package,
-            runtime = ?config.runtime,
-            "Starting server with args"
-        );
-        let ct = match config.runtime {
-            crate::ServerRuntime::Python => {
-                PythonRunner
-                    .start(
-                        ctx,
-                        service_id,
-                        config.package.clone(),
-                        args.clone(),
-                        env_vars.clone(),
-                    )
-                    .await?
-            }
-            crate::ServerRuntime::Javascript => {
-                JsRunner
-                    .start(
-                        ctx,
-                        service_id,
-                        config.package.clone(),
-                        args.clone(),
-                        env_vars.clone(),
-                    )
-                    .await?
-            }
-            crate::ServerRuntime::Docker => {
-                DockerRunner
-                    .start(
-                        ctx,
-                        service_id,
-                        config.package.clone(),
-                        args.clone(),
-                        env_vars.clone(),
-                    )
-                    .await?
-            }
-            crate::ServerRuntime::Unknown => {
-                return Err(Error::UnknownRuntime);
-            }
-        };
-        let server = Server {
+        let mut server = Server {
             runtime: config.runtime,
             package: config.package,
             args,
             env_vars,
-            cancellation_token: Some(ct),
+            readiness,
+            resources,
+            volumes,
+            ports,
+            tunnel: config.tunnel.0,
+            registry_auth: config.registry_auth.0,
+            digest: config.digest.0,
+            build: config.build.0,
+            compose: config.compose.0,
+            binary: config.binary.0,
+            ..Default::default()
         };
-        let endpoint = format!("http://127.0.0.1:{allocated_port}");
-        self.servers.insert(service_id, server);
-        self.owners.insert(service_id, owner);
-        self.endpoints.insert(service_id, endpoint.clone());
         blueprint_sdk::debug!(
-            %endpoint,
-            "Server started"
+            args = ?server.args,
+            env_vars = ?server.env_vars,
+            package = %server.package,
+            runtime = ?server.runtime,
+            "Starting server with args"
         );
-        Ok(endpoint)
+        let started = match ctx.runtimes.get(&server.runtime) {
+            Ok(runner) => runner.start(ctx, id, &server).await,
+            Err(e) => Err(e),
+        };
+        // The runners release the port right before the runtime binds it, but not on every error
+        ctx.ports.release(id);
+        let mut handle = started?;
+
+        // Without an explicit probe, servers that publish a port must at least accept connections
+        let probe = server
+            .readiness
+            .clone()
+            .or_else(|| handle.port.map(|_| crate::ReadinessConfig::default()));
+        if let Some(probe) = probe {
+            if let Err(e) = readiness::wait_until_ready(ctx, &mut handle, &probe).await {
+                blueprint_sdk::warn!(?e, "Server did not become ready, tearing it down");
+                handle.shutdown.shutdown().await;
+                return Err(e);
+            }
+        }
+
+        server.container_id = handle.container_id;
+        server.published_ports = handle.published_ports;
+        server.version = handle.version;
+        server.status = handle.status;
+        server.shutdown = Some(handle.shutdown);
+        Ok(server)
     }
-    /// Stop the server with the given service_id.
-    #[tracing::instrument(skip(self), fields(service_id))]
-    pub async fn stop_server(&mut self, service_id: u64) -> Result<bool, Error> {
-        blueprint_sdk::debug!("Stopping server");
-        if let Some(mut server) = self.servers.remove(&service_id) {
-            if let Some(ct) = server.cancellation_token.take() {
-                ct.cancel();
-                ct.cancelled().await;
-                blueprint_sdk::debug!("Server cancelled");
-            }
-            self.owners.remove(&service_id);
-            self.endpoints.remove(&service_id);
-            blueprint_sdk::debug!("Server stopped");
-            Ok(true)
+
+    /// Delete the Docker volumes no server used for longer than the retention period
+    ///
+    /// The manager is only locked to find the volumes in use and to record the unused
+    /// ones, Docker is queried without holding the lock so jobs are not blocked meanwhile.
+    #[tracing::instrument(skip_all)]
+    pub async fn collect_volumes(ctx: &crate::MyContext) -> Result<(), Error> {
+        let existing = volumes::list(&ctx.docker).await?;
+        let expired = {
+            let mut manager = ctx.server_manager.lock().await;
+            let in_use = volumes::in_use(manager.servers.iter().flat_map(|(service_id, slots)| {
+                slots
+                    .iter()
+                    .map(|(slot, server)| (ServerId::new(*service_id, slot), server))
+            }));
+            volumes::expired(
+                &mut manager.orphaned_volumes,
+                &existing,
+                &in_use,
+                status::unix_now(),
+                ctx.config.volumes.retention_secs(),
+            )
+        };
+        let mut deleted = Vec::new();
+        for name in expired {
+            match volumes::remove(&ctx.docker, &name).await {
+                Ok(true) => {
+                    blueprint_sdk::info!(%name, "Deleted unused volume");
+                    deleted.push(name);
+                }
+                Ok(false) => blueprint_sdk::debug!(%name, "Unused volume is still mounted"),
+                Err(e) => blueprint_sdk::warn!(%name, ?e, "Failed to delete unused volume"),
+            }
+        }
+
+        let mut manager = ctx.server_manager.lock().await;
+        for name in &deleted {
+            manager.orphaned_volumes.remove(name);
+        }
+        manager.persist().await
+    }
+
+    /// What a backup of a server needs to know about it
+    pub fn backup_target(&self, id: &ServerId) -> Result<backups::Target, Error> {
+        let server = self.server(id)?;
+        self.check_not_restoring(id)?;
+        Ok(backups::Target {
+            id: id.clone(),
+            runtime: server.runtime.clone(),
+            volumes: server.volumes.clone(),
+            container_id: server.container_id.clone(),
+            status: server.status.clone(),
+        })
+    }
+
+    /// Schedule further backups of a server after one was taken, or cancel them if
+    /// no interval is set
+    pub async fn schedule_backups(
+        &mut self,
+        id: &ServerId,
+        options: crate::BackupOptions,
+    ) -> Result<(), Error> {
+        // The server may have been stopped while it was backed up
+        self.server(id)?;
+        let schedules = self.backup_schedules.entry(id.service_id).or_default();
+        if options.interval_hours == 0 {
+            schedules.remove(&id.slot);
         } else {
-            blueprint_sdk::debug!("Server not found");
-            Ok(false)
+            let schedule = backups::Schedule {
+                options,
+                last_backup_at: status::unix_now(),
+            };
+            schedules.insert(id.slot.clone(), schedule);
+        }
+        if schedules.is_empty() {
+            self.backup_schedules.remove(&id.service_id);
+        }
+        self.persist().await
+    }
+
+    /// Take the scheduled backups that are due
+    ///
+    /// The manager is only locked to look the servers up and to record the backups,
+    /// so jobs are not blocked while the data is archived.
+    #[tracing::instrument(skip_all)]
+    pub async fn run_scheduled_backups(ctx: &crate::MyContext) {
+        let now = status::unix_now();
+        let due: Vec<(ServerId, crate::BackupOptions)> = ctx
+            .server_manager
+            .lock()
+            .await
+            .backup_schedules
+            .iter()
+            .flat_map(|(service_id, schedules)| {
+                schedules
+                    .iter()
+                    .filter(|(_, schedule)| schedule.is_due(now))
+                    .map(|(slot, schedule)| {
+                        (ServerId::new(*service_id, slot), schedule.options.clone())
+                    })
+            })
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+
+        for (id, options) in &due {
+            let target = ctx.server_manager.lock().await.backup_target(id);
+            let taken = match target {
+                Ok(target) => Self::take_backup(ctx, &target, options).await,
+                Err(e) => Err(e),
+            };
+            match taken {
+                Ok(backup_id) => {
+                    blueprint_sdk::info!(server = %id, %backup_id, "Scheduled backup taken")
+                }
+                Err(e) => blueprint_sdk::warn!(server = %id, ?e, "Scheduled backup failed"),
+            }
+        }
+
+        let mut manager = ctx.server_manager.lock().await;
+        for (id, _) in &due {
+            // Also after a failure, so a broken server is not retried on every check
+            if let Some(schedule) = manager
+                .backup_schedules
+                .get_mut(&id.service_id)
+                .and_then(|schedules| schedules.get_mut(&id.slot))
+            {
+                schedule.last_backup_at = now;
+            }
+        }
+        if let Err(e) = manager.persist().await {
+            blueprint_sdk::error!(?e, "Failed to persist the backup schedules");
+        }
+    }
+
+    /// Archive the data of a server into a new backup and prune the old ones
+    ///
+    /// This does not need the manager, so it can run without holding its lock.
+    /// Returns the id of the new backup.
+    #[tracing::instrument(skip(ctx, target, options), fields(server = %target.id))]
+    pub async fn take_backup(
+        ctx: &crate::MyContext,
+        target: &backups::Target,
+        options: &crate::BackupOptions,
+    ) -> Result<String, Error> {
+        let id = &target.id;
+        let backup_id = backups::BackupStore::new_id();
+        let scratch = ctx.backups.scratch_dir(id, &backup_id).await?;
+
+        if options.pause {
+            Self::set_paused(ctx, target, true).await?;
+        }
+        let archived = Self::archive(ctx, target, &backup_id, &scratch).await;
+        if options.pause {
+            if let Err(e) = Self::set_paused(ctx, target, false).await {
+                blueprint_sdk::error!(?e, "Failed to resume the server after the backup");
+            }
+        }
+        if let Err(e) = tokio::fs::remove_dir_all(&scratch).await {
+            blueprint_sdk::debug!(?e, "Failed to remove backup scratch directory");
+        }
+        archived?;
+
+        ctx.backups
+            .prune(id, options.keep(&ctx.config.backups))
+            .await?;
+        blueprint_sdk::debug!(%backup_id, "Server backed up");
+        Ok(backup_id)
+    }
+
+    /// Write the data of a server into a backup, using `scratch` for temporary files
+    async fn archive(
+        ctx: &crate::MyContext,
+        target: &backups::Target,
+        backup_id: &str,
+        scratch: &std::path::Path,
+    ) -> Result<(), Error> {
+        let id = &target.id;
+        let sources = match target.runtime {
+            ServerRuntime::Compose => {
+                return Err(Error::InvalidConfig(
+                    "backups are not supported for Compose stacks".to_string(),
+                ));
+            }
+            ServerRuntime::Docker | ServerRuntime::Dockerfile => {
+                if target.volumes.is_empty() {
+                    return Err(Error::InvalidConfig(
+                        "the server has no volumes to back up".to_string(),
+                    ));
+                }
+                let container_id = target
+                    .container_id
+                    .as_deref()
+                    .ok_or_else(|| Error::ServerNotFound(id.clone()))?;
+                let mut sources = Vec::with_capacity(target.volumes.len());
+                for volume in &target.volumes {
+                    let path = scratch.join(format!("{}.tar", volume.name));
+                    volumes::download(&ctx.docker, container_id, volume, &path).await?;
+                    sources.push(backups::Source::File {
+                        name: format!("{}/{}.tar", backups::VOLUMES_DIR, volume.name),
+                        path,
+                    });
+                }
+                sources
+            }
+            _ => {
+                let work_dir = ctx.work_dir(id);
+                tokio::fs::create_dir_all(&work_dir).await?;
+                vec![backups::Source::Dir {
+                    name: backups::WORK_DIR.to_string(),
+                    path: work_dir,
+                }]
+            }
+        };
+        ctx.backups.create(id, backup_id, sources).await
+    }
+
+    /// Suspend or resume a running server
+    async fn set_paused(
+        ctx: &crate::MyContext,
+        target: &backups::Target,
+        paused: bool,
+    ) -> Result<(), Error> {
+        if let Some(container_id) = &target.container_id {
+            let result = if paused {
+                ctx.docker.pause_container(container_id).await
+            } else {
+                ctx.docker.unpause_container(container_id).await
+            };
+            return result.map_err(|e| {
+                Error::Io(std::io::Error::other(format!(
+                    "Failed to pause or resume Docker container {container_id}: {e}"
+                )))
+            });
+        }
+        if let Some(pid) = target.status.get().pid {
+            if paused {
+                supervisor::pause(pid).await;
+            } else {
+                supervisor::resume(pid).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the data of a server with a backup and restart it
+    ///
+    /// Restores the newest backup if no id is given, and returns the id of the
+    /// restored backup. The server is restarted even if the restore failed.
+    ///
+    /// The manager is only locked to stop and to restart the server, so other jobs
+    /// are not blocked while the backup is checked and unpacked. Meanwhile the server
+    /// is marked as restoring, which keeps the other jobs off it.
+    #[tracing::instrument(skip(ctx), fields(server = %id))]
+    pub async fn restore_server(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        backup_id: Option<String>,
+    ) -> Result<String, Error> {
+        {
+            let manager = ctx.server_manager.lock().await;
+            manager.server(id)?;
+            manager.check_not_restoring(id)?;
+        }
+        let backup_id = match backup_id {
+            Some(backup_id) => backup_id,
+            None => ctx
+                .backups
+                .list(id)
+                .await?
+                .pop()
+                .ok_or_else(|| Error::NoBackups(id.clone()))?,
+        };
+        let archive = ctx.backups.verify(id, &backup_id).await?;
+
+        // The data must not change while it is replaced
+        let config = {
+            let mut manager = ctx.server_manager.lock().await;
+            manager.check_not_restoring(id)?;
+            let server = manager
+                .server_mut(id)
+                .ok_or_else(|| Error::ServerNotFound(id.clone()))?;
+            let config = server.config();
+            if let Some(shutdown) = server.shutdown.take() {
+                shutdown.shutdown().await;
+            }
+            manager.restoring.insert(id.clone());
+            config
+        };
+        let scratch = ctx.backups.scratch_dir(id, &backup_id).await;
+        let restored = match scratch {
+            Ok(scratch) => {
+                let restored = Self::unarchive(ctx, id, &config, &archive, &scratch).await;
+                if let Err(e) = tokio::fs::remove_dir_all(&scratch).await {
+                    blueprint_sdk::debug!(?e, "Failed to remove backup scratch directory");
+                }
+                restored
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &restored {
+            blueprint_sdk::error!(?e, "Failed to restore backup, restarting the server");
+        }
+
+        let mut manager = ctx.server_manager.lock().await;
+        manager.restoring.remove(id);
+        manager.restart_server(ctx, id).await?;
+        restored?;
+        blueprint_sdk::debug!(%backup_id, "Server restored");
+        Ok(backup_id)
+    }
+
+    /// Replace the data of a stopped server with the contents of a backup archive
+    async fn unarchive(
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        config: &crate::ServerConfig,
+        archive: &std::path::Path,
+        scratch: &std::path::Path,
+    ) -> Result<(), Error> {
+        let missing = || {
+            Error::Io(std::io::Error::other(
+                "The backup holds no data for this server",
+            ))
+        };
+        match config.runtime {
+            ServerRuntime::Compose => Err(Error::InvalidConfig(
+                "backups are not supported for Compose stacks".to_string(),
+            )),
+            ServerRuntime::Docker | ServerRuntime::Dockerfile => {
+                if !backups::extract(archive, backups::VOLUMES_DIR, scratch).await? {
+                    return Err(missing());
+                }
+                let mut archives = Vec::new();
+                for volume in config.volumes.0.iter().flat_map(|volumes| &volumes.0) {
+                    let path = scratch.join(format!("{}.tar", volume.name));
+                    if tokio::fs::try_exists(&path).await? {
+                        archives.push((volume.clone(), path));
+                    }
+                }
+                if archives.is_empty() {
+                    return Err(missing());
+                }
+                let image = match config.runtime {
+                    ServerRuntime::Dockerfile => dockerfile::DockerfileRunner::image_tag(id),
+                    _ => config.package.clone(),
+                };
+                volumes::restore(&ctx.docker, id, &image, &archives).await
+            }
+            _ => {
+                // Extract next to the working directory first, so a bad backup leaves it intact
+                let work_dir = ctx.work_dir(id);
+                let staging = work_dir.with_extension("restore");
+                let _ = tokio::fs::remove_dir_all(&staging).await;
+                if !backups::extract(archive, backups::WORK_DIR, &staging).await? {
+                    let _ = tokio::fs::remove_dir_all(&staging).await;
+                    return Err(missing());
+                }
+                match tokio::fs::remove_dir_all(&work_dir).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                tokio::fs::rename(&staging, &work_dir).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Stop the server with the given id.
+    #[tracing::instrument(skip(self, ctx), fields(server = %id))]
+    pub async fn stop_server(
+        &mut self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+    ) -> Result<bool, Error> {
+        blueprint_sdk::debug!("Stopping server");
+        self.check_not_restoring(id)?;
+        let stopped = self.remove_server(ctx, id).await;
+        self.forget_if_empty(id.service_id);
+        self.sync_proxy(ctx, id.service_id).await?;
+        self.persist().await?;
+        Ok(stopped)
+    }
+
+    /// Stop all servers of the given service.
+    #[tracing::instrument(skip(self, ctx))]
+    pub async fn stop_service(
+        &mut self,
+        ctx: &crate::MyContext,
+        service_id: u64,
+    ) -> Result<bool, Error> {
+        if let Some(id) = self.restoring.iter().find(|id| id.service_id == service_id) {
+            return Err(Error::Restoring(id.clone()));
+        }
+        let mut stopped = false;
+        for slot in self.slots(service_id) {
+            stopped |= self
+                .remove_server(ctx, &ServerId::new(service_id, slot))
+                .await;
+        }
+        self.forget_if_empty(service_id);
+        // The cached artifacts are only shared by the servers of the service
+        match tokio::fs::remove_dir_all(ctx.binary_cache(service_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                blueprint_sdk::warn!(service_id, ?e, "Failed to remove the binary cache");
+            }
+            _ => {}
+        }
+        self.sync_proxy(ctx, service_id).await?;
+        self.persist().await?;
+        Ok(stopped)
+    }
+
+    /// Tear a server down and free its port, without touching the proxy route
+    ///
+    /// Returns `false` if there is no such server.
+    async fn remove_server(&mut self, ctx: &crate::MyContext, id: &ServerId) -> bool {
+        let Some(mut server) = self.take_server(id) else {
+            blueprint_sdk::debug!(server = %id, "Server not found");
+            return false;
+        };
+        if let Some(shutdown) = server.shutdown.take() {
+            shutdown.shutdown().await;
+            blueprint_sdk::debug!(server = %id, "Server cancelled");
+        }
+        if let Some(schedules) = self.backup_schedules.get_mut(&id.service_id) {
+            schedules.remove(&id.slot);
+            if schedules.is_empty() {
+                self.backup_schedules.remove(&id.service_id);
+            }
+        }
+        ctx.ports.free(id);
+        if let Some(tunnel) = self.tunnels.remove(id) {
+            tunnel.shutdown().await;
+        }
+        ctx.tunnel_ports.free(id);
+        ctx.logs.remove(id).await;
+        // The volumes are kept until their retention expired
+        let now = status::unix_now();
+        for volume in &server.volumes {
+            self.orphaned_volumes
+                .insert(volumes::volume_name(id, &volume.name), now);
+        }
+        blueprint_sdk::debug!(server = %id, "Server stopped");
+        true
+    }
+
+    /// Forget the owner of a service once it has no servers left
+    fn forget_if_empty(&mut self, service_id: u64) {
+        if self
+            .servers
+            .get(&service_id)
+            .is_some_and(|slots| !slots.is_empty())
+        {
+            return;
         }
+        self.servers.remove(&service_id);
+        self.owners.remove(&service_id);
+        self.ecdsa_owners.remove(&service_id);
     }
 }
@@ -0,0 +1,370 @@
+//! Operator policy for the servers
+//!
+//! Every server configuration is checked against the policy before a server is
+//! started, so runtimes, images and packages the operator does not allow are
+//! rejected before anything is pulled or run. Matching servers also get the
+//! resource limits the policy forces on them.
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::config::{PolicyConfig, PolicyOverride, RuntimesPolicy};
+use crate::error::Error;
+use crate::manager::compose::Stack;
+use crate::manager::dockerfile;
+use crate::{ServerConfig, ServerRuntime};
+
+/// Glob patterns a name is matched against
+#[derive(Debug, Clone)]
+struct Rules {
+    globs: GlobSet,
+}
+
+impl Rules {
+    fn new(field: &str, patterns: &[String]) -> Result<Self, Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = Glob::new(pattern).map_err(|e| {
+                Error::InvalidOperatorConfig(format!("invalid pattern in `policy.{field}`: {e}"))
+            })?;
+            builder.add(glob);
+        }
+        let globs = builder.build().map_err(|e| {
+            Error::InvalidOperatorConfig(format!("invalid patterns in `policy.{field}`: {e}"))
+        })?;
+        Ok(Self { globs })
+    }
+
+    fn matches(&self, names: &[String]) -> bool {
+        names.iter().any(|name| self.globs.is_match(name))
+    }
+
+    /// Whether the names pass the allow rules `self` and the deny rules `deny`
+    fn allows(&self, deny: &Rules, names: &[String]) -> bool {
+        !deny.matches(names) && (self.globs.is_empty() || self.matches(names))
+    }
+}
+
+/// The compiled operator policy
+#[derive(Debug, Clone)]
+pub struct Policy {
+    runtimes: RuntimesPolicy,
+    allow_images: Rules,
+    deny_images: Rules,
+    allow_registries: Rules,
+    deny_registries: Rules,
+    allow_packages: Rules,
+    deny_packages: Rules,
+    overrides: Vec<(Rules, PolicyOverride)>,
+}
+
+impl Policy {
+    /// Compile the policy of the operator config
+    pub fn new(config: &PolicyConfig) -> Result<Self, Error> {
+        let overrides = config
+            .overrides
+            .iter()
+            .map(|rule| {
+                Ok((
+                    Rules::new("overrides.matches", &rule.matches)?,
+                    rule.clone(),
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self {
+            runtimes: config.runtimes.clone(),
+            allow_images: Rules::new("allow_images", &config.allow_images)?,
+            deny_images: Rules::new("deny_images", &config.deny_images)?,
+            allow_registries: Rules::new("allow_registries", &config.allow_registries)?,
+            deny_registries: Rules::new("deny_registries", &config.deny_registries)?,
+            allow_packages: Rules::new("allow_packages", &config.allow_packages)?,
+            deny_packages: Rules::new("deny_packages", &config.deny_packages)?,
+            overrides,
+        })
+    }
+
+    /// Check that the policy allows a server configuration
+    pub fn check(&self, config: &ServerConfig) -> Result<(), Error> {
+        let violation = |reason: String| Err(Error::PolicyViolation(reason));
+        let enabled = match &config.runtime {
+            ServerRuntime::Docker => self.runtimes.docker,
+            ServerRuntime::Python => self.runtimes.python,
+            ServerRuntime::Javascript => self.runtimes.javascript,
+            ServerRuntime::Dockerfile => self.runtimes.dockerfile,
+            ServerRuntime::Compose => self.runtimes.compose,
+            ServerRuntime::Binary => self.runtimes.binary,
+            ServerRuntime::Wasm => self.runtimes.wasm,
+            ServerRuntime::Custom(name) => self.runtimes.custom.get(name).copied().unwrap_or(true),
+            ServerRuntime::Unknown => return Err(Error::UnknownRuntime),
+        };
+        if !enabled {
+            return violation(format!("the {} runtime is disabled", config.runtime.name()));
+        }
+
+        match config.runtime {
+            // Every image the build pulls must be allowed
+            ServerRuntime::Dockerfile => {
+                match config.build.0.as_ref().and_then(dockerfile::base_images) {
+                    Some(images) => images.iter().try_for_each(|image| self.check_image(image)),
+                    None if self.restricts_images() => violation(
+                        "the base images of the build cannot be checked, use an inline \
+                         `dockerfile` with the arguments its images need"
+                            .to_string(),
+                    ),
+                    None => Ok(()),
+                }
+            }
+            ServerRuntime::Docker => self.check_image(&config.package),
+            // Every service of a stack must run an allowed image
+            ServerRuntime::Compose => stack(config)
+                .iter()
+                .flat_map(Stack::images)
+                .try_for_each(|image| self.check_image(image)),
+            _ => {
+                if !self
+                    .allow_packages
+                    .allows(&self.deny_packages, &names(config))
+                {
+                    return violation(format!("package `{}` is not allowed", config.package));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether there are rules on the images at all
+    fn restricts_images(&self) -> bool {
+        [
+            &self.allow_images,
+            &self.deny_images,
+            &self.allow_registries,
+            &self.deny_registries,
+        ]
+        .iter()
+        .any(|rules| !rules.globs.is_empty())
+    }
+
+    /// Check that the policy allows a Docker image
+    fn check_image(&self, image: &str) -> Result<(), Error> {
+        let (registry, name) = image_name(image.trim());
+        if !self
+            .allow_registries
+            .allows(&self.deny_registries, std::slice::from_ref(&registry))
+        {
+            return Err(Error::PolicyViolation(format!(
+                "registry `{registry}` is not allowed"
+            )));
+        }
+        if !self
+            .allow_images
+            .allows(&self.deny_images, &[image.trim().to_string(), name])
+        {
+            return Err(Error::PolicyViolation(format!(
+                "image `{image}` is not allowed"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check a server configuration and force the limits of the matching overrides on it
+    pub fn enforce(&self, mut config: ServerConfig) -> Result<ServerConfig, Error> {
+        self.check(&config)?;
+        let names = names(&config);
+        let mut resources = config.resources.0.take().unwrap_or_default();
+        // Zero asks for no limit, which an override replaces as well
+        let cap = |value: &mut u64, max: u64| {
+            if max != 0 && (*value == 0 || *value > max) {
+                *value = max;
+            }
+        };
+        for (rules, limits) in &self.overrides {
+            if rules.matches(&names) {
+                cap(&mut resources.cpu_millis, limits.max_cpu_millis);
+                cap(&mut resources.memory_mb, limits.max_memory_mb);
+                cap(&mut resources.pids, limits.max_pids);
+                if config.runtime.limits_disk() {
+                    cap(&mut resources.disk_mb, limits.max_disk_mb);
+                }
+            }
+        }
+        config.resources.0 = Some(resources);
+        Ok(config)
+    }
+}
+
+/// The names the rules match a server configuration by
+///
+/// A stack is matched by the images of all its services.
+fn names(config: &ServerConfig) -> Vec<String> {
+    let package = config.package.trim().to_string();
+    let name = match config.runtime {
+        ServerRuntime::Docker => image_name(&package).1,
+        // The URL or path of the artifact is matched as it is, like the packages of registered runtimes
+        ServerRuntime::Binary | ServerRuntime::Wasm | ServerRuntime::Custom(_) => {
+            return vec![package];
+        }
+        ServerRuntime::Compose => {
+            let mut names = vec![package];
+            for image in stack(config).iter().flat_map(Stack::images) {
+                let image = image.trim().to_string();
+                names.push(image_name(&image).1);
+                names.push(image);
+            }
+            return names;
+        }
+        ServerRuntime::Python => package
+            .split(['=', '<', '>', '!', '~', '@', '[', ';', ' '])
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        // Skip the leading `@` of scoped packages
+        _ => match package.get(1..).and_then(|rest| rest.find('@')) {
+            Some(at) => package[..=at].to_string(),
+            None => package.clone(),
+        },
+    };
+    vec![package, name]
+}
+
+/// The stack of a Compose server, if its document parses
+fn stack(config: &ServerConfig) -> Option<Stack> {
+    config
+        .compose
+        .0
+        .as_deref()
+        .and_then(|document| Stack::parse(document).ok())
+}
+
+/// The registry and the full name of a Docker image reference
+///
+/// E.g. `docker.io` and `docker.io/library/nginx:latest` for `nginx`.
+pub fn image_name(image: &str) -> (String, String) {
+    let (name, digest) = match image.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image, None),
+    };
+    let (registry, path) = match name.split_once('/') {
+        Some((host, path)) if host.contains(['.', ':']) || host == "localhost" => (host, path),
+        _ => ("docker.io", name),
+    };
+    let mut full = format!("{registry}/");
+    if registry == "docker.io" && !path.contains('/') {
+        full.push_str("library/");
+    }
+    full.push_str(path);
+    let tagged = path
+        .rsplit('/')
+        .next()
+        .is_some_and(|last| last.contains(':'));
+    if !tagged && digest.is_none() {
+        full.push_str(":latest");
+    }
+    if let Some(digest) = digest {
+        full.push('@');
+        full.push_str(digest);
+    }
+    (registry.to_string(), full)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str]) -> Rules {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        Rules::new("test", &patterns).unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn image_name_defaults_to_docker_hub() {
+        assert_eq!(
+            image_name("nginx"),
+            (
+                "docker.io".to_string(),
+                "docker.io/library/nginx:latest".to_string()
+            )
+        );
+        assert_eq!(
+            image_name("grafana/grafana:10.0"),
+            (
+                "docker.io".to_string(),
+                "docker.io/grafana/grafana:10.0".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn image_name_keeps_the_registry() {
+        assert_eq!(
+            image_name("ghcr.io/org/app"),
+            ("ghcr.io".to_string(), "ghcr.io/org/app:latest".to_string())
+        );
+        assert_eq!(
+            image_name("localhost:5000/app:1"),
+            (
+                "localhost:5000".to_string(),
+                "localhost:5000/app:1".to_string()
+            )
+        );
+        assert_eq!(image_name("localhost/app").0, "localhost".to_string());
+    }
+
+    #[test]
+    fn image_name_keeps_the_digest_without_a_tag() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        assert_eq!(
+            image_name(&format!("nginx@{digest}")).1,
+            format!("docker.io/library/nginx@{digest}")
+        );
+        assert_eq!(
+            image_name(&format!("nginx:1.25@{digest}")).1,
+            format!("docker.io/library/nginx:1.25@{digest}")
+        );
+    }
+
+    #[test]
+    fn image_name_does_not_take_a_registry_port_for_a_tag() {
+        assert_eq!(
+            image_name("registry:5000/app").1,
+            "registry:5000/app:latest"
+        );
+    }
+
+    #[test]
+    fn empty_allow_rules_allow_everything_not_denied() {
+        let allow = rules(&[]);
+        let deny = rules(&["docker.io/library/*"]);
+        assert!(allow.allows(&deny, &names(&["ghcr.io/org/app:latest"])));
+        assert!(!allow.allows(&deny, &names(&["docker.io/library/nginx:latest"])));
+    }
+
+    #[test]
+    fn allow_rules_need_a_match() {
+        let allow = rules(&["ghcr.io/org/*"]);
+        let deny = rules(&[]);
+        assert!(allow.allows(&deny, &names(&["ghcr.io/org/app:latest"])));
+        assert!(!allow.allows(&deny, &names(&["ghcr.io/other/app:latest"])));
+        // Any of the names of a server may match
+        assert!(allow.allows(&deny, &names(&["app", "ghcr.io/org/app:latest"])));
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let allow = rules(&["ghcr.io/org/*"]);
+        let deny = rules(&["*:latest"]);
+        assert!(!allow.allows(&deny, &names(&["ghcr.io/org/app:latest"])));
+        assert!(allow.allows(&deny, &names(&["ghcr.io/org/app:1.0"])));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let patterns = vec!["[".to_string()];
+        assert!(matches!(
+            Rules::new("images.allow", &patterns),
+            Err(Error::InvalidOperatorConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+//! Host port allocation
+//!
+//! Every server leases one port from the operator-configured range for as long as
+//! it exists, so it keeps the same port (and proxy endpoint) across server restarts
+//! and operator restarts. Containers that publish more than one port lease further
+//! ports the same way. While a server is being started, its ports are additionally
+//! held by bound sockets, which are only released right before the runtime binds
+//! them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::manager::ServerId;
+
+/// How many times binding a server's leased port is attempted, e.g. while the
+/// previous runtime is still releasing it
+const BIND_ATTEMPTS: u32 = 10;
+/// The delay between two attempts to bind a leased port
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Hands out host ports to servers
+#[derive(Debug, Clone)]
+pub struct PortAllocator {
+    range: RangeInclusive<u16>,
+    inner: Arc<Mutex<Leases>>,
+}
+
+#[derive(Debug, Default)]
+struct Leases {
+    /// The port leased by each server
+    ports: BTreeMap<ServerId, u16>,
+    /// The further ports leased by each server, e.g. for the other ports of a container
+    extra: BTreeMap<ServerId, Vec<u16>>,
+    /// Sockets holding the ports of the servers that are being started
+    reserved: HashMap<ServerId, Vec<(TcpListener, UdpSocket)>>,
+}
+
+impl Leases {
+    fn is_leased(&self, port: u16) -> bool {
+        self.ports.values().any(|leased| *leased == port)
+            || self.extra.values().flatten().any(|leased| *leased == port)
+    }
+
+    fn hold(&mut self, id: &ServerId, sockets: (TcpListener, UdpSocket)) {
+        self.reserved.entry(id.clone()).or_default().push(sockets);
+    }
+}
+
+impl PortAllocator {
+    /// Create an allocator handing out ports from `range`
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        Self {
+            range,
+            inner: Arc::default(),
+        }
+    }
+
+    fn leases(&self) -> std::sync::MutexGuard<'_, Leases> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record the lease of a server that was started by a previous run of the operator
+    pub fn restore(&self, id: &ServerId, port: u16) {
+        self.leases().ports.insert(id.clone(), port);
+    }
+
+    /// Record the further ports leased by a server started by a previous run of the operator
+    pub fn restore_extra(&self, id: &ServerId, ports: Vec<u16>) {
+        if !ports.is_empty() {
+            self.leases().extra.insert(id.clone(), ports);
+        }
+    }
+
+    /// Reserve the port of a server, leasing a new one if it has none yet
+    ///
+    /// The port stays bound until [`PortAllocator::release`] is called, so nothing
+    /// else can take it in the meantime.
+    pub async fn reserve(&self, id: &ServerId) -> Result<u16, Error> {
+        let leased = self.leases().ports.get(id).copied();
+        match leased {
+            Some(port) => self.reserve_leased(id, port).await,
+            None => {
+                let port = self.lease(id)?;
+                self.leases().ports.insert(id.clone(), port);
+                Ok(port)
+            }
+        }
+    }
+
+    /// Reserve `count` further ports of a server, leasing new ones as needed
+    ///
+    /// The ports the server already leases are reused in order, surplus ones are
+    /// freed. Like [`PortAllocator::reserve`], the ports stay bound until
+    /// [`PortAllocator::release`] is called.
+    pub async fn reserve_extra(&self, id: &ServerId, count: usize) -> Result<Vec<u16>, Error> {
+        let leased = self.leases().extra.get(id).cloned().unwrap_or_default();
+        let mut ports = Vec::with_capacity(count);
+        for port in leased.into_iter().take(count) {
+            ports.push(self.reserve_leased(id, port).await?);
+        }
+        while ports.len() < count {
+            let port = self.lease(id)?;
+            // Not recorded yet, so the next search has to skip it explicitly
+            self.leases()
+                .extra
+                .entry(id.clone())
+                .or_default()
+                .push(port);
+            ports.push(port);
+        }
+        let mut leases = self.leases();
+        if ports.is_empty() {
+            leases.extra.remove(id);
+        } else {
+            leases.extra.insert(id.clone(), ports.clone());
+        }
+        Ok(ports)
+    }
+
+    /// Bind a port a server already leases
+    async fn reserve_leased(&self, id: &ServerId, port: u16) -> Result<u16, Error> {
+        for attempt in 1..=BIND_ATTEMPTS {
+            match bind(port) {
+                Ok(sockets) => {
+                    self.leases().hold(id, sockets);
+                    return Ok(port);
+                }
+                Err(e) if attempt == BIND_ATTEMPTS => {
+                    blueprint_sdk::warn!(server = %id, %port, ?e, "Leased port is still in use");
+                }
+                Err(_) => tokio::time::sleep(BIND_RETRY_DELAY).await,
+            }
+        }
+        Err(Error::PortUnavailable(port))
+    }
+
+    /// Find a free port in the range for a server and hold it
+    ///
+    /// The search starts at an offset derived from the service id, so a server
+    /// that is started again after being stopped likely gets its previous port back.
+    /// The caller records the lease.
+    fn lease(&self, id: &ServerId) -> Result<u16, Error> {
+        let mut leases = self.leases();
+        let start = u64::from(*self.range.start());
+        let len = u64::from(*self.range.end()) - start + 1;
+        let offset = id.service_id % len;
+        for i in 0..len {
+            let Ok(port) = u16::try_from(start + (offset + i) % len) else {
+                continue;
+            };
+            if leases.is_leased(port) {
+                continue;
+            }
+            if let Ok(sockets) = bind(port) {
+                leases.hold(id, sockets);
+                blueprint_sdk::debug!(server = %id, %port, "Leased port");
+                return Ok(port);
+            }
+        }
+        Err(Error::NoPortAvailable)
+    }
+
+    /// Stop holding the reserved ports of a server, so its runtime can bind them
+    pub fn release(&self, id: &ServerId) {
+        self.leases().reserved.remove(id);
+    }
+
+    /// End the leases of a server, making its ports available to other servers
+    pub fn free(&self, id: &ServerId) {
+        let mut leases = self.leases();
+        leases.reserved.remove(id);
+        if let Some(port) = leases.ports.remove(id) {
+            blueprint_sdk::debug!(server = %id, %port, "Freed port");
+        }
+        if let Some(ports) = leases.extra.remove(id) {
+            blueprint_sdk::debug!(server = %id, ?ports, "Freed further ports");
+        }
+    }
+}
+
+/// Bind the port for TCP and UDP on all interfaces, which fails if anything is bound
+/// to it on any interface
+fn bind(port: u16) -> std::io::Result<(TcpListener, UdpSocket)> {
+    let tcp = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    Ok((tcp, udp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every test uses its own range, as the tests bind the ports and run in parallel
+
+    #[tokio::test]
+    async fn servers_start_at_an_offset_by_service_id() {
+        let ports = PortAllocator::new(47100..=47109);
+        assert_eq!(
+            ports.reserve(&ServerId::new(3, "default")).await.unwrap(),
+            47103
+        );
+        assert_eq!(
+            ports.reserve(&ServerId::new(13, "default")).await.unwrap(),
+            47104
+        );
+    }
+
+    #[tokio::test]
+    async fn a_server_keeps_its_port() {
+        let ports = PortAllocator::new(47110..=47119);
+        let id = ServerId::new(1, "default");
+        let port = ports.reserve(&id).await.unwrap();
+        ports.release(&id);
+        assert_eq!(ports.reserve(&id).await.unwrap(), port);
+    }
+
+    #[tokio::test]
+    async fn freed_ports_are_leased_again() {
+        let ports = PortAllocator::new(47120..=47120);
+        let first = ServerId::new(1, "default");
+        let second = ServerId::new(2, "default");
+        assert_eq!(ports.reserve(&first).await.unwrap(), 47120);
+        assert!(matches!(
+            ports.reserve(&second).await,
+            Err(Error::NoPortAvailable)
+        ));
+        ports.free(&first);
+        assert_eq!(ports.reserve(&second).await.unwrap(), 47120);
+    }
+
+    #[tokio::test]
+    async fn extra_ports_are_reused_in_order() {
+        let ports = PortAllocator::new(47130..=47139);
+        let id = ServerId::new(0, "default");
+        assert_eq!(ports.reserve(&id).await.unwrap(), 47130);
+        let extra = ports.reserve_extra(&id, 2).await.unwrap();
+        assert_eq!(extra, vec![47131, 47132]);
+        ports.release(&id);
+        assert_eq!(ports.reserve_extra(&id, 1).await.unwrap(), vec![47131]);
+        // The surplus port is free for the other servers
+        ports.release(&id);
+        assert_eq!(
+            ports.reserve(&ServerId::new(2, "default")).await.unwrap(),
+            47132
+        );
+    }
+
+    #[tokio::test]
+    async fn restored_leases_are_skipped() {
+        let ports = PortAllocator::new(47140..=47149);
+        ports.restore(&ServerId::new(0, "default"), 47140);
+        ports.restore_extra(&ServerId::new(0, "default"), vec![47141]);
+        assert_eq!(
+            ports.reserve(&ServerId::new(0, "other")).await.unwrap(),
+            47142
+        );
+    }
+}
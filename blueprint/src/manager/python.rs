@@ -1,26 +1,126 @@
-use std::collections::BTreeMap;
-
-use tokio::process::Command;
-use tokio_util::sync::CancellationToken;
+use std::path::Path;
 
 use crate::error::Error;
-use crate::manager::ServerRunner;
+use crate::manager::cgroup;
+use crate::manager::supervisor::{self, ProcessSpec};
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
+
+/// The requirements file pinning a package to its digest, in the working directory
+const REQUIREMENTS_FILE: &str = ".server-blueprint-requirements.txt";
+/// The directory a pinned package is downloaded to for checking, in the working directory
+const VERIFY_DIR: &str = ".server-blueprint-verify";
 
 /// Python runner
 /// This runner uses the `uv` package to run Python scripts
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct PythonRunner;
 
+impl PythonRunner {
+    /// The command that runs the package with `uvx`
+    fn process_spec(ctx: &crate::MyContext, id: &ServerId, server: &Server) -> ProcessSpec {
+        let work_dir = ctx.work_dir(id);
+        // uv checks the hashes in requirements files when it installs the package
+        let pinned = server.digest.as_ref().map(|_| {
+            [
+                "--with-requirements".to_string(),
+                work_dir
+                    .join(REQUIREMENTS_FILE)
+                    .to_string_lossy()
+                    .into_owned(),
+            ]
+        });
+        ProcessSpec {
+            program: "uvx".to_string(),
+            args: pinned
+                .into_iter()
+                .flatten()
+                .chain(["run".to_string(), server.package.clone(), "--".to_string()])
+                .chain(server.args.iter().cloned())
+                .collect(),
+            env: server.env_vars.clone(),
+            clear_env: false,
+            work_dir,
+            grace_period: ctx.config.stop.grace_period(),
+            cgroup: cgroup::needed(&server.resources)
+                .then(|| cgroup::path(&ctx.config.limits.cgroup_root, id)),
+            limits: server.resources.clone(),
+        }
+    }
+
+    /// The exact version the package is pinned to, e.g. `1.2.3` for `pkg==1.2.3` or `pkg@1.2.3`
+    fn pinned_version(package: &str) -> Option<String> {
+        let (_, version) = package
+            .split_once("==")
+            .or_else(|| package.split_once('@'))?;
+        super::exact_version(version)
+    }
+
+    /// The package as a requirement on its exact version, e.g. `pkg==1.2.3` for `pkg@1.2.3`
+    pub fn requirement(package: &str) -> Option<String> {
+        let (name, _) = package
+            .split_once("==")
+            .or_else(|| package.split_once('@'))?;
+        let version = Self::pinned_version(package)?;
+        Some(format!("{}=={version}", name.trim()))
+    }
+
+    /// Pin the package to its digest and check that it matches
+    ///
+    /// The package is downloaded on its own, without its dependencies, so a
+    /// mismatch fails the start instead of crashing the server later.
+    async fn verify_digest(work_dir: &Path, package: &str, digest: &str) -> Result<(), Error> {
+        let requirement = Self::requirement(package).ok_or_else(|| {
+            Error::InvalidConfig("a package with a `digest` must be pinned".to_string())
+        })?;
+        tokio::fs::create_dir_all(work_dir).await?;
+        let requirements = work_dir.join(REQUIREMENTS_FILE);
+        tokio::fs::write(&requirements, format!("{requirement} --hash={digest}\n")).await?;
+
+        let target = work_dir.join(VERIFY_DIR);
+        let output = tokio::process::Command::new("uv")
+            .args(["pip", "install", "--quiet", "--no-deps", "--require-hashes"])
+            .arg("--target")
+            .arg(&target)
+            .arg("-r")
+            .arg(&requirements)
+            .output()
+            .await;
+        let _ = tokio::fs::remove_dir_all(&target).await;
+        let output = output?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("Hash mismatch") {
+            // uv lists the computed hashes after the expected ones
+            let actual = stderr
+                .split_once("Computed:")
+                .and_then(|(_, computed)| computed.split_whitespace().next())
+                .unwrap_or("an unknown digest");
+            return Err(Error::IntegrityMismatch {
+                subject: format!("package {package}"),
+                expected: digest.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        Err(Error::Io(std::io::Error::other(format!(
+            "Failed to download package {package}: {}",
+            stderr.trim()
+        ))))
+    }
+}
+
 impl ServerRunner for PythonRunner {
-    #[tracing::instrument(skip(self, ctx), fields(%package, args, runtime = "python"))]
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "python")
+    )]
     async fn start(
         &self,
         ctx: &crate::MyContext,
-        service_id: u64,
-        package: String,
-        args: Vec<String>,
-        env_vars: BTreeMap<String, String>,
-    ) -> Result<CancellationToken, Error> {
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
         // Ensure uv is installed
         let mut checked = self.check(ctx).await;
         blueprint_sdk::debug!(?checked, "Checking if uv is installed");
@@ -37,40 +137,44 @@ impl ServerRunner for PythonRunner {
             }
         }
 
+        if let Some(digest) = &server.digest {
+            Self::verify_digest(&ctx.work_dir(id), &server.package, digest).await?;
+        }
+
         blueprint_sdk::debug!("Starting Python server with uvx");
 
-        // Start the Python process directly
-        let mut cmd = Command::new("uvx");
-        cmd.arg("run")
-            .arg(&package)
-            .arg("--")
-            .args(&args)
-            .envs(&env_vars)
-            .kill_on_drop(true);
-
-        let child = cmd.spawn().map_err(Error::Io)?;
-        
-        // Create cancellation token for cleanup
-        let ct = CancellationToken::new();
-        let cleanup_ct = ct.clone();
-        let child_id = child.id();
-
-        // Spawn cleanup task
-        tokio::spawn(async move {
-            cleanup_ct.cancelled().await;
-            if let Some(pid) = child_id {
-                blueprint_sdk::debug!(?pid, "Terminating Python process");
-                #[cfg(unix)]
-                {
-                    let _ = tokio::process::Command::new("kill")
-                        .arg(pid.to_string())
-                        .status()
-                        .await;
-                }
-            }
-        });
+        let version = Self::pinned_version(&server.package);
+        let buffer = ctx.logs.buffer(id).await;
+        // Let go of the port right before the server binds it
+        ctx.ports.release(id);
+        let mut handle = supervisor::spawn(
+            Self::process_spec(ctx, id, server),
+            id.clone(),
+            "Python",
+            buffer,
+        )
+        .await?;
+        handle.version = version;
+        Ok(handle)
+    }
 
-        Ok(ct)
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        match supervisor::adoptable(&server.status.get()).await {
+            Some(pid) => Ok(Some(supervisor::adopt(
+                pid,
+                Self::process_spec(ctx, id, server),
+                id.clone(),
+                "Python",
+                server.status.clone(),
+                ctx.logs.buffer(id).await,
+            ))),
+            None => Ok(None),
+        }
     }
 
     async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
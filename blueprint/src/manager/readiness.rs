@@ -0,0 +1,236 @@
+//! Readiness probes
+//!
+//! A freshly started server is only handed out (and proxied) once it passes its
+//! readiness probe, so early callers never hit a server that is still booting and
+//! a server that crashes on boot fails the start instead of looking successful.
+
+use std::time::Duration;
+
+use docktopus::bollard::container::LogsOptions;
+use docktopus::bollard::exec::{CreateExecOptions, StartExecResults};
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+use crate::error::Error;
+use crate::manager::RuntimeHandle;
+use crate::{ProbeKind, ReadinessConfig};
+
+/// How long to wait for a server to become ready when no timeout is configured
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// The delay between two probe attempts when no interval is configured
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+/// Upper bound for a single probe attempt
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait until the server behind `handle` passes the configured readiness probe
+///
+/// # Errors
+///
+/// Returns [`Error::NotReady`] if the probe does not succeed before the timeout or
+/// fails more often than the configured number of retries.
+#[tracing::instrument(skip_all, fields(probe = ?config.probe, target = %config.target))]
+pub async fn wait_until_ready(
+    ctx: &crate::MyContext,
+    handle: &mut RuntimeHandle,
+    config: &ReadinessConfig,
+) -> Result<(), Error> {
+    let timeout = match config.timeout_secs {
+        0 => DEFAULT_TIMEOUT,
+        secs => Duration::from_secs(secs),
+    };
+    let interval = match config.interval_ms {
+        0 => DEFAULT_INTERVAL,
+        ms => Duration::from_millis(ms),
+    };
+    let deadline = Instant::now() + timeout;
+
+    // Log lines are streamed rather than polled, so a single attempt covers the whole timeout
+    if config.probe == ProbeKind::Log {
+        return match tokio::time::timeout_at(
+            deadline,
+            wait_for_log_line(ctx, handle, &config.target),
+        )
+        .await
+        {
+            Ok(result) => result.map_err(Error::NotReady),
+            Err(_) => Err(Error::NotReady(format!(
+                "no log line containing {:?} within {timeout:?}",
+                config.target
+            ))),
+        };
+    }
+
+    let mut failures = 0;
+    loop {
+        let attempt_timeout =
+            ATTEMPT_TIMEOUT.min(deadline.saturating_duration_since(Instant::now()));
+        let result =
+            match tokio::time::timeout(attempt_timeout, probe_once(ctx, handle, config)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("attempt timed out after {attempt_timeout:?}")),
+            };
+
+        let reason = match result {
+            Ok(()) => {
+                blueprint_sdk::debug!(failures, "Server is ready");
+                return Ok(());
+            }
+            Err(reason) => reason,
+        };
+
+        failures += 1;
+        blueprint_sdk::trace!(failures, %reason, "Readiness probe failed");
+        if config.retries != 0 && failures >= config.retries {
+            return Err(Error::NotReady(format!(
+                "{:?} probe failed {failures} times, last error: {reason}",
+                config.probe
+            )));
+        }
+        if Instant::now() + interval >= deadline {
+            return Err(Error::NotReady(format!(
+                "{:?} probe did not succeed within {timeout:?}, last error: {reason}",
+                config.probe
+            )));
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Run a single probe attempt
+async fn probe_once(
+    ctx: &crate::MyContext,
+    handle: &RuntimeHandle,
+    config: &ReadinessConfig,
+) -> Result<(), String> {
+    match config.probe {
+        ProbeKind::Tcp => {
+            let port = handle.port.ok_or("the server does not publish a port")?;
+            TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, port))
+                .await
+                .map(drop)
+                .map_err(|e| e.to_string())
+        }
+        ProbeKind::Http => {
+            let port = handle.port.ok_or("the server does not publish a port")?;
+            probe_http(port, &config.target).await
+        }
+        ProbeKind::Exec => match &handle.container_id {
+            Some(container_id) => probe_docker_exec(ctx, container_id, &config.target).await,
+            None => Err("exec probes need a container".to_string()),
+        },
+        ProbeKind::Log => unreachable!("log probes are streamed"),
+    }
+}
+
+/// Issue a plain HTTP/1.1 `GET` and accept any 2xx or 3xx status
+async fn probe_http(port: u16, path: &str) -> Result<(), String> {
+    let path = if path.is_empty() { "/" } else { path };
+    let mut stream = TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, port))
+        .await
+        .map_err(|e| e.to_string())?;
+    let request =
+        format!("GET {path} HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\nConnection: close\r\n\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Only the status line is needed, e.g. `HTTP/1.1 200 OK`
+    let mut buf = [0u8; 64];
+    let n = stream.read(&mut buf).await.map_err(|e| e.to_string())?;
+    let status_line = String::from_utf8_lossy(&buf[..n]);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| format!("invalid HTTP response: {status_line:?}"))?;
+    if (200..400).contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("GET {path} returned status {status}"))
+    }
+}
+
+/// Run the probe command inside the container and check its exit code
+async fn probe_docker_exec(
+    ctx: &crate::MyContext,
+    container_id: &str,
+    command: &str,
+) -> Result<(), String> {
+    let exec = ctx
+        .docker
+        .create_exec(
+            container_id,
+            CreateExecOptions {
+                cmd: Some(vec!["sh", "-c", command]),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let StartExecResults::Attached { mut output, .. } = ctx
+        .docker
+        .start_exec(&exec.id, None)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        // Drain the output so the exec runs to completion
+        while output.next().await.is_some() {}
+    }
+
+    let inspect = ctx
+        .docker
+        .inspect_exec(&exec.id)
+        .await
+        .map_err(|e| e.to_string())?;
+    match inspect.exit_code {
+        Some(0) => Ok(()),
+        code => Err(format!("`{command}` exited with {code:?}")),
+    }
+}
+
+/// Wait until the server prints a line containing `needle`
+async fn wait_for_log_line(
+    ctx: &crate::MyContext,
+    handle: &mut RuntimeHandle,
+    needle: &str,
+) -> Result<(), String> {
+    if let Some(container_id) = &handle.container_id {
+        let mut logs = ctx.docker.logs(
+            container_id,
+            Some(LogsOptions::<String> {
+                follow: true,
+                stdout: true,
+                stderr: true,
+                ..Default::default()
+            }),
+        );
+        while let Some(chunk) = logs.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            if String::from_utf8_lossy(&chunk.into_bytes()).contains(needle) {
+                return Ok(());
+            }
+        }
+        return Err("the container exited before printing the log line".to_string());
+    }
+
+    let mut logs = handle
+        .logs
+        .take()
+        .ok_or("the server output is not captured")?;
+    loop {
+        match logs.recv().await {
+            Ok(line) if line.contains(needle) => return Ok(()),
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => {
+                return Err("the process exited before printing the log line".to_string());
+            }
+        }
+    }
+}
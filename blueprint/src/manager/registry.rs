@@ -0,0 +1,132 @@
+//! Credentials for private Docker registries
+//!
+//! Service owners either refer to credentials the operator stores in its config by
+//! name, or bring their own. Their own credentials are a JSON object like
+//! `{"username": "…", "password": "…"}`, encrypted to the operator's ECDSA key with
+//! ECIES over secp256k1 (as produced by `eciesjs`), so they never appear in plaintext
+//! in the service request or the persisted server state. They are only decrypted
+//! right before an image is pulled.
+
+use std::fmt;
+
+use blueprint_sdk::crypto::sp_core::SpEcdsa;
+use blueprint_sdk::keystore::backends::Backend;
+use blueprint_sdk::runner::config::BlueprintEnvironment;
+use docktopus::bollard::auth::DockerCredentials;
+
+use crate::RegistryAuth;
+use crate::config::OperatorConfig;
+use crate::error::Error;
+
+/// The operator's ECDSA key, which owners encrypt their registry credentials to
+#[derive(Clone)]
+pub struct OperatorKey {
+    secret: [u8; 32],
+}
+
+impl fmt::Debug for OperatorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OperatorKey(..)")
+    }
+}
+
+impl OperatorKey {
+    /// Load the first local ECDSA key from the keystore
+    ///
+    /// Returns `None` if the keystore has none, in which case encrypted credentials
+    /// cannot be used.
+    pub fn load(env: &BlueprintEnvironment) -> Option<Self> {
+        let keystore = env.keystore();
+        let loaded = keystore
+            .first_local::<SpEcdsa>()
+            .and_then(|public| keystore.get_secret::<SpEcdsa>(&public));
+        let secret = match loaded {
+            Ok(pair) => pair.0.seed(),
+            Err(e) => {
+                blueprint_sdk::warn!(
+                    ?e,
+                    "No ECDSA key, encrypted registry credentials are disabled"
+                );
+                return None;
+            }
+        };
+        let public = ecies::SecretKey::parse_slice(&secret)
+            .map(|secret| ecies::PublicKey::from_secret_key(&secret).serialize_compressed());
+        if let Ok(public) = public {
+            blueprint_sdk::info!(
+                public_key = %hex::encode(public),
+                "Registry credentials can be encrypted to the operator key"
+            );
+        }
+        Some(Self { secret })
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        ecies::decrypt(&self.secret, ciphertext).map_err(|_| {
+            Error::InvalidConfig(
+                "the registry credentials are not encrypted to the operator key".to_string(),
+            )
+        })
+    }
+}
+
+/// Registry credentials as encrypted by a service owner
+#[derive(Default, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+struct OwnerCredentials {
+    server_address: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identity_token: Option<String>,
+}
+
+/// Resolve the credentials to pull the image of a server of the given service with
+pub fn credentials(
+    config: &OperatorConfig,
+    key: Option<&OperatorKey>,
+    service_id: u64,
+    auth: &RegistryAuth,
+) -> Result<DockerCredentials, Error> {
+    if let Some(name) = &auth.name.0 {
+        let registry = config
+            .registries
+            .get(name)
+            .filter(|registry| {
+                registry.service_ids.is_empty() || registry.service_ids.contains(&service_id)
+            })
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!("unknown registry credentials `{name}`"))
+            })?;
+        return Ok(DockerCredentials {
+            serveraddress: registry.server_address.clone(),
+            username: registry.username.clone(),
+            password: registry.password.clone(),
+            identitytoken: registry.identity_token.clone(),
+            ..Default::default()
+        });
+    }
+
+    let Some(encrypted) = &auth.encrypted.0 else {
+        return Err(Error::InvalidConfig(
+            "registry credentials need either a `name` or `encrypted`".to_string(),
+        ));
+    };
+    let key = key.ok_or_else(|| {
+        Error::InvalidConfig("the operator does not accept encrypted credentials".to_string())
+    })?;
+    let ciphertext = hex::decode(encrypted.trim_start_matches("0x")).map_err(|_| {
+        Error::InvalidConfig("the encrypted registry credentials are not hex".to_string())
+    })?;
+    // Parse errors are dropped, they could quote the credentials
+    let owner: OwnerCredentials =
+        serde_json::from_slice(&key.decrypt(&ciphertext)?).map_err(|_| {
+            Error::InvalidConfig("the decrypted registry credentials are invalid".to_string())
+        })?;
+    Ok(DockerCredentials {
+        serveraddress: owner.server_address,
+        username: owner.username,
+        password: owner.password,
+        identitytoken: owner.identity_token,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,175 @@
+//! The runtimes the servers can run on
+//!
+//! Every runtime is a [`ServerRunner`] registered under the name servers ask for it
+//! by in their `runtime`. The built-in runtimes are always registered, crates that
+//! embed the blueprint can add their own with [`MyContext::with_runtimes`], and the
+//! operator config can add [command runtimes](super::command).
+//!
+//! [`MyContext::with_runtimes`]: crate::MyContext::with_runtimes
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::ServerRuntime;
+use crate::error::Error;
+use crate::manager::binary::BinaryRunner;
+use crate::manager::compose::ComposeRunner;
+use crate::manager::docker::DockerRunner;
+use crate::manager::dockerfile::DockerfileRunner;
+use crate::manager::js::JsRunner;
+use crate::manager::python::PythonRunner;
+#[cfg(feature = "wasm")]
+use crate::manager::wasm::WasmRunner;
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
+
+/// The longest name of a registered runtime
+const MAX_NAME_LEN: usize = 64;
+
+/// A [`ServerRunner`] behind a pointer
+///
+/// Implemented for every [`ServerRunner`] that can be shared between tasks.
+pub trait DynServerRunner: Send + Sync {
+    /// See [`ServerRunner::start`]
+    fn start<'a>(
+        &'a self,
+        ctx: &'a crate::MyContext,
+        id: &'a ServerId,
+        server: &'a Server,
+    ) -> BoxFuture<'a, Result<RuntimeHandle, Error>>;
+
+    /// See [`ServerRunner::adopt`]
+    fn adopt<'a>(
+        &'a self,
+        ctx: &'a crate::MyContext,
+        id: &'a ServerId,
+        server: &'a Server,
+    ) -> BoxFuture<'a, Result<Option<ShutdownHandle>, Error>>;
+
+    /// See [`ServerRunner::check`]
+    fn check<'a>(&'a self, ctx: &'a crate::MyContext) -> BoxFuture<'a, Result<bool, Error>>;
+
+    /// See [`ServerRunner::install`]
+    fn install<'a>(&'a self, ctx: &'a crate::MyContext) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+impl<R: ServerRunner + Send + Sync> DynServerRunner for R {
+    fn start<'a>(
+        &'a self,
+        ctx: &'a crate::MyContext,
+        id: &'a ServerId,
+        server: &'a Server,
+    ) -> BoxFuture<'a, Result<RuntimeHandle, Error>> {
+        Box::pin(ServerRunner::start(self, ctx, id, server))
+    }
+
+    fn adopt<'a>(
+        &'a self,
+        ctx: &'a crate::MyContext,
+        id: &'a ServerId,
+        server: &'a Server,
+    ) -> BoxFuture<'a, Result<Option<ShutdownHandle>, Error>> {
+        Box::pin(ServerRunner::adopt(self, ctx, id, server))
+    }
+
+    fn check<'a>(&'a self, ctx: &'a crate::MyContext) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(ServerRunner::check(self, ctx))
+    }
+
+    fn install<'a>(&'a self, ctx: &'a crate::MyContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(ServerRunner::install(self, ctx))
+    }
+}
+
+/// The runners of the runtimes, by name
+#[derive(Clone)]
+pub struct RuntimeRegistry {
+    runners: BTreeMap<String, Arc<dyn DynServerRunner>>,
+}
+
+impl RuntimeRegistry {
+    /// A registry of the built-in runtimes
+    pub fn new() -> Self {
+        let mut registry = Self {
+            runners: BTreeMap::new(),
+        };
+        registry.insert(ServerRuntime::Python, PythonRunner);
+        registry.insert(ServerRuntime::Javascript, JsRunner);
+        registry.insert(ServerRuntime::Docker, DockerRunner);
+        registry.insert(ServerRuntime::Dockerfile, DockerfileRunner);
+        registry.insert(ServerRuntime::Compose, ComposeRunner);
+        registry.insert(ServerRuntime::Binary, BinaryRunner);
+        #[cfg(feature = "wasm")]
+        registry.insert(ServerRuntime::Wasm, WasmRunner);
+        registry
+    }
+
+    fn insert(
+        &mut self,
+        runtime: ServerRuntime,
+        runner: impl ServerRunner + Send + Sync + 'static,
+    ) {
+        self.runners
+            .insert(runtime.name().to_string(), Arc::new(runner));
+    }
+
+    /// Register a runtime, which servers can then ask for by `name`
+    ///
+    /// Names are lowercase letters, digits, `-` and `_`, and must not be taken by
+    /// another runtime, built in or not.
+    pub fn register(
+        &mut self,
+        name: &str,
+        runner: impl ServerRunner + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidOperatorConfig(format!(
+                "cannot register runtime `{name}`: {reason}"
+            )))
+        };
+        let valid = !name.is_empty()
+            && name.len() <= MAX_NAME_LEN
+            && name
+                .bytes()
+                .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'));
+        if !valid {
+            return invalid("names are lowercase letters, digits, `-` and `_`");
+        }
+        // Built-in names are reserved, even for runtimes this operator is built without
+        if !matches!(ServerRuntime::from_name(name), ServerRuntime::Custom(_)) {
+            return invalid("the name is taken by a built-in runtime");
+        }
+        if self.runners.contains_key(name) {
+            return invalid("the name is already registered");
+        }
+        self.insert(ServerRuntime::Custom(name.to_string()), runner);
+        Ok(())
+    }
+
+    /// The runner of a runtime
+    pub fn get(&self, runtime: &ServerRuntime) -> Result<&dyn DynServerRunner, Error> {
+        self.runners
+            .get(runtime.name())
+            .map(Arc::as_ref)
+            .ok_or(Error::UnknownRuntime)
+    }
+
+    /// The names of the registered runtimes
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.runners.keys().map(String::as_str)
+    }
+}
+
+impl Default for RuntimeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RuntimeRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
@@ -0,0 +1,156 @@
+//! On-disk persistence for the [`ServerManager`]
+//!
+//! The state is stored as a versioned JSON document so that the operator can
+//! be restarted (or upgraded) without forgetting which servers are running.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::manager::{DEFAULT_SLOT, ServerManager};
+
+/// The current version of the on-disk state format
+pub const STATE_VERSION: u32 = 2;
+
+/// The name of the state file inside the state directory
+const STATE_FILE: &str = "state.json";
+
+/// Stores the [`ServerManager`] state in a directory on disk
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+struct StateFileRef<'a> {
+    version: u32,
+    manager: &'a ServerManager,
+}
+
+#[derive(serde::Deserialize)]
+struct StateFile {
+    version: u32,
+    manager: serde_json::Value,
+}
+
+impl StateStore {
+    /// Create a new state store rooted at the given directory
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory this store writes to
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE)
+    }
+
+    /// Load the persisted manager state, if any
+    ///
+    /// Returns `Ok(None)` when no state has been written yet.
+    pub async fn load(&self) -> Result<Option<ServerManager>, Error> {
+        let bytes = match tokio::fs::read(self.path()).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let file: StateFile = serde_json::from_slice(&bytes)?;
+        if file.version > STATE_VERSION {
+            return Err(Error::UnsupportedStateVersion(file.version));
+        }
+        let mut manager = file.manager;
+        if file.version < 2 {
+            migrate_v1(&mut manager);
+        }
+        let manager = serde_json::from_value(manager)?;
+        Ok(Some(manager))
+    }
+
+    /// Persist the manager state
+    ///
+    /// The state is written to a temporary file first and then renamed over the
+    /// previous state, so a crash mid-write never leaves a truncated file behind.
+    pub async fn save(&self, manager: &ServerManager) -> Result<(), Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let bytes = serde_json::to_vec_pretty(&StateFileRef {
+            version: STATE_VERSION,
+            manager,
+        })?;
+        let tmp = self.dir.join(format!("{STATE_FILE}.tmp"));
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, self.path()).await?;
+        Ok(())
+    }
+}
+
+/// Version 1 ran a single server per service, which now runs in the default slot
+fn migrate_v1(manager: &mut serde_json::Value) {
+    for key in ["servers", "backup_schedules"] {
+        let Some(serde_json::Value::Object(services)) = manager.get_mut(key) else {
+            continue;
+        };
+        for value in services.values_mut() {
+            let slots = serde_json::Map::from_iter([(DEFAULT_SLOT.to_string(), value.take())]);
+            *value = serde_json::Value::Object(slots);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn v1_servers_move_to_the_default_slot() {
+        let mut manager = json!({
+            "servers": { "1": { "port": 8000 }, "2": { "port": 8001 } },
+            "backup_schedules": { "1": { "interval_secs": 60 } },
+            "owners": { "1": "owner" },
+        });
+        migrate_v1(&mut manager);
+        assert_eq!(
+            manager,
+            json!({
+                "servers": {
+                    "1": { "default": { "port": 8000 } },
+                    "2": { "default": { "port": 8001 } },
+                },
+                "backup_schedules": { "1": { "default": { "interval_secs": 60 } } },
+                "owners": { "1": "owner" },
+            })
+        );
+    }
+
+    #[test]
+    fn v1_without_servers_is_left_alone() {
+        let mut manager = json!({ "owners": {} });
+        migrate_v1(&mut manager);
+        assert_eq!(manager, json!({ "owners": {} }));
+    }
+
+    #[tokio::test]
+    async fn newer_versions_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("state-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let file = json!({ "version": STATE_VERSION + 1, "manager": {} });
+        tokio::fs::write(dir.join(STATE_FILE), file.to_string())
+            .await
+            .unwrap();
+        let loaded = StateStore::new(&dir).load().await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert!(matches!(
+            loaded,
+            Err(Error::UnsupportedStateVersion(v)) if v == STATE_VERSION + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_state_loads_as_none() {
+        let dir = std::env::temp_dir().join(format!("state-{}", uuid::Uuid::new_v4()));
+        assert!(StateStore::new(dir).load().await.unwrap().is_none());
+    }
+}
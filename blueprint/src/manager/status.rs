@@ -0,0 +1,105 @@
+//! Runtime status of the servers
+//!
+//! The status is updated by background tasks (the process supervisor, the Docker
+//! events watcher) without going through the [`ServerManager`](super::ServerManager)
+//! lock, and is persisted together with the rest of the manager state.
+
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The last known state of a server
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerState {
+    /// The server runtime is up
+    #[default]
+    Running,
+    /// The server exited with a failure and is about to be restarted
+    Restarting,
+    /// The server exited successfully and will not be restarted
+    Exited,
+    /// The server disappeared or kept crashing and will not be restarted
+    Failed,
+    /// The server was stopped on request
+    Stopped,
+}
+
+/// A snapshot of a server's runtime status
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RuntimeStatus {
+    /// The last known state of the server
+    pub state: ServerState,
+    /// The process id, if the server runs as a local process
+    pub pid: Option<u32>,
+    /// The start time of the process, in clock ticks since boot
+    ///
+    /// Tells the process apart from a later one reusing its pid.
+    pub pid_start_time: Option<u64>,
+    /// How many times the server was restarted after a failure
+    pub restart_count: u32,
+    /// The exit code of the last run, if it exited
+    pub exit_code: Option<i64>,
+    /// Whether the last run was killed for running out of memory
+    pub oom_killed: bool,
+    /// When the current run started, in seconds since the UNIX epoch
+    pub started_at: Option<u64>,
+}
+
+impl RuntimeStatus {
+    /// Record that a new run of the server started just now
+    pub fn mark_started(&mut self) {
+        self.started_at = Some(unix_now());
+    }
+
+    /// How long the server has been up, if it is running
+    pub fn uptime_secs(&self) -> Option<u64> {
+        match self.state {
+            ServerState::Running => self
+                .started_at
+                .map(|started_at| unix_now().saturating_sub(started_at)),
+            _ => None,
+        }
+    }
+}
+
+/// The current time, in seconds since the UNIX epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A [`RuntimeStatus`] shared between the manager and the task watching the runtime
+#[derive(Default, Clone, Debug)]
+pub struct SharedStatus(Arc<Mutex<RuntimeStatus>>);
+
+impl SharedStatus {
+    /// Create a shared status with the given initial value
+    pub fn new(status: RuntimeStatus) -> Self {
+        Self(Arc::new(Mutex::new(status)))
+    }
+
+    /// Get a snapshot of the current status
+    pub fn get(&self) -> RuntimeStatus {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Update the status in place
+    pub fn update(&self, f: impl FnOnce(&mut RuntimeStatus)) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+impl serde::Serialize for SharedStatus {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.get().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SharedStatus {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RuntimeStatus::deserialize(deserializer).map(Self::new)
+    }
+}
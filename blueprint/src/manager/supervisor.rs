@@ -0,0 +1,490 @@
+//! Process supervisor
+//!
+//! Owns the child processes of the process runtimes (Python, JavaScript, Binary and
+//! the command runtimes): it reaps them, records their exit status, and restarts
+//! them on failure with an exponential backoff, the same way Docker's `on-failure`
+//! restart policy treats containers.
+//!
+//! Every process is spawned in its own process group. The actual server is often a
+//! grandchild of the `uvx`/`bunx` wrapper, so the whole group is signalled when the
+//! process is stopped, and stopping only completes once every process in it exited.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::ResourceLimits;
+use crate::error::Error;
+use crate::manager::cgroup;
+use crate::manager::logs::{LogBuffer, LogStream};
+use crate::manager::status::{RuntimeStatus, ServerState, SharedStatus};
+use crate::manager::{RuntimeHandle, ServerId, ShutdownHandle};
+
+/// The delay before the first restart, doubled after each consecutive failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper bound for the restart delay
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Failures older than this are forgotten, and a process that stays up this long
+/// starts over with the initial backoff
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(60);
+/// The number of failures within [`CRASH_LOOP_WINDOW`] after which the process is given up
+const CRASH_LOOP_THRESHOLD: usize = 5;
+/// How long to wait for a process group to disappear after `SIGKILL`
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a terminating process group is checked for remaining processes
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the liveness of an adopted process is checked
+const ADOPTED_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How many output lines are buffered for slow subscribers
+const LOG_CHANNEL_CAPACITY: usize = 1024;
+
+/// Everything needed to (re)spawn a supervised process
+#[derive(Debug, Clone)]
+pub struct ProcessSpec {
+    /// The program to run
+    pub program: String,
+    /// The arguments to pass to the program
+    pub args: Vec<String>,
+    /// The environment variables to set, including `PORT`
+    pub env: BTreeMap<String, String>,
+    /// Whether the processes get only `env`, rather than the environment of the operator
+    /// with `env` on top
+    pub clear_env: bool,
+    /// The working directory of the processes, created if needed
+    pub work_dir: PathBuf,
+    /// How long the processes get to exit after `SIGTERM` before they are killed
+    pub grace_period: Duration,
+    /// The resources the processes may use
+    pub limits: ResourceLimits,
+    /// The cgroup enforcing the limits, if any are set
+    pub cgroup: Option<PathBuf>,
+}
+
+impl ProcessSpec {
+    fn command(&self) -> Command {
+        let mut cmd = match &self.cgroup {
+            None => {
+                let mut cmd = Command::new(&self.program);
+                cmd.args(&self.args);
+                cmd
+            }
+            Some(cgroup) => {
+                // Join the cgroup from a shell that then `exec`s the program, so the
+                // program and everything it spawns are limited from the start
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c")
+                    .arg(r#"echo $$ > "$1/cgroup.procs" || exit 1; shift; exec "$@""#)
+                    .arg("sh")
+                    .arg(cgroup)
+                    .arg(&self.program)
+                    .args(&self.args);
+                cmd
+            }
+        };
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(&self.env)
+            .current_dir(&self.work_dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Lead a new process group, so the whole tree can be signalled at once
+            .process_group(0);
+        cmd
+    }
+
+    /// Spawn the process, returning it along with its process group id
+    async fn spawn(&self) -> Result<(Child, u32), Error> {
+        if let Some(cgroup) = &self.cgroup {
+            cgroup::configure(cgroup, &self.limits).await?;
+        }
+        tokio::fs::create_dir_all(&self.work_dir).await?;
+        let child = self.command().spawn()?;
+        let pgid = child
+            .id()
+            .ok_or_else(|| Error::Io(std::io::Error::other("Spawned process has no pid")))?;
+        Ok((child, pgid))
+    }
+
+    fn port(&self) -> Option<u16> {
+        self.env.get("PORT").and_then(|port| port.parse().ok())
+    }
+}
+
+/// The process currently being supervised
+enum Supervised {
+    /// A process spawned by this supervisor, leading the process group `pgid`
+    Child { child: Child, pgid: u32 },
+    /// A process spawned by a previous run of the operator
+    Adopted(u32),
+}
+
+impl Supervised {
+    /// The process group of the process
+    ///
+    /// Processes spawned by older versions do not lead a group, in which case this
+    /// is just their pid, see [`signal`].
+    fn pgid(&self) -> u32 {
+        match self {
+            Self::Child { pgid, .. } => *pgid,
+            Self::Adopted(pid) => *pid,
+        }
+    }
+}
+
+/// Spawn the process and a supervisor task that owns it
+///
+/// The output of the process, including after restarts, is captured into `buffer`.
+pub async fn spawn(
+    spec: ProcessSpec,
+    id: ServerId,
+    runtime: impl Into<Arc<str>>,
+    buffer: LogBuffer,
+) -> Result<RuntimeHandle, Error> {
+    let (logs_tx, logs) = broadcast::channel(LOG_CHANNEL_CAPACITY);
+    let output = Output {
+        tx: logs_tx,
+        buffer,
+        id,
+        runtime: runtime.into(),
+    };
+    let (mut child, pgid) = spec.spawn().await?;
+    output.forward(&mut child);
+
+    let mut status = RuntimeStatus {
+        pid: Some(pgid),
+        pid_start_time: process_start_time(pgid).await,
+        ..Default::default()
+    };
+    status.mark_started();
+    let status = SharedStatus::new(status);
+    let port = spec.port();
+    let shutdown = ShutdownHandle::spawn({
+        let status = status.clone();
+        move |ct| supervise(Supervised::Child { child, pgid }, spec, output, status, ct)
+    });
+
+    Ok(RuntimeHandle {
+        shutdown,
+        container_id: None,
+        status,
+        port,
+        published_ports: Vec::new(),
+        logs: Some(logs),
+        version: None,
+    })
+}
+
+/// Supervise a process that was spawned by a previous run of the operator
+///
+/// Its output cannot be captured anymore, but it is restarted from `spec` on failure,
+/// and the output of the restarted process is captured into `buffer`.
+pub fn adopt(
+    pid: u32,
+    spec: ProcessSpec,
+    id: ServerId,
+    runtime: impl Into<Arc<str>>,
+    status: SharedStatus,
+    buffer: LogBuffer,
+) -> ShutdownHandle {
+    let (tx, _) = broadcast::channel(LOG_CHANNEL_CAPACITY);
+    let output = Output {
+        tx,
+        buffer,
+        id,
+        runtime: runtime.into(),
+    };
+    ShutdownHandle::spawn(move |ct| supervise(Supervised::Adopted(pid), spec, output, status, ct))
+}
+
+#[tracing::instrument(skip_all, fields(server = %output.id, runtime = %output.runtime))]
+async fn supervise(
+    supervised: Supervised,
+    spec: ProcessSpec,
+    output: Output,
+    status: SharedStatus,
+    ct: CancellationToken,
+) {
+    let cgroup = spec.cgroup.clone();
+    supervise_process(supervised, spec, output, status, ct).await;
+    if let Some(cgroup) = cgroup {
+        cgroup::remove(&cgroup).await;
+    }
+}
+
+/// Supervise the process until it is stopped, exits successfully or keeps crashing
+async fn supervise_process(
+    mut supervised: Supervised,
+    spec: ProcessSpec,
+    output: Output,
+    status: SharedStatus,
+    ct: CancellationToken,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut failures = VecDeque::new();
+
+    loop {
+        let started = Instant::now();
+        let exit_code = tokio::select! {
+            () = ct.cancelled() => {
+                terminate(supervised, spec.grace_period).await;
+                status.update(|s| {
+                    s.state = ServerState::Stopped;
+                    s.pid = None;
+                    s.pid_start_time = None;
+                });
+                blueprint_sdk::debug!("Process stopped");
+                return;
+            }
+            exit_code = wait(&mut supervised) => exit_code,
+        };
+
+        status.update(|s| {
+            s.exit_code = exit_code;
+            s.pid = None;
+            s.pid_start_time = None;
+        });
+        // The wrapper is gone, but its children may still be around and hold the port
+        let pgid = supervised.pgid();
+        if process_alive(pgid).await {
+            blueprint_sdk::warn!(%pgid, "The exited process left processes behind, stopping them");
+            terminate(Supervised::Adopted(pgid), spec.grace_period).await;
+        }
+        if exit_code == Some(0) {
+            blueprint_sdk::info!("Process exited successfully, not restarting");
+            status.update(|s| s.state = ServerState::Exited);
+            return;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(started) >= CRASH_LOOP_WINDOW {
+            backoff = INITIAL_BACKOFF;
+        }
+        failures.push_back(now);
+        while failures
+            .front()
+            .is_some_and(|failed_at| now.duration_since(*failed_at) > CRASH_LOOP_WINDOW)
+        {
+            failures.pop_front();
+        }
+        if failures.len() >= CRASH_LOOP_THRESHOLD {
+            blueprint_sdk::error!(
+                ?exit_code,
+                failures = failures.len(),
+                "Process is crash looping, giving up"
+            );
+            status.update(|s| s.state = ServerState::Failed);
+            return;
+        }
+
+        blueprint_sdk::warn!(
+            ?exit_code,
+            ?backoff,
+            "Process exited with a failure, restarting"
+        );
+        status.update(|s| s.state = ServerState::Restarting);
+        tokio::select! {
+            () = ct.cancelled() => {
+                status.update(|s| s.state = ServerState::Stopped);
+                return;
+            }
+            () = tokio::time::sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+
+        let (mut child, pgid) = match spec.spawn().await {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                blueprint_sdk::error!(?e, "Failed to restart process");
+                status.update(|s| s.state = ServerState::Failed);
+                return;
+            }
+        };
+        output.forward(&mut child);
+        let start_time = process_start_time(pgid).await;
+        status.update(|s| {
+            s.state = ServerState::Running;
+            s.pid = Some(pgid);
+            s.pid_start_time = start_time;
+            s.restart_count += 1;
+            s.mark_started();
+        });
+        supervised = Supervised::Child { child, pgid };
+    }
+}
+
+/// Wait for the supervised process to exit and return its exit code, if known
+async fn wait(supervised: &mut Supervised) -> Option<i64> {
+    match supervised {
+        Supervised::Child { child, .. } => child
+            .wait()
+            .await
+            .ok()
+            .and_then(|status| status.code())
+            .map(i64::from),
+        Supervised::Adopted(pid) => loop {
+            tokio::time::sleep(ADOPTED_POLL_INTERVAL).await;
+            if !process_alive(*pid).await {
+                break None;
+            }
+        },
+    }
+}
+
+/// Ask the process group to terminate and kill it if it does not exit in time
+///
+/// Returns once every process of the group exited.
+async fn terminate(supervised: Supervised, grace_period: Duration) {
+    let pgid = supervised.pgid();
+    let mut child = match supervised {
+        Supervised::Child { child, .. } => Some(child),
+        Supervised::Adopted(_) => None,
+    };
+
+    signal(pgid, "-TERM").await;
+    if wait_for_exit(pgid, child.as_mut(), grace_period).await {
+        return;
+    }
+    blueprint_sdk::warn!(%pgid, ?grace_period, "Processes did not exit in time, killing them");
+    signal(pgid, "-KILL").await;
+    if !wait_for_exit(pgid, child.as_mut(), KILL_TIMEOUT).await {
+        blueprint_sdk::error!(%pgid, "Processes are still alive after SIGKILL");
+    }
+}
+
+/// Wait until every process of the group exited, reaping the group leader
+///
+/// Returns `false` if some are still alive after `timeout`.
+async fn wait_for_exit(pgid: u32, mut leader: Option<&mut Child>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // An unreaped leader would keep the group alive as a zombie
+        if let Some(leader) = &mut leader {
+            let _ = leader.try_wait();
+        }
+        if !process_alive(pgid).await {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Run `kill` with the given arguments, returning whether it succeeded
+async fn kill(args: &[&str]) -> bool {
+    Command::new("kill")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
+
+/// Send a signal to a process group
+///
+/// Falls back to signalling the process alone if `pgid` is the pid of a process
+/// that does not lead a group.
+async fn signal(pgid: u32, signal: &str) {
+    if !kill(&[signal, "--", &format!("-{pgid}")]).await {
+        kill(&[signal, &pgid.to_string()]).await;
+    }
+}
+
+/// Suspend every process of the given process group until it is resumed
+pub async fn pause(pgid: u32) {
+    signal(pgid, "-STOP").await;
+}
+
+/// Resume the processes of a group suspended with [`pause`]
+pub async fn resume(pgid: u32) {
+    signal(pgid, "-CONT").await;
+}
+
+/// Check whether any process of the given process group, or the process with
+/// the given pid, is still alive
+pub async fn process_alive(pgid: u32) -> bool {
+    kill(&["-0", "--", &format!("-{pgid}")]).await || kill(&["-0", &pgid.to_string()]).await
+}
+
+/// The start time of a process, in clock ticks since boot
+///
+/// Pids are reused once a process exited or the host rebooted, so only the pid and
+/// the start time together identify a process. `None` if there is no such process
+/// or `/proc` is not available.
+pub async fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = tokio::fs::read_to_string(format!("/proc/{pid}/stat"))
+        .await
+        .ok()?;
+    // The command name in the second field may contain spaces and parentheses, and the
+    // start time is the 22nd field
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The pid of the process a previous run of the operator left in `status`, if that
+/// process is still running and can be adopted
+///
+/// A process is only adopted if it still has the recorded start time, so an unrelated
+/// process that got the same pid is never signalled.
+pub async fn adoptable(status: &RuntimeStatus) -> Option<u32> {
+    let pid = status.pid?;
+    let start_time = status.pid_start_time?;
+    match process_start_time(pid).await {
+        Some(current) if current == start_time => Some(pid),
+        Some(_) => {
+            blueprint_sdk::warn!(%pid, "The pid was reused by another process, not adopting it");
+            None
+        }
+        None => None,
+    }
+}
+
+/// Where the output of the supervised process goes
+struct Output {
+    /// Subscribers to the live output, e.g. readiness probes
+    tx: broadcast::Sender<String>,
+    /// The captured output of the server
+    buffer: LogBuffer,
+    id: ServerId,
+    runtime: Arc<str>,
+}
+
+impl Output {
+    /// Forward the stdout and stderr of a child process to the log, the buffer and
+    /// the subscribers, line by line
+    fn forward(&self, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            self.forward_stream(stdout, LogStream::Stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.forward_stream(stderr, LogStream::Stderr);
+        }
+    }
+
+    fn forward_stream(&self, reader: impl AsyncRead + Unpin + Send + 'static, stream: LogStream) {
+        let tx = self.tx.clone();
+        let buffer = self.buffer.clone();
+        let id = self.id.clone();
+        let runtime = self.runtime.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                // The log buffer keeps the output, the operator log only traces it
+                blueprint_sdk::trace!(server = %id, %runtime, "{line}");
+                buffer.push(stream, &line);
+                let _ = tx.send(line);
+            }
+        });
+    }
+}
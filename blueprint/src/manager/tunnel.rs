@@ -0,0 +1,217 @@
+//! Authenticated TCP tunnels
+//!
+//! Servers that do not speak HTTP, like databases, are reached through a tunnel
+//! listener on the operator instead of the auth proxy. A client opens a TCP
+//! connection and sends a single line `AUTH {access_token}\n`, with a token from the
+//! auth proxy's challenge-response flow. The tunnel answers `OK\n` and splices the
+//! connection to the server if the token grants access to the service, and
+//! `ERR {reason}\n` before closing the connection otherwise.
+//!
+//! Tokens are checked by the auth proxy itself: the tunnel sends a request with the
+//! token through the proxy to [`VERIFY_PATH`] and a random nonce. The proxy only
+//! forwards it if the token is valid, and to the upstream of the token's service.
+//! That upstream is always the service's gateway, which confirms the nonce to its
+//! [`TokenVerifier`] if the check is for its own service. No gateway forwards a
+//! check to a server, so the nonce stays secret and only a request through the
+//! proxy can confirm it.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use blueprint_sdk::auth::proxy::DEFAULT_AUTH_PROXY_PORT;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::Error;
+use crate::manager::ShutdownHandle;
+
+/// The path the gateways confirm the nonces of token checks at, followed by the nonce
+pub const VERIFY_PATH: &str = "/.server-blueprint/tunnel/";
+/// How long a client gets to authenticate
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a token check through the auth proxy may take
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum length of the handshake line
+const MAX_HANDSHAKE_LEN: u64 = 8 * 1024;
+/// How long to back off after failing to accept a connection
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Checks access tokens with the auth proxy
+#[derive(Debug, Clone, Default)]
+pub struct TokenVerifier {
+    /// The nonces of the running checks, with the service they are expected for
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    service_id: u64,
+    confirmed: bool,
+}
+
+impl TokenVerifier {
+    fn pending(&self) -> std::sync::MutexGuard<'_, HashMap<String, Pending>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check whether `token` grants access to the given service
+    pub async fn verify(&self, service_id: u64, token: &str) -> Result<bool, Error> {
+        // The token ends up in a header, so it must not be able to add others
+        if token.is_empty() || !token.bytes().all(|b| b.is_ascii_graphic()) {
+            return Ok(false);
+        }
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        self.pending().insert(
+            nonce.clone(),
+            Pending {
+                service_id,
+                confirmed: false,
+            },
+        );
+        let requested = tokio::time::timeout(VERIFY_TIMEOUT, request_through_proxy(&nonce, token))
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::Io(std::io::Error::other(
+                    "Timed out checking the token with the auth proxy",
+                )))
+            });
+        let confirmed = self
+            .pending()
+            .remove(&nonce)
+            .is_some_and(|pending| pending.confirmed);
+        requested?;
+        Ok(confirmed)
+    }
+
+    /// Confirm the nonce of a token check that reached the upstream of the given service
+    ///
+    /// Returns `false` if no such check is running for the service.
+    pub fn confirm(&self, service_id: u64, nonce: &str) -> bool {
+        match self.pending().get_mut(nonce) {
+            Some(pending) if pending.service_id == service_id => {
+                pending.confirmed = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Send a request with the token through the auth proxy to the nonce's verify path
+async fn request_through_proxy(nonce: &str, token: &str) -> Result<(), Error> {
+    let mut stream =
+        TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, DEFAULT_AUTH_PROXY_PORT)).await?;
+    let request = format!(
+        "GET {VERIFY_PATH}{nonce} HTTP/1.1\r\nHost: 127.0.0.1:{DEFAULT_AUTH_PROXY_PORT}\r\nAuthorization: Bearer {token}\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // The answer does not matter, only whether the nonce was confirmed
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(())
+}
+
+/// A tunnel listener in front of a server port
+#[derive(Debug)]
+pub struct Tunnel {
+    backend_port: u16,
+    shutdown: ShutdownHandle,
+}
+
+impl Tunnel {
+    /// Start a tunnel listening on `address:port`, splicing authenticated clients
+    /// of the service to the local `backend_port`
+    pub async fn start(
+        address: IpAddr,
+        port: u16,
+        service_id: u64,
+        backend_port: u16,
+        verifier: TokenVerifier,
+    ) -> Result<Self, Error> {
+        let listener = TcpListener::bind((address, port)).await?;
+        let shutdown = ShutdownHandle::spawn(move |token| async move {
+            loop {
+                let accepted = tokio::select! {
+                    () = token.cancelled() => break,
+                    accepted = listener.accept() => accepted,
+                };
+                let (client, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        blueprint_sdk::warn!(?e, %port, "Tunnel failed to accept a connection");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let verifier = verifier.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(client, service_id, backend_port, &verifier).await {
+                        blueprint_sdk::debug!(?e, %peer, %port, "Tunnel connection failed");
+                    }
+                });
+            }
+        });
+        blueprint_sdk::debug!(%address, %port, %backend_port, "Started tunnel");
+        Ok(Self {
+            backend_port,
+            shutdown,
+        })
+    }
+
+    /// The local port the tunnel splices connections to
+    pub fn backend_port(&self) -> u16 {
+        self.backend_port
+    }
+
+    /// Stop accepting connections
+    ///
+    /// Connections that are already spliced are not interrupted.
+    pub async fn shutdown(self) {
+        self.shutdown.shutdown().await;
+    }
+}
+
+/// Authenticate a client and splice it to the backend
+async fn serve(
+    client: TcpStream,
+    service_id: u64,
+    backend_port: u16,
+    verifier: &TokenVerifier,
+) -> Result<(), Error> {
+    // Bytes the client sends right after the handshake stay in the buffer and are spliced too
+    let mut client = BufReader::new(client);
+    let mut line = String::new();
+    let read = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        (&mut client).take(MAX_HANDSHAKE_LEN).read_line(&mut line),
+    )
+    .await;
+    if !matches!(read, Ok(Ok(_))) || !line.ends_with('\n') {
+        return reject(&mut client, "expected `AUTH {token}`").await;
+    }
+    let Some(token) = line.trim_end().strip_prefix("AUTH ") else {
+        return reject(&mut client, "expected `AUTH {token}`").await;
+    };
+    if !verifier.verify(service_id, token).await? {
+        return reject(&mut client, "unauthorized").await;
+    }
+
+    let Ok(mut backend) = TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, backend_port)).await
+    else {
+        return reject(&mut client, "server unavailable").await;
+    };
+    client.write_all(b"OK\n").await?;
+    tokio::io::copy_bidirectional(&mut client, &mut backend).await?;
+    Ok(())
+}
+
+/// Tell the client why it is not let through and close the connection
+async fn reject(client: &mut BufReader<TcpStream>, reason: &str) -> Result<(), Error> {
+    client
+        .write_all(format!("ERR {reason}\n").as_bytes())
+        .await?;
+    client.shutdown().await?;
+    Ok(())
+}
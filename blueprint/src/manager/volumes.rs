@@ -0,0 +1,283 @@
+//! Named Docker volumes
+//!
+//! Every volume of a server is a Docker volume named after the server, so its data
+//! survives the server being stopped, restarted or reconfigured. Volumes that no
+//! server uses anymore are deleted once the operator's retention period expired.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use docktopus::bollard::Docker;
+use docktopus::bollard::container::{
+    Config, CreateContainerOptions, DownloadFromContainerOptions, RemoveContainerOptions,
+    UploadToContainerOptions,
+};
+use docktopus::bollard::errors::Error as DockerError;
+use docktopus::bollard::models::{HostConfig, Mount, MountTypeEnum};
+use docktopus::bollard::volume::{CreateVolumeOptions, ListVolumesOptions};
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::VolumeMount;
+use crate::error::Error;
+use crate::manager::compose::Stack;
+use crate::manager::events::{SERVICE_ID_LABEL, SLOT_LABEL};
+use crate::manager::{Server, ServerId};
+
+/// The label holding the name of the volume as chosen in the server config
+pub const VOLUME_NAME_LABEL: &str = "server-blueprint.volume";
+
+/// The name of the Docker volume backing a volume of the given server
+pub fn volume_name(id: &ServerId, name: &str) -> String {
+    format!("{}-{name}", id.name())
+}
+
+/// The names of the Docker volumes used by the given servers
+pub fn in_use<'a>(servers: impl IntoIterator<Item = (ServerId, &'a Server)>) -> HashSet<String> {
+    servers
+        .into_iter()
+        .flat_map(|(id, server)| {
+            // The volumes of a stack are declared in its document
+            let stack = server
+                .compose
+                .as_deref()
+                .and_then(|document| Stack::parse(document).ok());
+            let names: Vec<String> = match stack {
+                Some(stack) => stack.volumes().to_vec(),
+                None => server
+                    .volumes
+                    .iter()
+                    .map(|volume| volume.name.clone())
+                    .collect(),
+            };
+            names.into_iter().map(move |name| volume_name(&id, &name))
+        })
+        .collect()
+}
+
+/// Create the Docker volumes of a server and return the mounts to attach them
+///
+/// Volumes that already exist, e.g. from a previous run of the server, are reused.
+pub async fn create(
+    docker: &Docker,
+    id: &ServerId,
+    volumes: &[VolumeMount],
+) -> Result<Vec<Mount>, Error> {
+    let mut mounts = Vec::with_capacity(volumes.len());
+    for volume in volumes {
+        let name = volume_name(id, &volume.name);
+        let options = CreateVolumeOptions {
+            name: name.clone(),
+            labels: HashMap::from([
+                (SERVICE_ID_LABEL.to_string(), id.service_id.to_string()),
+                (SLOT_LABEL.to_string(), id.slot.clone()),
+                (VOLUME_NAME_LABEL.to_string(), volume.name.clone()),
+            ]),
+            ..Default::default()
+        };
+        docker.create_volume(options).await.map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to create Docker volume {name}: {e}"
+            )))
+        })?;
+        blueprint_sdk::debug!(%name, path = %volume.path, "Created Docker volume");
+        mounts.push(Mount {
+            target: Some(volume.path.clone()),
+            source: Some(name),
+            typ: Some(MountTypeEnum::VOLUME),
+            read_only: Some(volume.read_only),
+            ..Default::default()
+        });
+    }
+    Ok(mounts)
+}
+
+/// List the names of all Docker volumes created for the servers
+pub async fn list(docker: &Docker) -> Result<Vec<String>, Error> {
+    let options = ListVolumesOptions::<String> {
+        filters: HashMap::from([("label".to_string(), vec![SERVICE_ID_LABEL.to_string()])]),
+    };
+    let response = docker.list_volumes(Some(options)).await.map_err(|e| {
+        Error::Io(std::io::Error::other(format!(
+            "Failed to list Docker volumes: {e}"
+        )))
+    })?;
+    Ok(response
+        .volumes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|volume| volume.name)
+        .collect())
+}
+
+/// Delete a Docker volume
+///
+/// Returns `false` if the volume is still used by a container.
+pub async fn remove(docker: &Docker, name: &str) -> Result<bool, Error> {
+    match docker.remove_volume(name, None).await {
+        Ok(()) => Ok(true),
+        Err(DockerError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(true),
+        Err(DockerError::DockerResponseServerError {
+            status_code: 409, ..
+        }) => Ok(false),
+        Err(e) => Err(Error::Io(std::io::Error::other(format!(
+            "Failed to remove Docker volume {name}: {e}"
+        )))),
+    }
+}
+
+/// Download the contents of a mounted volume from a container into a tar file
+///
+/// The archive holds a single directory named after the last component of the
+/// mount path, as produced by the Docker archive API.
+pub async fn download(
+    docker: &Docker,
+    container_id: &str,
+    volume: &VolumeMount,
+    dest: &Path,
+) -> Result<(), Error> {
+    let options = DownloadFromContainerOptions {
+        path: volume.path.clone(),
+    };
+    let mut stream = docker.download_from_container(container_id, Some(options));
+    let mut file = tokio::fs::File::create(dest).await?;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to download volume {}: {e}",
+                volume.name
+            )))
+        })?;
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Replace the contents of the volumes of a server with the given tar files
+///
+/// The volumes are recreated empty and the archives, as written by [`download`],
+/// are uploaded through a container that is created but never started. The
+/// volumes must not be in use.
+pub async fn restore(
+    docker: &Docker,
+    id: &ServerId,
+    image: &str,
+    archives: &[(VolumeMount, std::path::PathBuf)],
+) -> Result<(), Error> {
+    for (volume, _) in archives {
+        let name = volume_name(id, &volume.name);
+        if !remove(docker, &name).await? {
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Docker volume {name} is still in use"
+            ))));
+        }
+    }
+    let volumes: Vec<VolumeMount> = archives.iter().map(|(volume, _)| volume.clone()).collect();
+    let mounts = create(docker, id, &volumes).await?;
+
+    let name = format!("{}-restore", id.name());
+    let remove_options = RemoveContainerOptions {
+        force: true,
+        v: true,
+        link: false,
+    };
+    let _ = docker.remove_container(&name, Some(remove_options)).await;
+    let config = Config {
+        image: Some(image.to_string()),
+        host_config: Some(HostConfig {
+            mounts: Some(mounts),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let options = CreateContainerOptions {
+        name: name.clone(),
+        platform: None,
+    };
+    docker
+        .create_container(Some(options), config)
+        .await
+        .map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to create Docker container {name}: {e}"
+            )))
+        })?;
+
+    let mut uploaded = Ok(());
+    for (volume, archive) in archives {
+        uploaded = upload(docker, &name, volume, archive).await;
+        if uploaded.is_err() {
+            break;
+        }
+    }
+    if let Err(e) = docker.remove_container(&name, Some(remove_options)).await {
+        blueprint_sdk::warn!(?e, %name, "Failed to remove restore container");
+    }
+    uploaded
+}
+
+/// Upload a tar file written by [`download`] into the volume mounted in a container
+async fn upload(
+    docker: &Docker,
+    container: &str,
+    volume: &VolumeMount,
+    archive: &Path,
+) -> Result<(), Error> {
+    // The archive holds the mount directory itself, so it is extracted into its parent
+    let parent = Path::new(&volume.path)
+        .parent()
+        .unwrap_or(Path::new("/"))
+        .to_string_lossy()
+        .into_owned();
+    let options = UploadToContainerOptions {
+        path: parent,
+        ..Default::default()
+    };
+    let file = tokio::fs::File::open(archive).await?;
+    let volume_name = volume.name.clone();
+    let body = tokio_util::io::ReaderStream::new(file).scan((), move |(), chunk| {
+        futures::future::ready(match chunk {
+            Ok(chunk) => Some(chunk),
+            Err(e) => {
+                // Ending the stream early makes Docker reject the truncated archive
+                blueprint_sdk::warn!(?e, volume = %volume_name, "Failed to read volume backup");
+                None
+            }
+        })
+    });
+    docker
+        .upload_to_container_streaming(container, Some(options), body)
+        .await
+        .map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "Failed to restore volume {}: {e}",
+                volume.name
+            )))
+        })
+}
+
+/// Work out which orphaned volumes are due for deletion
+///
+/// `orphaned` maps the volumes no server uses to when they were first seen unused,
+/// in seconds since the UNIX epoch. It is updated to cover exactly the volumes in
+/// `existing` that are not `in_use`.
+pub fn expired(
+    orphaned: &mut BTreeMap<String, u64>,
+    existing: &[String],
+    in_use: &HashSet<String>,
+    now: u64,
+    retention_secs: Option<u64>,
+) -> Vec<String> {
+    orphaned.retain(|name, _| existing.contains(name) && !in_use.contains(name));
+    let mut expired = Vec::new();
+    for name in existing.iter().filter(|name| !in_use.contains(*name)) {
+        let since = *orphaned.entry(name.clone()).or_insert(now);
+        if retention_secs.is_some_and(|retention| now.saturating_sub(since) >= retention) {
+            expired.push(name.clone());
+        }
+    }
+    expired
+}
@@ -0,0 +1,422 @@
+//! Sandboxed WebAssembly servers
+//!
+//! The Wasm runtime serves a WebAssembly component of the `wasi:http/proxy` world
+//! from inside the operator, on the port of the server, so the auth proxy routes to
+//! it like to any other server. The component is fetched and pinned to its digest
+//! like the artifacts of the [`BinaryRunner`].
+//!
+//! Every request runs in a fresh instance, with the fuel, memory and time the
+//! operator allows. Instances get no filesystem and no network, unless the operator
+//! allows outgoing HTTP requests or access to the working directory of the server.
+//! Nothing of a server outlives the operator, so re-adopting one serves its cached
+//! component again.
+
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::{Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::sync::{Semaphore, broadcast};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use wasmtime::component::{Component, Linker, ResourceTable};
+use wasmtime::{Engine, Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
+use wasmtime_wasi::{DirPerms, FilePerms, WasiCtx, WasiCtxBuilder, WasiCtxView, WasiView};
+use wasmtime_wasi_http::bindings::ProxyPre;
+use wasmtime_wasi_http::bindings::http::types::{ErrorCode, Scheme};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::io::TokioIo;
+use wasmtime_wasi_http::types::{
+    HostFutureIncomingResponse, OutgoingRequestConfig, default_send_request,
+};
+use wasmtime_wasi_http::{HttpResult, WasiHttpCtx, WasiHttpView};
+
+use crate::error::Error;
+use crate::manager::binary::{Artifact, BinaryRunner};
+use crate::manager::logs::{LogBuffer, LogStream};
+use crate::manager::status::{RuntimeStatus, ServerState, SharedStatus};
+use crate::manager::{RuntimeHandle, Server, ServerId, ServerRunner, ShutdownHandle};
+
+/// The fuel a request burns between the points where it yields to other tasks
+const FUEL_YIELD_INTERVAL: u64 = 10_000_000;
+/// How much a request may write to stdout and to stderr, in bytes
+const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+/// Where the working directory of the server is mounted, if the operator allows it
+const WORK_DIR_MOUNT: &str = "/data";
+/// The capacity of the channel the output lines are broadcast on
+const LOG_CHANNEL_CAPACITY: usize = 1024;
+
+/// Wasm runner
+///
+/// This runner serves a WebAssembly HTTP component from inside the operator
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct WasmRunner;
+
+impl WasmRunner {
+    /// Fetch and compile the component of a server
+    async fn compile(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<ProxyPre<Instance>, Error> {
+        let path = BinaryRunner.fetch(ctx, id, server).await?;
+        let invalid = |e: wasmtime::Error| {
+            Error::InvalidConfig(format!("the component cannot be served: {e:#}"))
+        };
+
+        let mut config = wasmtime::Config::new();
+        config.async_support(true).consume_fuel(true);
+        let engine = Engine::new(&config).map_err(invalid)?;
+        // Compiling to machine code takes a while for larger components
+        let component = tokio::task::spawn_blocking({
+            let engine = engine.clone();
+            move || Component::from_file(&engine, path)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+        .map_err(invalid)?;
+
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::p2::add_to_linker_async(&mut linker).map_err(invalid)?;
+        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).map_err(invalid)?;
+        linker
+            .instantiate_pre(&component)
+            .and_then(ProxyPre::new)
+            .map_err(invalid)
+    }
+
+    /// Serve the component of a server on its port until the server is stopped
+    async fn serve(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+        status: SharedStatus,
+    ) -> Result<RuntimeHandle, Error> {
+        let port = server
+            .env_vars
+            .get("PORT")
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or_else(|| Error::InvalidConfig("the server has no port".to_string()))?;
+        let pre = self.compile(ctx, id, server).await?;
+
+        let limits = &ctx.config.wasm;
+        let memory_mb = match server.resources.memory_mb {
+            0 => limits.max_memory_mb,
+            memory_mb => memory_mb.min(limits.max_memory_mb),
+        };
+        let work_dir = if limits.allow_work_dir {
+            let work_dir = ctx.work_dir(id);
+            tokio::fs::create_dir_all(&work_dir).await?;
+            Some(work_dir)
+        } else {
+            None
+        };
+        let (logs_tx, logs) = broadcast::channel(LOG_CHANNEL_CAPACITY);
+        let service = Arc::new(Service {
+            id: id.clone(),
+            pre,
+            sandbox: Sandbox {
+                args: server.args.clone(),
+                env: server.env_vars.clone().into_iter().collect(),
+                fuel: limits.fuel_per_request,
+                memory_bytes: usize::try_from(memory_mb.saturating_mul(1024 * 1024))
+                    .unwrap_or(usize::MAX),
+                timeout: limits.request_timeout(),
+                allow_outgoing_http: limits.allow_outgoing_http,
+                work_dir,
+            },
+            permits: Arc::new(Semaphore::new(limits.max_concurrent_requests)),
+            buffer: ctx.logs.buffer(id).await,
+            logs: logs_tx,
+        });
+
+        // Let go of the port right before the server binds it
+        ctx.ports.release(id);
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        status.update(|s| {
+            s.state = ServerState::Running;
+            s.pid = None;
+            s.mark_started();
+        });
+        blueprint_sdk::info!(server = %id, port, "Serving Wasm component");
+
+        let grace_period = ctx.config.stop.grace_period();
+        let shutdown = ShutdownHandle::spawn({
+            let status = status.clone();
+            move |ct| accept(service, listener, status, grace_period, ct)
+        });
+        Ok(RuntimeHandle {
+            shutdown,
+            container_id: None,
+            status,
+            port: Some(port),
+            published_ports: Vec::new(),
+            logs: Some(logs),
+            version: server.digest.clone(),
+        })
+    }
+}
+
+/// What the instances of a server may use
+#[derive(Debug, Clone)]
+struct Sandbox {
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    fuel: u64,
+    memory_bytes: usize,
+    timeout: Duration,
+    allow_outgoing_http: bool,
+    work_dir: Option<PathBuf>,
+}
+
+/// A served component
+struct Service {
+    id: ServerId,
+    pre: ProxyPre<Instance>,
+    sandbox: Sandbox,
+    /// Limits the requests handled at once
+    permits: Arc<Semaphore>,
+    buffer: LogBuffer,
+    logs: broadcast::Sender<String>,
+}
+
+impl Service {
+    /// Handle a request, answering with an error if the component fails
+    async fn handle(self: Arc<Self>, request: Request<Incoming>) -> Response<HyperOutgoingBody> {
+        let id = self.id.clone();
+        match self.run(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                blueprint_sdk::warn!(server = %id, "Wasm request failed: {e:#}");
+                let body = Empty::new().map_err(|never| match never {}).boxed();
+                let mut response = Response::new(body);
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            }
+        }
+    }
+
+    /// Run a request in a fresh instance of the component
+    async fn run(
+        self: Arc<Self>,
+        request: Request<Incoming>,
+    ) -> wasmtime::Result<Response<HyperOutgoingBody>> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let sandbox = &self.sandbox;
+        let stdout = MemoryOutputPipe::new(MAX_OUTPUT_BYTES);
+        let stderr = MemoryOutputPipe::new(MAX_OUTPUT_BYTES);
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.args(&sandbox.args)
+            .envs(&sandbox.env)
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .allow_tcp(false)
+            .allow_udp(false);
+        if let Some(work_dir) = &sandbox.work_dir {
+            wasi.preopened_dir(work_dir, WORK_DIR_MOUNT, DirPerms::all(), FilePerms::all())?;
+        }
+        let instance = Instance {
+            wasi: wasi.build(),
+            http: WasiHttpCtx::new(),
+            table: ResourceTable::new(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(sandbox.memory_bytes)
+                .build(),
+            allow_outgoing_http: sandbox.allow_outgoing_http,
+        };
+        let mut store = Store::new(self.pre.engine(), instance);
+        store.limiter(|instance| &mut instance.limits);
+        store.set_fuel(sandbox.fuel)?;
+        store.fuel_async_yield_interval(Some(FUEL_YIELD_INTERVAL))?;
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let request = store
+            .data_mut()
+            .new_incoming_request(Scheme::Http, request)?;
+        let out = store.data_mut().new_response_outparam(sender)?;
+        // The component keeps running after it set the response, to write the body
+        let task = tokio::spawn({
+            let service = self.clone();
+            async move {
+                let timeout = service.sandbox.timeout;
+                let handled = tokio::time::timeout(timeout, async {
+                    let proxy = service.pre.instantiate_async(&mut store).await?;
+                    proxy
+                        .wasi_http_incoming_handler()
+                        .call_handle(&mut store, request, out)
+                        .await
+                })
+                .await
+                .unwrap_or_else(|_| {
+                    Err(wasmtime::Error::msg(format!(
+                        "the request timed out after {}s",
+                        timeout.as_secs()
+                    )))
+                });
+                drop(permit);
+                service.forward(LogStream::Stdout, &stdout);
+                service.forward(LogStream::Stderr, &stderr);
+                handled
+            }
+        });
+
+        match receiver.await {
+            Ok(response) => Ok(response?),
+            // The component dropped the response without setting it
+            Err(_) => match task.await? {
+                Ok(()) => Err(wasmtime::Error::msg("the component set no response")),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Forward the output of a request to the log, the buffer and the subscribers
+    fn forward(&self, stream: LogStream, pipe: &MemoryOutputPipe) {
+        let contents = pipe.contents();
+        for line in String::from_utf8_lossy(&contents).lines() {
+            blueprint_sdk::trace!(server = %self.id, runtime = "Wasm", "{line}");
+            self.buffer.push(stream, line);
+            let _ = self.logs.send(line.to_string());
+        }
+    }
+}
+
+/// Accept connections until the server is stopped, then let the requests finish
+async fn accept(
+    service: Arc<Service>,
+    listener: TcpListener,
+    status: SharedStatus,
+    grace_period: Duration,
+    ct: CancellationToken,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            () = ct.cancelled() => break,
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        blueprint_sdk::warn!(server = %service.id, ?e, "Failed to accept a connection");
+                        continue;
+                    }
+                };
+                let service = service.clone();
+                connections.spawn(async move {
+                    let id = service.id.clone();
+                    let handler = hyper::service::service_fn(move |request| {
+                        let service = service.clone();
+                        async move { Ok::<_, Infallible>(service.handle(request).await) }
+                    });
+                    if let Err(e) = http1::Builder::new()
+                        .keep_alive(true)
+                        .serve_connection(TokioIo::new(stream), handler)
+                        .await
+                    {
+                        blueprint_sdk::debug!(server = %id, ?e, "Wasm connection failed");
+                    }
+                });
+            }
+            // Reap the closed connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+    drop(listener);
+    let drained = tokio::time::timeout(grace_period, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        blueprint_sdk::debug!(server = %service.id, "Closing the remaining Wasm connections");
+    }
+    status.update(|s| s.state = ServerState::Stopped);
+}
+
+/// The state of an instance of a component
+struct Instance {
+    wasi: WasiCtx,
+    http: WasiHttpCtx,
+    table: ResourceTable,
+    limits: StoreLimits,
+    allow_outgoing_http: bool,
+}
+
+impl WasiView for Instance {
+    fn ctx(&mut self) -> WasiCtxView<'_> {
+        WasiCtxView {
+            ctx: &mut self.wasi,
+            table: &mut self.table,
+        }
+    }
+}
+
+impl WasiHttpView for Instance {
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.http
+    }
+
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn send_request(
+        &mut self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        if !self.allow_outgoing_http {
+            return Err(ErrorCode::HttpRequestDenied.into());
+        }
+        Ok(default_send_request(request, config))
+    }
+}
+
+impl ServerRunner for WasmRunner {
+    #[tracing::instrument(
+        skip(self, ctx, server),
+        fields(package = %server.package, runtime = "wasm")
+    )]
+    async fn start(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<RuntimeHandle, Error> {
+        let remote = matches!(Artifact::parse(&server.package), Some(Artifact::Url(_)));
+        if remote && !matches!(BinaryRunner.check(ctx).await, Ok(true)) {
+            return Err(Error::Io(std::io::Error::other(
+                "curl is needed to download components but is not installed",
+            )));
+        }
+        let status = SharedStatus::new(RuntimeStatus::default());
+        self.serve(ctx, id, server, status).await
+    }
+
+    async fn adopt(
+        &self,
+        ctx: &crate::MyContext,
+        id: &ServerId,
+        server: &Server,
+    ) -> Result<Option<ShutdownHandle>, Error> {
+        // The component is served by the operator, so it went down with the previous run
+        let handle = self.serve(ctx, id, server, server.status.clone()).await?;
+        Ok(Some(handle.shutdown))
+    }
+
+    async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
+        // The engine is built into the operator
+        Ok(true)
+    }
+
+    async fn install(&self, _ctx: &crate::MyContext) -> Result<(), Error> {
+        Ok(())
+    }
+}
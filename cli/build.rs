@@ -1,7 +1,10 @@
 // use blueprint_sdk::build;
 use blueprint_sdk::tangle::blueprint;
 use server_blueprint::server_start;
-use server_blueprint::{BlueprintRequestParams, server_stop};
+use server_blueprint::{
+    BlueprintRequestParams, server_backup, server_logs, server_reconfigure, server_restart,
+    server_restore, server_status, server_stop,
+};
 use std::path::Path;
 use std::process;
 
@@ -29,7 +32,16 @@ fn main() {
         name: "server-blueprint",
         master_manager_revision: "Latest",
         manager: { Evm = "HelloBlueprint" },
-        jobs: [server_start, server_stop],
+        jobs: [
+            server_start,
+            server_stop,
+            server_logs,
+            server_status,
+            server_restart,
+            server_reconfigure,
+            server_backup,
+            server_restore
+        ],
         request_params: BlueprintRequestParams,
     };
 
@@ -11,7 +11,12 @@ use blueprint_sdk::tangle::consumer::TangleConsumer;
 use blueprint_sdk::tangle::filters::MatchesServiceId;
 use blueprint_sdk::tangle::layers::TangleLayer;
 use blueprint_sdk::tangle::producer::TangleProducer;
-use server_blueprint::{SERVER_START_JOB_ID, SERVER_STOP_JOB_ID, MyContext, server_start, server_stop};
+use server_blueprint::{
+    MyContext, SERVER_BACKUP_JOB_ID, SERVER_LOGS_JOB_ID, SERVER_RECONFIGURE_JOB_ID,
+    SERVER_RESTART_JOB_ID, SERVER_RESTORE_JOB_ID, SERVER_START_JOB_ID, SERVER_STATUS_JOB_ID,
+    SERVER_STOP_JOB_ID, server_backup, server_logs, server_reconfigure, server_restart,
+    server_restore, server_start, server_status, server_stop,
+};
 use tower::filter::FilterLayer;
 use tracing::error;
 use tracing::level_filters::LevelFilter;
@@ -37,11 +42,23 @@ async fn main() -> color_eyre::Result<()> {
 
     let service_id = env.protocol_settings.tangle()?.service_id.unwrap();
     let ctx = MyContext::new(env.clone()).await?;
+    ctx.reconcile_servers().await?;
+    ctx.spawn_volume_gc();
+    ctx.spawn_backup_scheduler();
     let result = BlueprintRunner::builder(tangle_config, env.clone())
         .router(
             Router::new()
                 .route(SERVER_START_JOB_ID, server_start.layer(TangleLayer))
                 .route(SERVER_STOP_JOB_ID, server_stop.layer(TangleLayer))
+                .route(SERVER_LOGS_JOB_ID, server_logs.layer(TangleLayer))
+                .route(SERVER_STATUS_JOB_ID, server_status.layer(TangleLayer))
+                .route(SERVER_RESTART_JOB_ID, server_restart.layer(TangleLayer))
+                .route(
+                    SERVER_RECONFIGURE_JOB_ID,
+                    server_reconfigure.layer(TangleLayer),
+                )
+                .route(SERVER_BACKUP_JOB_ID, server_backup.layer(TangleLayer))
+                .route(SERVER_RESTORE_JOB_ID, server_restore.layer(TangleLayer))
                 .layer(FilterLayer::new(MatchesServiceId(service_id)))
                 .with_context(ctx),
         )